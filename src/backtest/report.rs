@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::exchanges::paper::matching::PaperFill;
+use crate::oms::oms::TrackedOrder;
+
+const YEAR_MS: f64 = 365.0 * 24.0 * 3_600_000.0;
+
+/// One sample of the equity curve.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Point {
+    /// Simulated ms
+    pub timestamp: u128,
+    pub equity: Decimal,
+    /// Since the start
+    pub pnl: Decimal,
+    /// Below the highest equity so far
+    pub drawdown: Decimal,
+}
+
+/// What a backtest did, amounts in `coin`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub coin: String,
+    /// Recorded messages played
+    pub events: usize,
+    pub start_equity: Decimal,
+    pub end_equity: Decimal,
+    pub pnl: Decimal,
+    pub max_drawdown: Decimal,
+    /// Of the peak it fell from, 0.1 is 10%
+    pub max_drawdown_pct: f64,
+    /// Of the returns between samples, annualised, 0 with fewer than 3 samples or no variance
+    pub sharpe: f64,
+    /// Notional of all fills
+    pub turnover: Decimal,
+    pub fees: Decimal,
+    /// Orders the exchange took
+    pub orders: usize,
+    /// Orders refused by the risk checks or the exchange
+    pub rejected: usize,
+    pub fills: usize,
+    pub maker_fills: usize,
+    /// Filled qty over the qty of the orders the exchange took
+    pub fill_ratio: f64,
+    pub curve: Vec<Point>,
+}
+
+impl Report {
+    /// `samples` are the equity in `coin` every `sample_ms`, oldest first.
+    pub fn new(
+        samples: &[(u128, Decimal)],
+        fills: &[PaperFill],
+        orders: &[TrackedOrder],
+        sample_ms: u64,
+        events: usize,
+        coin: &str,
+    ) -> Self {
+        let start_equity = samples.first().map_or(Decimal::ZERO, |(_, e)| *e);
+        let end_equity = samples.last().map_or(Decimal::ZERO, |(_, e)| *e);
+        let mut peak = start_equity;
+        let mut max_drawdown = Decimal::ZERO;
+        let mut max_drawdown_pct = 0.0;
+        let curve: Vec<Point> = samples
+            .iter()
+            .map(|(timestamp, equity)| {
+                peak = peak.max(*equity);
+                let drawdown = peak - equity;
+                if drawdown > max_drawdown {
+                    max_drawdown = drawdown;
+                }
+                if peak > Decimal::ZERO {
+                    let pct = (drawdown / peak).to_f64().unwrap_or_default();
+                    max_drawdown_pct = f64::max(max_drawdown_pct, pct);
+                }
+                Point {
+                    timestamp: *timestamp,
+                    equity: *equity,
+                    pnl: equity - start_equity,
+                    drawdown,
+                }
+            })
+            .collect();
+
+        let placed: Vec<&TrackedOrder> = orders.iter().filter(|o| o.order_id.is_some()).collect();
+        let ordered: Decimal = placed.iter().map(|o| o.intent.qty).sum();
+        let filled: Decimal = placed.iter().map(|o| o.filled_qty).sum();
+        Self {
+            coin: coin.to_string(),
+            events,
+            start_equity,
+            end_equity,
+            pnl: end_equity - start_equity,
+            max_drawdown,
+            max_drawdown_pct,
+            sharpe: sharpe(samples, sample_ms),
+            turnover: fills.iter().map(|f| f.qty * f.price).sum(),
+            fees: fills.iter().map(|f| f.fee).sum(),
+            orders: placed.len(),
+            rejected: orders.len() - placed.len(),
+            fills: fills.len(),
+            maker_fills: fills.iter().filter(|f| f.maker).count(),
+            fill_ratio: match ordered.is_zero() {
+                true => 0.0,
+                false => (filled / ordered).to_f64().unwrap_or_default(),
+            },
+            curve,
+        }
+    }
+
+    /// The report to `<output>.json`, the curve to `<output>.csv`.
+    pub fn write(&self, output: &str) -> io::Result<()> {
+        let json = BufWriter::new(File::create(format!("{}.json", output))?);
+        serde_json::to_writer_pretty(json, self)?;
+
+        let mut csv = BufWriter::new(File::create(format!("{}.csv", output))?);
+        writeln!(csv, "timestamp,equity,pnl,drawdown")?;
+        for point in &self.curve {
+            writeln!(
+                csv,
+                "{},{},{},{}",
+                point.timestamp, point.equity, point.pnl, point.drawdown
+            )?;
+        }
+        csv.flush()
+    }
+}
+
+fn sharpe(samples: &[(u128, Decimal)], sample_ms: u64) -> f64 {
+    let returns: Vec<f64> = samples
+        .windows(2)
+        .filter(|w| w[0].1 > Decimal::ZERO)
+        .filter_map(|w| (w[1].1 / w[0].1 - Decimal::ONE).to_f64())
+        .collect();
+    if returns.len() < 2 {
+        return 0.0;
+    }
+    let n = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / n;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    match variance > 0.0 {
+        true => mean / variance.sqrt() * (YEAR_MS / sample_ms as f64).sqrt(),
+        false => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn drawdown_and_sharpe() {
+        let samples = [
+            (0, dec!(100)),
+            (1, dec!(110)),
+            (2, dec!(99)),
+            (3, dec!(104.5)),
+        ];
+        let report = Report::new(&samples, &[], &[], 1, 4, "USDT");
+        assert_eq!(report.pnl, dec!(4.5));
+        assert_eq!(report.max_drawdown, dec!(11));
+        assert!((report.max_drawdown_pct - 0.1).abs() < 1e-12);
+        assert_eq!(report.curve[3].drawdown, dec!(5.5));
+        // +10%, -10%, +5.56%
+        assert!(report.sharpe > 0.0);
+        assert_eq!(report.fill_ratio, 0.0);
+
+        let flat = Report::new(
+            &[(0, dec!(100)), (1, dec!(100)), (2, dec!(100))],
+            &[],
+            &[],
+            1,
+            0,
+            "USDT",
+        );
+        assert_eq!(flat.sharpe, 0.0);
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use rust_decimal::Decimal;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use super::report::Report;
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::paper::paper::PaperClient;
+use crate::exchanges::util::Clock;
+use crate::executor;
+use crate::market::bars::{self, BarBuilder};
+use crate::market::replay::{self, Replayer};
+use crate::oms::oms::{Applied, Oms, SharedOms};
+use crate::risk::kill_switch::KillSwitch;
+use crate::risk::manager::RiskManager;
+use crate::settings::settings::{self, BacktestSettings, Settings, StrategySettings};
+use crate::strategy::strategy::{self, Context, Strategy};
+
+/// Room for the order updates of one strategy callback.
+const UPDATES_CAPACITY: usize = 1024;
+
+/// Plays a recording through the strategies, every account on a `PaperClient` whatever its
+/// exchange. Runs on one task without spawning: each recorded message sets the simulated clock the
+/// oms, the risk checks and the kill switch go by to its receive time, then it and everything it
+/// causes (fills, bars, order updates) goes through the engines, the oms and the strategies before
+/// the next one.
+pub struct Backtest {
+    settings: BacktestSettings,
+    instances: Vec<(Box<dyn Strategy>, Context)>,
+    /// Keyed by exchange_account_id
+    clients: HashMap<String, Arc<PaperClient>>,
+    oms: HashMap<String, SharedOms>,
+    risk: HashMap<String, Arc<RiskManager>>,
+    kill_switch: Arc<KillSwitch>,
+    builders: Vec<BarBuilder>,
+    clock: Clock,
+    /// What the clients send outside of the market data, the cancels
+    updates: broadcast::Receiver<ExchangeEvent>,
+}
+
+impl Backtest {
+    pub fn new(settings: &Settings) -> Result<Self, String> {
+        let coin = &settings.backtest.coin;
+        let mut strategies = vec![];
+        for strategy in &settings.strategies {
+            for pair in &strategy.pairs {
+                let instrument = pair.instrument();
+                if instrument.quote != coin.to_uppercase() {
+                    return Err(format!(
+                        "{}: {} settles in {}, the report is in {}",
+                        strategy.id, instrument, instrument.quote, coin
+                    ));
+                }
+            }
+            let built = strategy::build(strategy).map_err(|e| format!("{}: {}", strategy.id, e))?;
+            strategies.push((strategy.clone(), built));
+        }
+        Ok(Self::with_strategies(settings, strategies))
+    }
+
+    /// Runs `strategies` in place of the ones `settings` would build.
+    pub fn with_strategies(
+        settings: &Settings,
+        strategies: Vec<(StrategySettings, Box<dyn Strategy>)>,
+    ) -> Self {
+        let (sender, updates) = broadcast::channel(UPDATES_CAPACITY);
+        let clock = Clock::simulated(0);
+        let configured: Vec<StrategySettings> = strategies.iter().map(|(s, _)| s.clone()).collect();
+        let mut clients = HashMap::new();
+        let mut oms = HashMap::new();
+        for strategy in &configured {
+            let account = &strategy.exchange_account_id;
+            clients.entry(account.clone()).or_insert_with(|| {
+                Arc::new(PaperClient::new(
+                    account,
+                    settings.paper.clone(),
+                    sender.clone(),
+                ))
+            });
+            oms.entry(account.clone())
+                .or_insert_with(|| Oms::shared_with(clock.clone()));
+        }
+        let risk = settings::instruments_by_account(&configured)
+            .into_iter()
+            .map(|(account, instruments)| {
+                // paper accounts start flat
+                for instrument in &instruments {
+                    let mut account_oms = oms[&account].lock().unwrap();
+                    account_oms.set_position(instrument.clone(), Decimal::ZERO);
+                }
+                let manager =
+                    RiskManager::new(settings.risk.clone(), instruments).with_clock(clock.clone());
+                (account, Arc::new(manager))
+            })
+            .collect();
+
+        let mut builders: Vec<BarBuilder> = vec![];
+        for strategy in &configured {
+            for pair in &strategy.pairs {
+                for spec in strategy.bar_specs() {
+                    let instrument = pair.instrument();
+                    if !builders
+                        .iter()
+                        .any(|b| b.instrument == instrument && b.spec == spec)
+                    {
+                        builders.push(BarBuilder::new(instrument, spec, bars::TOLERANCE_MS));
+                    }
+                }
+            }
+        }
+
+        let instances = strategies
+            .into_iter()
+            .map(|(strategy_settings, strategy)| {
+                let oms = oms[&strategy_settings.exchange_account_id].clone();
+                (strategy, Context::new(strategy_settings, oms))
+            })
+            .collect();
+        Self {
+            settings: settings.backtest.clone(),
+            instances,
+            clients,
+            oms,
+            risk,
+            kill_switch: Arc::new(
+                KillSwitch::new(settings.kill_switch.clone()).with_clock(clock.clone()),
+            ),
+            builders,
+            clock,
+            updates,
+        }
+    }
+
+    /// Plays `[backtest] dir` from `from` to `to`.
+    pub async fn run(mut self) -> Result<Report, String> {
+        let mut replayer = Replayer::open(&self.settings.dir)
+            .map_err(|e| format!("{}: {}", self.settings.dir, e))?;
+        let sample_ms = u128::from(self.settings.sample_ms);
+        let mut samples = vec![];
+        let mut next_sample = None;
+        let mut last = None;
+        let mut events = 0;
+        let played = loop {
+            let recorded = match replayer.next() {
+                Ok(Some(recorded)) => recorded,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(format!("{}: {}", self.settings.dir, e)),
+            };
+            let now = recorded.received;
+            if self
+                .settings
+                .from
+                .is_some_and(|from| now < u128::from(from))
+            {
+                continue;
+            }
+            if self.settings.to.is_some_and(|to| now > u128::from(to)) {
+                break Ok(());
+            }
+            if !self.settings.books && recorded.channel == "book" {
+                continue;
+            }
+            let event = match replay::event(&recorded) {
+                Some(event) => event,
+                None => continue,
+            };
+            self.clock.set(now);
+            // the equity as it was when each sample time passed
+            sample(&mut samples, &mut next_sample, now, sample_ms, || {
+                self.equity()
+            });
+            self.step(event, now).await;
+            events += 1;
+            last = Some(now);
+        };
+        // the end, in place of a sample taken just before the last message
+        if let Some(last) = last {
+            if samples.last().is_some_and(|(t, _)| *t == last) {
+                samples.pop();
+            }
+            samples.push((last, self.equity()));
+        }
+        played?;
+
+        let mut fills = vec![];
+        for client in self.clients.values() {
+            fills.extend_from_slice(client.engine().lock().unwrap().fills());
+        }
+        fills.sort_by_key(|f| f.timestamp);
+        let mut orders = vec![];
+        for oms in self.oms.values() {
+            orders.extend(oms.lock().unwrap().orders().cloned());
+        }
+        Ok(Report::new(
+            &samples,
+            &fills,
+            &orders,
+            self.settings.sample_ms,
+            events,
+            &self.settings.coin,
+        ))
+    }
+
+    /// `event` and everything following from it, in order.
+    async fn step(&mut self, event: ExchangeEvent, now: u128) {
+        let mut queue: VecDeque<ExchangeEvent> = self
+            .builders
+            .iter_mut()
+            .flat_map(|b| b.advance(now))
+            .map(ExchangeEvent::Bar)
+            .collect();
+        queue.push_back(event);
+        while let Some(event) = queue.pop_front() {
+            match &event {
+                ExchangeEvent::Trade(trade) => queue.extend(
+                    self.builders
+                        .iter_mut()
+                        .flat_map(|b| b.push(trade))
+                        .map(ExchangeEvent::Bar),
+                ),
+                ExchangeEvent::OrderUpdate(update) => {
+                    if let Some(oms) = self.oms.get(&update.exchange_account_id) {
+                        match oms.lock().unwrap().apply(update) {
+                            Ok(Applied {
+                                refused: Some(e), ..
+                            })
+                            | Err(e) => println!(
+                                "{}: order update not applied: {}",
+                                update.exchange_account_id, e
+                            ),
+                            Ok(_) => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+            for client in self.clients.values() {
+                let updates = client.engine().lock().unwrap().on_event(&event);
+                queue.extend(updates.into_iter().map(ExchangeEvent::OrderUpdate));
+            }
+            for (strategy, ctx) in &mut self.instances {
+                strategy.on_event(&event, ctx);
+                let commands = ctx.take_commands();
+                if commands.is_empty() {
+                    continue;
+                }
+                let account = &ctx.settings.exchange_account_id;
+                let client = self.clients[account].clone();
+                let risk = self.risk[account].clone();
+                executor::execute(ctx, client.as_ref(), &risk, &self.kill_switch, commands).await;
+            }
+            loop {
+                match self.updates.try_recv() {
+                    Ok(event) => queue.push_back(event),
+                    Err(TryRecvError::Lagged(missed)) => {
+                        println!("backtest: missed {} order updates", missed)
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    /// Every account's equity in the report's coin, added up.
+    fn equity(&self) -> Decimal {
+        let coin = self.settings.coin.to_uppercase();
+        self.clients
+            .values()
+            .filter_map(|c| c.engine().lock().unwrap().balances().remove(&coin))
+            .map(|b| b.equity)
+            .sum()
+    }
+}
+
+/// Adds a sample for every sample time up to `now`, a gap without messages carries the equity
+/// forward. `next` is the next sample time, the first message's when None.
+fn sample(
+    samples: &mut Vec<(u128, Decimal)>,
+    next: &mut Option<u128>,
+    now: u128,
+    sample_ms: u128,
+    equity: impl FnOnce() -> Decimal,
+) {
+    let due = next.get_or_insert(now);
+    if *due > now {
+        return;
+    }
+    let equity = equity();
+    while *due <= now {
+        samples.push((*due, equity));
+        *due = *due - *due % sample_ms + sample_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::event::Trade;
+    use crate::exchanges::instrument::Instrument;
+    use crate::exchanges::r#trait::{OrderType, PlaceOrder, Side, TimeInForce};
+    use crate::exchanges::util;
+    use crate::market::recorder::{Recorded, Recorder};
+    use crate::settings::settings::{Pair, Parameters};
+
+    /// Buys on the first trade and sells on the third.
+    struct RoundTrip {
+        trades: usize,
+    }
+
+    impl Strategy for RoundTrip {
+        fn on_event(&mut self, event: &ExchangeEvent, ctx: &mut Context) {
+            if let ExchangeEvent::Trade(trade) = event {
+                self.trades += 1;
+                let side = match self.trades {
+                    1 => Side::Buy,
+                    3 => Side::Sell,
+                    _ => return,
+                };
+                ctx.place_order(PlaceOrder {
+                    client_order_id: None,
+                    side,
+                    instrument: trade.instrument.clone(),
+                    order_type: OrderType::Market,
+                    qty: dec!(1),
+                    price: None,
+                    time_in_force: TimeInForce::ImmediateOrCancel,
+                    reduce_only: side == Side::Sell,
+                    close_on_trigger: false,
+                });
+            }
+        }
+
+        fn update_parameters(&mut self, _: &Parameters) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip_on_simulated_time() {
+        let dir =
+            std::env::temp_dir().join(format!("decay-backtest-{}", util::millseconds().unwrap()));
+        let btc = Instrument::perpetual("btc", "usdt");
+        let at = 1_680_271_200_000;
+        let mut recorder = Recorder::new(&dir, 3);
+        for (i, price) in [dec!(100), dec!(100), dec!(110), dec!(110)]
+            .iter()
+            .enumerate()
+        {
+            let timestamp = at + i as u128 * 60_000;
+            let trade = Trade {
+                exchange: "bybit".to_string(),
+                instrument: btc.clone(),
+                side: Side::Buy,
+                price: *price,
+                qty: dec!(1),
+                timestamp,
+                received: timestamp,
+            };
+            let recorded = Recorded {
+                venue: "bybit".to_string(),
+                instrument: btc.clone(),
+                channel: "trade".to_string(),
+                received: timestamp,
+                exchange: Some(timestamp),
+                raw: None,
+                normalized: None,
+            }
+            .normalized(&trade);
+            recorder.write(&recorded).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut settings = Settings::load_with(&Default::default()).unwrap();
+        settings.paper.latency_ms = 0;
+        settings.paper.taker_fee_bps = dec!(10);
+        settings.backtest.dir = dir.to_string_lossy().to_string();
+        let strategy = StrategySettings {
+            id: "rt".to_string(),
+            kind: "watch".to_string(),
+            exchange_account_id: "sim".to_string(),
+            pairs: vec![Pair {
+                base: "btc".to_string(),
+                qoute: "usdt".to_string(),
+                kind: Default::default(),
+                expiry: None,
+                strike: None,
+                right: None,
+            }],
+            max_amount: 10.0,
+            parameters: Parameters::new(),
+            bars: vec![],
+        };
+        let backtest = Backtest::with_strategies(
+            &settings,
+            vec![(strategy, Box::new(RoundTrip { trades: 0 }))],
+        );
+        let oms = backtest.oms["sim"].clone();
+        let report = backtest.run().await.unwrap();
+
+        // bought at 100 on the second trade, sold at 110 on the fourth
+        assert_eq!(report.events, 4);
+        assert_eq!(report.fills, 2);
+        assert_eq!(report.turnover, dec!(210));
+        assert_eq!(report.fees, dec!(0.21));
+        assert_eq!(report.pnl, dec!(10) - dec!(0.21));
+        assert_eq!(report.fill_ratio, 1.0);
+        assert_eq!(report.curve.len(), 4);
+        // stamped with the recording's time, not the system's
+        let updated: Vec<u128> = oms.lock().unwrap().orders().map(|o| o.updated).collect();
+        assert!(updated.iter().all(|u| *u >= at && *u < at + 240_000));
+
+        let output = dir.join("report");
+        report.write(&output.to_string_lossy()).unwrap();
+        let csv = fs::read_to_string(dir.join("report.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(dir.join("report.json").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gaps_carry_the_equity_forward() {
+        let (mut samples, mut next) = (vec![], None);
+        sample(&mut samples, &mut next, 1_500, 1_000, || dec!(100));
+        sample(&mut samples, &mut next, 1_900, 1_000, || panic!("not due"));
+        // nothing came for three sample times
+        sample(&mut samples, &mut next, 5_200, 1_000, || dec!(90));
+        assert_eq!(
+            samples,
+            vec![
+                (1_500, dec!(100)),
+                (2_000, dec!(90)),
+                (3_000, dec!(90)),
+                (4_000, dec!(90)),
+                (5_000, dec!(90)),
+            ]
+        );
+        assert_eq!(next, Some(6_000));
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs;
+
+use clap::{Parser, Subcommand};
+
+use crate::backtest::runner::Backtest;
+
+use crate::settings::encrypted::{self, PASSPHRASE_VAR};
+use crate::settings::settings::{Settings, CONFIG_PATH, CREDENTIALS_PATH};
+use crate::settings::sources::{self, env_var, SettingsOptions, ENV_PREFIX};
+
+/// Settings are layered: defaults < --config < --credentials < DECAY_* env vars < --set
+#[derive(Parser, Debug)]
+#[clap(name = "decay")]
+pub struct Cli {
+    /// Config file, the extension can be left out
+    #[clap(long, default_value = CONFIG_PATH)]
+    pub config: String,
+
+    /// Credentials file, the extension can be left out
+    #[clap(long, default_value = CREDENTIALS_PATH)]
+    pub credentials: String,
+
+    /// Overrides a single setting, e.g. --set exchanges.bybit.recv_window=10000
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = sources::parse_override)]
+    pub overrides: Vec<(String, String)>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Encrypted credentials files, the passphrase is read from DECAY_CREDENTIALS_PASSPHRASE
+    /// or asked for
+    #[clap(subcommand)]
+    Credentials(CredentialsCommand),
+    /// Runs the strategies over the recording in [backtest] dir on paper accounts and writes the
+    /// report, takes the same settings as the engine
+    Backtest,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CredentialsCommand {
+    /// Encrypts a plain toml credentials file, to <INPUT>.enc unless --output is given
+    Encrypt {
+        input: String,
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Prints the decrypted credentials, or writes them to --output
+    Decrypt {
+        input: String,
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Re-encrypts in place with a fresh salt and nonce, optionally replacing entries and the
+    /// passphrase (DECAY_NEW_CREDENTIALS_PASSPHRASE or asked for)
+    Rotate {
+        input: String,
+        /// Replaces one entry, e.g. --set exchanges.bybit.api_key=NEW
+        #[clap(long = "set", value_name = "KEY=VALUE", value_parser = sources::parse_override)]
+        entries: Vec<(String, String)>,
+        #[clap(long)]
+        new_passphrase: bool,
+    },
+}
+
+impl Cli {
+    pub fn settings_options(&self) -> SettingsOptions {
+        SettingsOptions {
+            config_path: self.config.clone(),
+            credentials_path: self.credentials.clone(),
+            env_prefix: ENV_PREFIX.to_string(),
+            env: None,
+            overrides: self.overrides.clone(),
+            credentials_passphrase: None,
+        }
+    }
+}
+
+/// Plays `[backtest] dir` through the strategies and writes the report to `[backtest] output`.
+pub async fn run_backtest(settings: &Settings) -> Result<(), String> {
+    let report = Backtest::new(settings)?.run().await?;
+    let output = &settings.backtest.output;
+    report
+        .write(output)
+        .map_err(|e| format!("{}: {}", output, e))?;
+    println!(
+        "backtest: {} events, pnl {}, max drawdown {}, sharpe {:.2}, turnover {}, fill ratio {:.2}, fees {}",
+        report.events,
+        report.pnl,
+        report.max_drawdown,
+        report.sharpe,
+        report.turnover,
+        report.fill_ratio,
+        report.fees
+    );
+    println!(
+        "backtest: report in {}.json, pnl curve in {}.csv",
+        output, output
+    );
+    Ok(())
+}
+
+/// Runs a credentials subcommand, neither the settings are loaded nor the engine started for those.
+pub fn run_credentials(command: &CredentialsCommand) -> Result<(), String> {
+    let var = env_var(ENV_PREFIX, PASSPHRASE_VAR);
+    let passphrase = |confirm: bool| {
+        encrypted::passphrase(std::env::var(&var).ok(), &var, confirm).map_err(|e| e.to_string())
+    };
+
+    match command {
+        CredentialsCommand::Encrypt { input, output } => {
+            let plaintext = read(input)?;
+            if encrypted::is_encrypted(&plaintext) {
+                return Err(format!("{} is already encrypted", input));
+            }
+            plaintext
+                .parse::<toml::Value>()
+                .map_err(|e| format!("{} is not valid toml: {}", input, e))?;
+            let output = output.clone().unwrap_or_else(|| format!("{}.enc", input));
+            let encrypted =
+                encrypted::encrypt(&plaintext, &passphrase(true)?).map_err(|e| e.to_string())?;
+            write(&output, &encrypted)?;
+            println!("wrote {}, remove {} once it works", output, input);
+        }
+        CredentialsCommand::Decrypt { input, output } => {
+            let plaintext = encrypted::decrypt(&read(input)?, &passphrase(false)?)
+                .map_err(|e| e.to_string())?;
+            match output {
+                Some(output) => write(output, &plaintext)?,
+                None => print!("{}", plaintext),
+            }
+        }
+        CredentialsCommand::Rotate {
+            input,
+            entries,
+            new_passphrase,
+        } => {
+            let old = passphrase(false)?;
+            let mut plaintext =
+                encrypted::decrypt(&read(input)?, &old).map_err(|e| e.to_string())?;
+            if !entries.is_empty() {
+                plaintext = set_entries(&plaintext, entries)?;
+            }
+            let passphrase = match new_passphrase {
+                false => old,
+                true => {
+                    let var = env_var(ENV_PREFIX, &format!("NEW_{}", PASSPHRASE_VAR));
+                    println!("new passphrase:");
+                    encrypted::passphrase(std::env::var(&var).ok(), &var, true)
+                        .map_err(|e| e.to_string())?
+                }
+            };
+            let encrypted =
+                encrypted::encrypt(&plaintext, &passphrase).map_err(|e| e.to_string())?;
+            write(input, &encrypted)?;
+            println!("rotated {}", input);
+        }
+    }
+    Ok(())
+}
+
+// Replaces `a.b.c=value` entries, missing tables are created
+fn set_entries(plaintext: &str, entries: &[(String, String)]) -> Result<String, String> {
+    let mut root: toml::Value = plaintext
+        .parse()
+        .map_err(|e| format!("the credentials are not valid toml: {}", e))?;
+    for (key, value) in entries {
+        let mut table = &mut root;
+        let path: Vec<&str> = key.split('.').collect();
+        for part in &path[..path.len() - 1] {
+            table = table
+                .as_table_mut()
+                .ok_or_else(|| format!("{} <- is not a table", key))?
+                .entry(part.to_string())
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+        }
+        table
+            .as_table_mut()
+            .ok_or_else(|| format!("{} <- is not a table", key))?
+            .insert(
+                path[path.len() - 1].to_string(),
+                toml::Value::String(value.clone()),
+            );
+    }
+    toml::to_string(&root).map_err(|e| e.to_string())
+}
+
+fn read(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+// Written next to the target first so a failed write never leaves half a file
+fn write(path: &str, contents: &str) -> Result<(), String> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_replaced() {
+        let plaintext = "[exchanges.bybit]\napi_key = \"old\"\nsecret_key = \"secret\"\n";
+        let entries = vec![
+            sources::parse_override("exchanges.bybit.api_key=new").unwrap(),
+            sources::parse_override("control_api.token=token").unwrap(),
+        ];
+        let replaced: toml::Value = set_entries(plaintext, &entries).unwrap().parse().unwrap();
+
+        assert_eq!(
+            replaced["exchanges"]["bybit"]["api_key"].as_str(),
+            Some("new")
+        );
+        assert_eq!(
+            replaced["exchanges"]["bybit"]["secret_key"].as_str(),
+            Some("secret")
+        );
+        assert_eq!(replaced["control_api"]["token"].as_str(), Some("token"));
+        assert!(set_entries(
+            plaintext,
+            &[sources::parse_override("exchanges.bybit.api_key.x=1").unwrap()]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// One mutating request and what came of it, written as a single json line.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    /// Unix time in ms
+    pub time: u128,
+    pub remote: String,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub request: &'a Value,
+    pub status: u16,
+    pub response: &'a Value,
+}
+
+/// Append-only log of everything the control api was asked to change.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opened once at startup so an unwritable path stops the api from starting at all.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+
+use super::audit::{AuditEntry, AuditLog};
+use crate::exchanges::r#trait::ExchangeClient;
+use crate::exchanges::util;
+use crate::executor::{self, Control, Controls};
+use crate::risk::kill_switch::{KillSwitch, Trip};
+use crate::settings::settings::{self, Parameters, Settings};
+use crate::strategy::strategy;
+
+/// Bodies above this are refused before they're parsed.
+const MAX_BODY: usize = 64 * 1024;
+
+/// What a request gets back, always json.
+#[derive(Debug)]
+pub struct Reply {
+    pub status: StatusCode,
+    pub body: Value,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            body,
+        }
+    }
+
+    fn error(status: StatusCode, message: String) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+/// Localhost json api over the running settings and instances:
+///
+/// - `GET /settings` settings with secrets redacted
+/// - `PUT|PATCH /strategies/<id>/parameters` replaces or merges (`null` removes) parameters
+/// - `POST /strategies/<id>/pause`, `POST /strategies/<id>/resume`
+/// - `POST /cancel-all[?account=<id>]`, `POST /flatten[?account=<id>]`
+/// - `GET /kill-switch`, `POST /kill-switch/trip`, `POST /kill-switch/rearm`
+///
+/// Everything but `GET` needs `Authorization: Bearer <control_api.token>` and is audited.
+pub struct ControlApi {
+    /// Shared with the settings watcher.
+    settings: Arc<RwLock<Settings>>,
+    controls: Controls,
+    /// Keyed by exchange_account_id
+    clients: HashMap<String, Arc<dyn ExchangeClient>>,
+    kill_switch: Arc<KillSwitch>,
+    audit: AuditLog,
+}
+
+impl ControlApi {
+    pub fn new(
+        settings: Arc<RwLock<Settings>>,
+        controls: Controls,
+        clients: HashMap<String, Arc<dyn ExchangeClient>>,
+        kill_switch: Arc<KillSwitch>,
+    ) -> std::io::Result<Self> {
+        let audit = AuditLog::open(&settings.read().unwrap().control_api.audit_log)?;
+        Ok(Self {
+            settings,
+            controls,
+            clients,
+            kill_switch,
+            audit,
+        })
+    }
+
+    /// Listens on `control_api.listen` until the task is dropped.
+    pub async fn serve(self) -> hyper::Result<()> {
+        // validated to be a loopback address when the settings were loaded
+        let addr: SocketAddr = self
+            .settings
+            .read()
+            .unwrap()
+            .control_api
+            .listen
+            .parse()
+            .unwrap();
+        let api = Arc::new(self);
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let api = api.clone();
+            let remote = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(api.respond(request, remote).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        println!("control api listening on http://{}", addr);
+        server.await
+    }
+
+    async fn respond(&self, request: Request<Body>, remote: SocketAddr) -> Response<Body> {
+        let (parts, body) = request.into_parts();
+        let authorization = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let too_big = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|length| length > MAX_BODY);
+
+        let reply = match hyper::body::to_bytes(body).await {
+            _ if too_big => Reply::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("bodies are limited to {} bytes", MAX_BODY),
+            ),
+            Ok(bytes) => {
+                self.handle(
+                    &parts.method,
+                    parts.uri.path(),
+                    parts.uri.query(),
+                    authorization,
+                    &bytes,
+                    remote,
+                )
+                .await
+            }
+            Err(e) => Reply::error(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+
+        Response::builder()
+            .status(reply.status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(reply.body.to_string()))
+            .unwrap()
+    }
+
+    /// Routes one request, mutating ones are authorized and written to the audit log.
+    pub async fn handle(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        authorization: Option<&str>,
+        body: &[u8],
+        remote: SocketAddr,
+    ) -> Reply {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if *method == Method::GET {
+            return match segments.as_slice() {
+                ["settings"] => Reply::ok(self.settings.read().unwrap().redacted()),
+                ["kill-switch"] => Reply::ok(json!(self.kill_switch.status())),
+                _ => Reply::error(StatusCode::NOT_FOUND, format!("no route {}", path)),
+            };
+        }
+
+        let request = match body.is_empty() {
+            true => Value::Null,
+            false => match serde_json::from_slice(body) {
+                Ok(value) => value,
+                Err(e) => {
+                    return Reply::error(StatusCode::BAD_REQUEST, format!("invalid json: {}", e))
+                }
+            },
+        };
+        let account = query
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("account="));
+
+        let reply = match self.authorize(authorization) {
+            Err(reply) => reply,
+            Ok(()) => match (method, segments.as_slice()) {
+                (&Method::PUT, ["strategies", id, "parameters"]) => {
+                    self.update_parameters(id, &request, false)
+                }
+                (&Method::PATCH, ["strategies", id, "parameters"]) => {
+                    self.update_parameters(id, &request, true)
+                }
+                (&Method::POST, ["strategies", id, "pause"]) => self.send(id, Control::Pause),
+                (&Method::POST, ["strategies", id, "resume"]) => self.send(id, Control::Resume),
+                (&Method::POST, ["cancel-all"]) => self.cancel_all(account, false).await,
+                (&Method::POST, ["flatten"]) => self.cancel_all(account, true).await,
+                (&Method::POST, ["kill-switch", "trip"]) => Reply::ok(json!({
+                    "tripped": self.kill_switch.trip(Trip::Manual),
+                    "status": self.kill_switch.status(),
+                })),
+                (&Method::POST, ["kill-switch", "rearm"]) => Reply::ok(json!({
+                    "rearmed": self.kill_switch.rearm(),
+                    "status": self.kill_switch.status(),
+                })),
+                _ => Reply::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no route {} {}", method, path),
+                ),
+            },
+        };
+
+        let entry = AuditEntry {
+            time: util::millseconds().unwrap_or_default(),
+            remote: remote.to_string(),
+            method: method.as_str(),
+            path,
+            request: &request,
+            status: reply.status.as_u16(),
+            response: &reply.body,
+        };
+        if let Err(e) = self.audit.record(&entry) {
+            println!("control api: could not write the audit log: {}", e);
+        }
+        reply
+    }
+
+    fn authorize(&self, authorization: Option<&str>) -> Result<(), Reply> {
+        let settings = self.settings.read().unwrap();
+        let token = settings.control_api.token.as_ref().ok_or_else(|| {
+            Reply::error(
+                StatusCode::FORBIDDEN,
+                "no control_api.token in credentials.toml, mutating endpoints are disabled"
+                    .to_string(),
+            )
+        })?;
+        let given = authorization
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        match util::verify_token(given, token) {
+            true => Ok(()),
+            false => Err(Reply::error(
+                StatusCode::UNAUTHORIZED,
+                "invalid bearer token".to_string(),
+            )),
+        }
+    }
+
+    fn update_parameters(&self, id: &str, request: &Value, merge: bool) -> Reply {
+        let given = match request.as_object() {
+            Some(given) => given,
+            None => {
+                return Reply::error(
+                    StatusCode::BAD_REQUEST,
+                    "parameters have to be a json object".to_string(),
+                )
+            }
+        };
+
+        let mut settings = self.settings.write().unwrap();
+        let running = match settings.strategies.iter_mut().find(|s| s.id == id) {
+            Some(running) => running,
+            None => return Reply::error(StatusCode::NOT_FOUND, format!("no strategy {}", id)),
+        };
+        let mut parameters: Parameters = match merge {
+            true => running.parameters.clone(),
+            false => Parameters::new(),
+        };
+        for (key, value) in given {
+            match value {
+                Value::Null => parameters.remove(key),
+                value => parameters.insert(key.clone(), value.clone()),
+            };
+        }
+
+        let mut updated = running.clone();
+        updated.parameters = parameters;
+        if let Err(e) = strategy::build(&updated) {
+            return Reply::error(StatusCode::BAD_REQUEST, e);
+        }
+        let reply = self.send(id, Control::Update(updated.clone()));
+        if reply.status == StatusCode::OK {
+            *running = updated;
+        }
+        reply
+    }
+
+    fn send(&self, id: &str, control: Control) -> Reply {
+        match self.controls.get(id) {
+            None => Reply::error(StatusCode::NOT_FOUND, format!("no strategy {}", id)),
+            Some(sender) => match sender.send(control) {
+                Ok(()) => Reply::ok(json!({ "strategy": id, "sent": true })),
+                Err(_) => Reply::error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("{} is not running", id),
+                ),
+            },
+        }
+    }
+
+    async fn cancel_all(&self, account: Option<&str>, flatten: bool) -> Reply {
+        let mut instruments =
+            settings::instruments_by_account(&self.settings.read().unwrap().strategies);
+        if let Some(account) = account {
+            if !self.clients.contains_key(account) {
+                return Reply::error(StatusCode::NOT_FOUND, format!("no account {}", account));
+            }
+            instruments.retain(|a, _| a == account);
+        }
+
+        let reports = executor::halt(&self.clients, instruments, flatten).await;
+        Reply {
+            status: match reports.values().any(|r| !r.failed.is_empty()) {
+                true => StatusCode::BAD_GATEWAY,
+                false => StatusCode::OK,
+            },
+            body: json!(reports),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use crate::exchanges::fake::FakeClient;
+    use crate::exchanges::instrument::Instrument;
+    use crate::exchanges::r#trait::{OrderType, PlaceOrder, Side, TimeInForce};
+    use crate::settings::secret::REDACTED;
+    use crate::settings::sources::SettingsOptions;
+
+    const TOKEN: &str = "Bearer let-me-in";
+
+    struct Setup {
+        api: ControlApi,
+        controls: mpsc::UnboundedReceiver<Control>,
+        client: Arc<FakeClient>,
+        audit_log: String,
+    }
+
+    fn setup(name: &str, token: bool) -> Setup {
+        let dir = std::env::temp_dir().join("decay_control_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| {
+            dir.join(format!("{}_{}", name, file))
+                .to_string_lossy()
+                .to_string()
+        };
+        let audit_log = path("audit.log");
+        let _ = fs::remove_file(&audit_log);
+        fs::write(
+            path("config.toml"),
+            format!(
+                r#"
+                [[strategies]]
+                id = "ada"
+                kind = "watch"
+                exchange_account_id = "main"
+                pairs = [{{ base = "ada", qoute = "usdt" }}]
+                max_amount = 0.1
+                [strategies.parameters]
+                min_qty = 1
+
+                [exchanges.bybit]
+                [control_api]
+                audit_log = "{}"
+                "#,
+                audit_log.replace('\\', "\\\\")
+            ),
+        )
+        .unwrap();
+        fs::write(
+            path("credentials.toml"),
+            format!(
+                "[exchanges.bybit]\napi_key = \"key\"\nsecret_key = \"secret\"\nexchange_account_id = \"main\"\n{}",
+                match token {
+                    true => "[control_api]\ntoken = \"let-me-in\"\n",
+                    false => "",
+                }
+            ),
+        )
+        .unwrap();
+        let settings = Settings::load_with(&SettingsOptions {
+            config_path: path("config.toml"),
+            credentials_path: path("credentials.toml"),
+            env: Some(Default::default()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (sender, controls) = mpsc::unbounded_channel();
+        let client = Arc::new(FakeClient::default());
+        let mut clients: HashMap<String, Arc<dyn ExchangeClient>> = HashMap::new();
+        clients.insert("main".to_string(), client.clone());
+        let api = ControlApi::new(
+            Arc::new(RwLock::new(settings)),
+            HashMap::from([("ada".to_string(), sender)]),
+            clients,
+            Arc::new(KillSwitch::new(Default::default())),
+        )
+        .unwrap();
+        Setup {
+            api,
+            controls,
+            client,
+            audit_log,
+        }
+    }
+
+    async fn call(api: &ControlApi, method: Method, path: &str, body: &str) -> Reply {
+        let remote = "127.0.0.1:50000".parse().unwrap();
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        api.handle(&method, path, query, Some(TOKEN), body.as_bytes(), remote)
+            .await
+    }
+
+    #[tokio::test]
+    async fn settings_are_redacted() {
+        let Setup { api, .. } = setup("redacted", true);
+        let reply = call(&api, Method::GET, "/settings", "").await;
+
+        assert_eq!(reply.status, StatusCode::OK);
+        let credentials = &reply.body["exchanges_credentials"]["bybit"];
+        assert_eq!(credentials["api_key"], REDACTED);
+        assert_eq!(credentials["secret_key"], REDACTED);
+        assert_eq!(reply.body["control_api"]["token"], REDACTED);
+        assert_eq!(reply.body["strategies"][0]["id"], "ada");
+        assert!(!reply.body.to_string().contains("let-me-in"));
+    }
+
+    #[tokio::test]
+    async fn mutating_needs_the_token() {
+        // the receiver has to stay alive for the instance to count as running
+        let Setup {
+            api,
+            controls: _controls,
+            ..
+        } = setup("auth", true);
+        let remote = "127.0.0.1:50000".parse().unwrap();
+        let pause = |authorization| {
+            api.handle(
+                &Method::POST,
+                "/strategies/ada/pause",
+                None,
+                authorization,
+                b"",
+                remote,
+            )
+        };
+
+        assert_eq!(pause(None).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            pause(Some("Bearer nope")).await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(pause(Some(TOKEN)).await.status, StatusCode::OK);
+
+        let Setup { api, .. } = setup("no_token", false);
+        let reply = call(&api, Method::POST, "/strategies/ada/pause", "").await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn parameters_are_updated_and_audited() {
+        let Setup {
+            api,
+            mut controls,
+            audit_log,
+            ..
+        } = setup("parameters", true);
+
+        let reply = call(
+            &api,
+            Method::PATCH,
+            "/strategies/ada/parameters",
+            r#"{"min_qty": "lots"}"#,
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert!(controls.try_recv().is_err());
+
+        let reply = call(
+            &api,
+            Method::PATCH,
+            "/strategies/ada/parameters",
+            r#"{"min_qty": 5, "note": "x"}"#,
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        match controls.try_recv().unwrap() {
+            Control::Update(settings) => {
+                assert_eq!(settings.parameters["min_qty"], 5);
+                assert_eq!(settings.parameters["note"], "x");
+            }
+            other => panic!("expected an update, got {:?}", other),
+        }
+        let running = api.settings.read().unwrap().strategies[0].clone();
+        assert_eq!(running.parameters["min_qty"], 5);
+
+        let reply = call(&api, Method::PUT, "/strategies/ada/parameters", "{}").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(api.settings.read().unwrap().strategies[0]
+            .parameters
+            .is_empty());
+
+        let reply = call(&api, Method::PUT, "/strategies/btc/parameters", "{}").await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+
+        let audit = fs::read_to_string(&audit_log).unwrap();
+        let statuses: Vec<u64> = audit
+            .lines()
+            .map(|l| {
+                serde_json::from_str::<Value>(l).unwrap()["status"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(statuses, vec![400, 200, 200, 404]);
+    }
+
+    #[tokio::test]
+    async fn flatten_cancels_then_closes() {
+        let Setup { api, client, .. } = setup("flatten", true);
+        let ada = Instrument::perpetual("ada", "usdt");
+        client.set_position(ada.clone(), Side::Buy, dec!(2));
+        client
+            .place_order(PlaceOrder {
+                client_order_id: None,
+                side: Side::Buy,
+                instrument: ada,
+                order_type: OrderType::Limit,
+                qty: dec!(1),
+                price: Some(dec!(1)),
+                time_in_force: TimeInForce::GoodTillCancel,
+                reduce_only: false,
+                close_on_trigger: false,
+            })
+            .await
+            .unwrap();
+
+        let reply = call(&api, Method::POST, "/flatten?account=main", "").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        // the resting order is canceled, the long closed by a market sell
+        let orders = client.orders.lock().unwrap().clone();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order_id, "fake-2");
+        assert_eq!(
+            (orders[0].order_type, orders[0].side, orders[0].qty),
+            (OrderType::Market, Side::Sell, dec!(2))
+        );
+
+        let reply = call(&api, Method::POST, "/cancel-all?account=other", "").await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn kill_switch_is_tripped_and_rearmed() {
+        let Setup { api, .. } = setup("kill_switch", true);
+
+        let reply = call(&api, Method::POST, "/kill-switch/trip", "").await;
+        assert_eq!(reply.body["tripped"], true);
+        let reply = call(&api, Method::GET, "/kill-switch", "").await;
+        assert_eq!(reply.body["tripped"]["reason"], "manual");
+
+        let reply = call(&api, Method::POST, "/kill-switch/rearm", "").await;
+        assert_eq!(reply.body["rearmed"]["reason"], "manual");
+        assert_eq!(reply.body["status"]["tripped"], Value::Null);
+        assert_eq!(api.kill_switch.tripped(), None);
+    }
+}
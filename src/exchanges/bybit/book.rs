@@ -0,0 +1,174 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use super::codec::BybitCodec;
+use crate::exchanges::error::{ExchangeError, Result};
+use crate::exchanges::instrument::{Instrument, SymbolCodec};
+use crate::exchanges::r#trait::Side;
+use crate::market::order_book::{BookMessage, LevelChange};
+
+pub const TOPIC: &str = "orderBookL2_25";
+
+#[derive(Deserialize)]
+struct Message {
+    topic: String,
+    #[serde(rename = "type")]
+    kind: String,
+    data: Value,
+    #[serde(deserialize_with = "number_or_string")]
+    cross_seq: u64,
+    #[serde(deserialize_with = "number_or_string")]
+    timestamp_e6: u64,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    price: Decimal,
+    side: Side,
+    // left out of deletes
+    #[serde(default)]
+    size: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Inverse(Vec<Entry>),
+    Linear { order_book: Vec<Entry> },
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    #[serde(default)]
+    delete: Vec<Entry>,
+    #[serde(default)]
+    update: Vec<Entry>,
+    #[serde(default)]
+    insert: Vec<Entry>,
+}
+
+/// Reads an `orderBookL2_25.<symbol>` message, `cross_seq` is the sequence.
+pub fn parse(codec: &BybitCodec, raw: &str) -> Result<(Instrument, BookMessage)> {
+    let parse_err = |e: serde_json::Error| {
+        ExchangeError::parsing_error(format!("{} <- is not an order book message: {}", raw, e))
+    };
+    let message: Message = serde_json::from_str(raw).map_err(parse_err)?;
+    let symbol = message
+        .topic
+        .strip_prefix(TOPIC)
+        .and_then(|s| s.strip_prefix('.'))
+        .ok_or_else(|| {
+            ExchangeError::parsing_error(format!("{} <- is not an order book topic", message.topic))
+        })?;
+    let instrument = codec.decode(symbol)?;
+    let seq = message.cross_seq;
+    let timestamp = u128::from(message.timestamp_e6) / 1000;
+    let book = match message.kind.as_str() {
+        "snapshot" => {
+            let levels = match serde_json::from_value(message.data).map_err(parse_err)? {
+                Snapshot::Inverse(levels) | Snapshot::Linear { order_book: levels } => levels,
+            };
+            BookMessage::Snapshot {
+                levels: changes(levels),
+                seq,
+                timestamp,
+            }
+        }
+        "delta" => {
+            let delta: Delta = serde_json::from_value(message.data).map_err(parse_err)?;
+            BookMessage::Delta {
+                delete: changes(delta.delete),
+                update: changes(delta.update),
+                insert: changes(delta.insert),
+                seq,
+                timestamp,
+            }
+        }
+        other => {
+            return Err(ExchangeError::parsing_error(format!(
+                "{} <- is not an order book message type",
+                other
+            )))
+        }
+    };
+    Ok((instrument, book))
+}
+
+fn changes(entries: Vec<Entry>) -> Vec<LevelChange> {
+    entries
+        .into_iter()
+        .map(|e| LevelChange {
+            side: e.side,
+            price: e.price,
+            size: e.size.unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn number_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Number(n) => n
+            .as_u64()
+            .ok_or_else(|| serde::de::Error::custom(format!("{} is not a sequence", n))),
+        Value::String(s) => s.parse().map_err(serde::de::Error::custom),
+        other => Err(serde::de::Error::custom(format!(
+            "{} is not a sequence",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::bybit::bybit::Category;
+    use crate::market::order_book::OrderBook;
+
+    const INVERSE: BybitCodec = BybitCodec {
+        default_category: Category::Inverse,
+    };
+
+    #[test]
+    fn snapshot_and_delta() {
+        let snapshot = r#"{"topic":"orderBookL2_25.BTCUSD","type":"snapshot","data":[
+            {"price":"2999.00","symbol":"BTCUSD","id":29990000,"side":"Buy","size":9},
+            {"price":"3001.00","symbol":"BTCUSD","id":30010000,"side":"Sell","size":10}],
+            "cross_seq":11518,"timestamp_e6":1555647164875373}"#;
+        let delta = r#"{"topic":"orderBookL2_25.BTCUSD","type":"delta","data":{
+            "delete":[{"price":"3001.00","symbol":"BTCUSD","id":30010000,"side":"Sell"}],
+            "update":[{"price":"2999.00","symbol":"BTCUSD","id":29990000,"side":"Buy","size":8}],
+            "insert":[{"price":"2999.50","symbol":"BTCUSD","id":29995000,"side":"Sell","size":1}],
+            "transactTimeE6":0},"cross_seq":11519,"timestamp_e6":1555647221331673}"#;
+
+        let (instrument, message) = parse(&INVERSE, snapshot).unwrap();
+        assert_eq!(instrument, Instrument::perpetual("btc", "usd"));
+        let mut book = OrderBook::new(instrument);
+        book.apply(message).unwrap();
+        assert_eq!(book.timestamp, 1555647164875);
+
+        let (_, message) = parse(&INVERSE, delta).unwrap();
+        book.apply(message).unwrap();
+        assert_eq!(book.best_bid().unwrap().size, dec!(8));
+        assert_eq!(book.best_ask().unwrap().price, dec!(2999.5));
+    }
+
+    #[test]
+    fn linear_snapshot() {
+        let linear = BybitCodec {
+            default_category: Category::Linear,
+        };
+        let raw = r#"{"topic":"orderBookL2_25.BTCUSDT","type":"snapshot","data":{"order_book":[
+            {"price":"2999.00","symbol":"BTCUSDT","id":"29990000","side":"Buy","size":0.5}]},
+            "cross_seq":"11518","timestamp_e6":"1555647164875373"}"#;
+        let (_, message) = parse(&linear, raw).unwrap();
+        assert!(matches!(
+            message,
+            BookMessage::Snapshot { levels, seq: 11518, .. } if levels[0].size == dec!(0.5)
+        ));
+        assert!(parse(&linear, r#"{"topic":"trade.BTCUSDT"}"#).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use rust_decimal::Decimal;
+
+use super::bybit::Category;
+use crate::exchanges::error::{ExchangeError, Result};
+use crate::exchanges::instrument::{self, Instrument, InstrumentKind, OptionRight, SymbolCodec};
+
+const VENUE: &str = "bybit";
+// Longest first so `BTCUSDT` isn't read as `BTCUSD` + `T`
+const QUOTES: &[&str] = &["USDT", "USDC", "EUR", "BTC", "ETH", "DAI", "USD"];
+const MONTH_CODES: &[char] = &['F', 'G', 'H', 'J', 'K', 'M', 'N', 'Q', 'U', 'V', 'X', 'Z'];
+
+/// Bybit symbols:
+///
+/// - spot and USDT perpetuals `BTCUSDT`, told apart only by `default_category`
+/// - USDC perpetuals `BTCPERP`, inverse perpetuals `BTCUSD`
+/// - inverse futures `BTCUSDH23` (expiring the last friday of the month)
+/// - USDC futures `BTC-29DEC23`, USDT futures `BTCUSDT-29DEC23`
+/// - options `BTC-30DEC22-18000-C`, settled in USDC
+pub struct BybitCodec {
+    pub default_category: Category,
+}
+
+impl BybitCodec {
+    pub fn category(instrument: &Instrument) -> Category {
+        match instrument.kind {
+            InstrumentKind::Spot => Category::Spot,
+            InstrumentKind::Option => Category::Option,
+            _ if instrument.quote == "USD" => Category::Inverse,
+            _ => Category::Linear,
+        }
+    }
+}
+
+impl SymbolCodec for BybitCodec {
+    fn encode(&self, instrument: &Instrument) -> Result<String> {
+        let Instrument { base, quote, .. } = instrument;
+        let expiry = || {
+            instrument
+                .expiry
+                .ok_or_else(|| instrument::unsupported(VENUE, instrument))
+        };
+        match (instrument.kind, quote.as_str()) {
+            (InstrumentKind::Spot, _) => Ok(format!("{}{}", base, quote)),
+            (InstrumentKind::Perpetual, "USDC") => Ok(format!("{}PERP", base)),
+            (InstrumentKind::Perpetual, "USD" | "USDT") => Ok(format!("{}{}", base, quote)),
+            (InstrumentKind::Future, "USD") => {
+                let expiry = expiry()?;
+                if expiry != last_friday(expiry.year(), expiry.month()) {
+                    return Err(instrument::unsupported(VENUE, instrument));
+                }
+                Ok(format!(
+                    "{}USD{}{:02}",
+                    base,
+                    MONTH_CODES[expiry.month0() as usize],
+                    expiry.year() % 100
+                ))
+            }
+            (InstrumentKind::Future, "USDC") => Ok(format!("{}-{}", base, fmt_date(expiry()?))),
+            (InstrumentKind::Future, "USDT") => Ok(format!("{}USDT-{}", base, fmt_date(expiry()?))),
+            (InstrumentKind::Option, "USDC") => match (instrument.strike, instrument.right) {
+                (Some(strike), Some(right)) => Ok(format!(
+                    "{}-{}-{}-{}",
+                    base,
+                    fmt_date(expiry()?),
+                    strike.normalize(),
+                    match right {
+                        OptionRight::Call => "C",
+                        OptionRight::Put => "P",
+                    }
+                )),
+                _ => Err(instrument::unsupported(VENUE, instrument)),
+            },
+            _ => Err(instrument::unsupported(VENUE, instrument)),
+        }
+    }
+
+    fn decode(&self, symbol: &str) -> Result<Instrument> {
+        let unknown =
+            || ExchangeError::parsing_error(format!("{} <- is not a bybit symbol", symbol));
+        let parts: Vec<&str> = symbol.split('-').collect();
+        match parts.as_slice() {
+            [base, expiry, strike, right] => {
+                let right = match *right {
+                    "C" => OptionRight::Call,
+                    "P" => OptionRight::Put,
+                    _ => return Err(unknown()),
+                };
+                let strike: Decimal = strike.parse().map_err(|_| unknown())?;
+                let expiry = parse_date(expiry).ok_or_else(unknown)?;
+                Ok(Instrument::option(base, "USDC", expiry, strike, right))
+            }
+            [pair, expiry] => {
+                let expiry = parse_date(expiry).ok_or_else(unknown)?;
+                match split_pair(pair) {
+                    Some((base, quote)) => Ok(Instrument::future(base, quote, expiry)),
+                    None => Ok(Instrument::future(pair, "USDC", expiry)),
+                }
+            }
+            [symbol] => {
+                if let Some(base) = symbol.strip_suffix("PERP") {
+                    return Ok(Instrument::perpetual(base, "USDC"));
+                }
+                if let Some(instrument) = inverse_future(symbol) {
+                    return Ok(instrument);
+                }
+                let (base, quote) = split_pair(symbol).ok_or_else(unknown)?;
+                match self.default_category {
+                    Category::Spot if quote != "USD" => Ok(Instrument::spot(base, quote)),
+                    _ => Ok(Instrument::perpetual(base, quote)),
+                }
+            }
+            _ => Err(unknown()),
+        }
+    }
+}
+
+fn split_pair(symbol: &str) -> Option<(&str, &str)> {
+    QUOTES.iter().find_map(|quote| {
+        symbol
+            .strip_suffix(quote)
+            .filter(|base| !base.is_empty())
+            .map(|base| (base, *quote))
+    })
+}
+
+// BTCUSDH23
+fn inverse_future(symbol: &str) -> Option<Instrument> {
+    if symbol.len() < 7 || !symbol.is_char_boundary(symbol.len() - 3) {
+        return None;
+    }
+    let (pair, code) = symbol.split_at(symbol.len() - 3);
+    let base = pair.strip_suffix("USD").filter(|b| !b.is_empty())?;
+    let (code, year) = code.split_at(1);
+    let month = MONTH_CODES.iter().position(|c| code.starts_with(*c))? as u32 + 1;
+    let year: i32 = year.parse().ok()?;
+    Some(Instrument::future(
+        base,
+        "USD",
+        last_friday(2000 + year, month),
+    ))
+}
+
+fn last_friday(year: i32, month: u32) -> NaiveDate {
+    let (next_year, next_month) = match month {
+        12 => (year + 1, 1),
+        m => (year, m + 1),
+    };
+    let mut day = NaiveDate::from_ymd_opt(next_year, next_month, 1).unwrap() - Duration::days(1);
+    while day.weekday() != Weekday::Fri {
+        day -= Duration::days(1);
+    }
+    day
+}
+
+// 29DEC23, days below 10 without a leading zero
+fn fmt_date(date: NaiveDate) -> String {
+    date.format("%-d%b%y").to_string().to_uppercase()
+}
+
+fn parse_date(raw: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(raw, "%d%b%y").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    const LINEAR: BybitCodec = BybitCodec {
+        default_category: Category::Linear,
+    };
+
+    #[test]
+    fn round_trips() {
+        for (symbol, instrument) in [
+            ("BTCUSDT", Instrument::perpetual("btc", "usdt")),
+            ("BTCPERP", Instrument::perpetual("btc", "usdc")),
+            ("BTCUSD", Instrument::perpetual("btc", "usd")),
+            (
+                "BTCUSDH23",
+                Instrument::future("btc", "usd", date(2023, 3, 31)),
+            ),
+            (
+                "BTCUSDZ22",
+                Instrument::future("btc", "usd", date(2022, 12, 30)),
+            ),
+            (
+                "BTC-29DEC23",
+                Instrument::future("btc", "usdc", date(2023, 12, 29)),
+            ),
+            (
+                "ETHUSDT-5JAN24",
+                Instrument::future("eth", "usdt", date(2024, 1, 5)),
+            ),
+            (
+                "BTC-30DEC22-18000-C",
+                Instrument::option(
+                    "btc",
+                    "usdc",
+                    date(2022, 12, 30),
+                    dec!(18000),
+                    OptionRight::Call,
+                ),
+            ),
+        ] {
+            assert_eq!(LINEAR.decode(symbol).unwrap(), instrument, "{}", symbol);
+            assert_eq!(
+                LINEAR.encode(&instrument).unwrap(),
+                symbol,
+                "{}",
+                instrument
+            );
+        }
+    }
+
+    #[test]
+    fn spot_needs_the_category() {
+        let spot = BybitCodec {
+            default_category: Category::Spot,
+        };
+        assert_eq!(
+            spot.decode("ETHBTC").unwrap(),
+            Instrument::spot("eth", "btc")
+        );
+        assert_eq!(
+            spot.encode(&Instrument::spot("eth", "btc")).unwrap(),
+            "ETHBTC"
+        );
+        assert_eq!(
+            BybitCodec::category(&Instrument::spot("btc", "usdt")),
+            Category::Spot
+        );
+        assert_eq!(
+            BybitCodec::category(&Instrument::perpetual("btc", "usd")),
+            Category::Inverse
+        );
+        assert_eq!(
+            BybitCodec::category(&Instrument::perpetual("btc", "usdt")),
+            Category::Linear
+        );
+    }
+
+    #[test]
+    fn unsupported() {
+        // inverse futures always expire on the last friday
+        assert!(LINEAR
+            .encode(&Instrument::future("btc", "usd", date(2023, 3, 30)))
+            .is_err());
+        assert!(LINEAR.encode(&Instrument::perpetual("btc", "eur")).is_err());
+        assert!(LINEAR.decode("BTC-XX-1-C").is_err());
+        assert!(LINEAR.decode("XYZ").is_err());
+    }
+}
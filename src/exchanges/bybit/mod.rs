@@ -1 +1,7 @@
+// read by the order book feed once there is one
+#[allow(dead_code)]
+pub mod book;
+#[allow(clippy::module_inception)]
 pub mod bybit;
+pub mod codec;
+pub mod params;
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use reqwest::Url;
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::exchanges::error::{ExchangeError, Result};
+
+/// Request parameters in the canonical form bybit signs: sorted by key, `null`s dropped and
+/// every value rendered exactly once, so the signed string and the sent body can't disagree.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CanonicalParams {
+    params: BTreeMap<String, Value>,
+}
+
+impl CanonicalParams {
+    /// Flattens any struct or map into parameters, nested objects and arrays are rejected.
+    pub fn from_serialize<T: Serialize>(value: &T) -> Result<Self> {
+        let mut params = Self::default();
+        match serde_json::to_value(value) {
+            Ok(Value::Object(map)) => {
+                for (k, v) in map {
+                    params.insert(&k, v)?;
+                }
+                Ok(params)
+            }
+            Ok(Value::Null) => Ok(params),
+            Ok(other) => Err(ExchangeError::parsing_error(format!(
+                "Request parameters have to be an object, got: {}",
+                other
+            ))),
+            Err(e) => Err(ExchangeError::parsing_error(e.to_string())),
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, value: Value) -> Result<()> {
+        match value {
+            Value::Null => {
+                self.params.remove(key);
+                Ok(())
+            }
+            Value::Array(_) | Value::Object(_) => Err(ExchangeError::parsing_error(format!(
+                "Parameter {} can't be signed, nested values are not supported",
+                key
+            ))),
+            v => {
+                self.params.insert(key.to_string(), v);
+                Ok(())
+            }
+        }
+    }
+
+    /// `key=value` pairs in signing order.
+    pub fn pairs(&self) -> Result<Vec<(&str, String)>> {
+        self.params
+            .iter()
+            .map(|(k, v)| Ok((k.as_str(), format_value(k, v)?)))
+            .collect()
+    }
+
+    /// `url` with the parameters as its query, `a=1&b=true&c=text`. The encoded query is what
+    /// gets signed.
+    pub fn to_url(&self, url: &str) -> Result<Url> {
+        Url::parse_with_params(url, self.pairs()?)
+            .map_err(|_e| ExchangeError::unknown_error("Could not parse URL"))
+    }
+
+    /// JSON body carrying the same values as the query.
+    pub fn to_body(&self) -> Map<String, Value> {
+        self.params
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+fn format_value(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Number(n) => Ok(format_number(&n.to_string())),
+        // insert() keeps these out, but they can't be signed either way
+        other => Err(ExchangeError::parsing_error(format!(
+            "Parameter {} can't be signed: {}",
+            key, other
+        ))),
+    }
+}
+
+// Bybit wants plain decimal notation, no exponent and no trailing zeros (1e-7 -> 0.0000001)
+fn format_number(raw: &str) -> String {
+    Decimal::from_str(raw)
+        .or_else(|_| Decimal::from_scientific(raw))
+        .map(|d| d.normalize().to_string())
+        .unwrap_or_else(|_| raw.to_string())
+}
+
+/// Serializes a decimal the way bybit expects it in a request, `0.0010` becomes `"0.001"`.
+pub fn decimal_str<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.normalize().to_string())
+}
+
+/// Same as `decimal_str` for optional fields, `None` serializes to null and is dropped.
+pub fn option_decimal_str<S: Serializer>(
+    value: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(d) => decimal_str(d, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::util;
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+
+    fn query(params: &CanonicalParams) -> String {
+        let url = params.to_url("https://api.bybit.com/").unwrap();
+        url.query().unwrap().to_string()
+    }
+
+    // The example from bybit's authentication docs, query string and signature as published.
+    const DOCS_API_KEY: &str = "B2Rou0PLPpGqcU0Vu2";
+    const DOCS_SECRET: &str = "t7T0YlFnYXk0Fx3JswQsDrViLg1Gh3DUU5Mr";
+    const DOCS_QUERY: &str =
+        "api_key=B2Rou0PLPpGqcU0Vu2&leverage=100&symbol=BTCUSD&timestamp=1542434791000";
+    const DOCS_SIGN: &str = "670e3e4aa32b243f2dedf1dafcec2fd17a440e71b05681550416507de591d908";
+
+    #[test]
+    fn docs_example_get() {
+        let mut params = CanonicalParams::default();
+        params.insert("timestamp", json!(1542434791000u64)).unwrap();
+        params.insert("symbol", json!("BTCUSD")).unwrap();
+        params.insert("leverage", json!(100)).unwrap();
+        params.insert("api_key", json!(DOCS_API_KEY)).unwrap();
+
+        assert_eq!(query(&params), DOCS_QUERY);
+        assert_eq!(util::sign(DOCS_SECRET, &query(&params)), DOCS_SIGN);
+    }
+
+    #[test]
+    fn docs_example_post() {
+        let body = json!({
+            "api_key": DOCS_API_KEY,
+            "leverage": 100,
+            "symbol": "BTCUSD",
+            "timestamp": 1542434791000u64,
+        });
+        let params = CanonicalParams::from_serialize(&body).unwrap();
+
+        assert_eq!(query(&params), DOCS_QUERY);
+        assert_eq!(util::sign(DOCS_SECRET, &query(&params)), DOCS_SIGN);
+    }
+
+    #[test]
+    fn sorted_regardless_of_field_order() {
+        #[derive(Serialize)]
+        struct Unsorted {
+            symbol: &'static str,
+            api_key: &'static str,
+            leverage: u32,
+            timestamp: u64,
+        }
+        let params = CanonicalParams::from_serialize(&Unsorted {
+            symbol: "BTCUSD",
+            api_key: DOCS_API_KEY,
+            leverage: 100,
+            timestamp: 1542434791000,
+        })
+        .unwrap();
+
+        assert_eq!(query(&params), DOCS_QUERY);
+    }
+
+    #[test]
+    fn strings_are_encoded_in_the_query_only() {
+        let params =
+            CanonicalParams::from_serialize(&json!({ "order_link_id": "say \"hi\"" })).unwrap();
+
+        assert_eq!(query(&params), "order_link_id=say+%22hi%22");
+        assert_eq!(params.to_body()["order_link_id"], json!("say \"hi\""));
+    }
+
+    #[test]
+    fn booleans_numbers_and_nulls() {
+        let params = CanonicalParams::from_serialize(&json!({
+            "reduce_only": false,
+            "close_on_trigger": true,
+            "price": null,
+            "qty": 1e-7,
+            "stop_px": 20500.50,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            query(&params),
+            "close_on_trigger=true&qty=0.0000001&reduce_only=false&stop_px=20500.5"
+        );
+        assert!(!params.to_body().contains_key("price"));
+    }
+
+    #[test]
+    fn decimals() {
+        #[derive(Serialize)]
+        struct Order {
+            #[serde(serialize_with = "decimal_str")]
+            qty: Decimal,
+            #[serde(serialize_with = "decimal_str")]
+            price: Decimal,
+        }
+        let params = CanonicalParams::from_serialize(&Order {
+            qty: dec!(0.0010),
+            price: dec!(22200.00),
+        })
+        .unwrap();
+
+        assert_eq!(query(&params), "price=22200&qty=0.001");
+    }
+
+    #[test]
+    fn nested_values_are_rejected() {
+        assert!(CanonicalParams::from_serialize(&json!({ "a": [1, 2] })).is_err());
+        assert!(CanonicalParams::from_serialize(&json!({ "a": { "b": 1 } })).is_err());
+        assert!(CanonicalParams::from_serialize(&json!([1])).is_err());
+        assert!(format_value("a", &json!([1])).is_err());
+    }
+}
@@ -11,11 +11,14 @@ pub enum ExchangeErrorType {
     RateLimit,
     OrderNotFound,
     OrderCompleted,
+    /// The client order id was used before.
+    DuplicateOrder,
     InsufficientFunds,
     InvalidOrder,
     Authentication,
     ParsingError,
     ServiceUnavailable,
+    Configuration,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, Error)]
@@ -39,9 +42,23 @@ impl ExchangeError {
         ExchangeError::new(ExchangeErrorType::RequestError, message, Some(code))
     }
 
+    pub fn configuration_error(message: String) -> Self {
+        ExchangeError::new(ExchangeErrorType::Configuration, message, None)
+    }
+
     pub fn parsing_error(message: String) -> Self {
         ExchangeError::new(ExchangeErrorType::ParsingError, message, None)
     }
+
+    /// Whether the request may have reached the exchange anyway, a timeout or a lost answer.
+    pub fn outcome_unknown(&self) -> bool {
+        match self.error_type {
+            ExchangeErrorType::RequestError => self.code.is_none(),
+            ExchangeErrorType::ParsingError | ExchangeErrorType::ServiceUnavailable => true,
+            _ => false,
+        }
+    }
+
     pub fn unknown_error(message: &str) -> Self {
         Self {
             error_type: ExchangeErrorType::Unknown,
@@ -0,0 +1,103 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::instrument::Instrument;
+use super::r#trait::{Order, OrderStatus, Side};
+use crate::market::bars::Bar;
+use crate::market::order_book::BookMessage;
+
+/// Everything the executor fans out to the strategy instances.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeEvent {
+    Trade(Trade),
+    OrderUpdate(OrderUpdate),
+    Discrepancy(Discrepancy),
+    /// Closed by a bar builder from the trades
+    Bar(Bar),
+    Book(BookUpdate),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    /// Exchange type, `bybit`
+    pub exchange: String,
+    pub instrument: Instrument,
+    pub side: Side,
+    pub price: Decimal,
+    pub qty: Decimal,
+    /// Exchange timestamp in ms
+    pub timestamp: u128,
+    /// Local ms it came off the socket, the recorded receive time in a replay
+    #[serde(default)]
+    pub received: u128,
+}
+
+/// A snapshot or delta of one instrument's L2 book, see `OrderBook::apply`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookUpdate {
+    /// Exchange type, `bybit`
+    pub exchange: String,
+    pub instrument: Instrument,
+    pub message: BookMessage,
+    /// Local ms it came off the socket, the recorded receive time in a replay
+    #[serde(default)]
+    pub received: u128,
+}
+
+/// An order changed on the exchange, from an account's private stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderUpdate {
+    pub exchange_account_id: String,
+    pub order_id: String,
+    pub client_order_id: Option<String>,
+    pub instrument: Instrument,
+    pub status: OrderStatus,
+    /// Cumulative
+    pub filled_qty: Decimal,
+    /// Over everything filled so far
+    pub avg_price: Decimal,
+    /// Exchange timestamp in ms
+    pub timestamp: u128,
+}
+
+/// Local state the reconciler found out of line with the exchange.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// Open on the exchange but sent by nobody here, `canceled` with `cancel_orphans`.
+    Orphan {
+        exchange_account_id: String,
+        order: Order,
+        /// Carried by its client order id, sent by an earlier run.
+        strategy_id: Option<String>,
+        canceled: bool,
+    },
+    /// Open locally but gone from the exchange, now canceled locally.
+    Ghost {
+        exchange_account_id: String,
+        client_order_id: String,
+        order_id: String,
+    },
+    /// Sent from here, its answer got lost and the exchange doesn't know it, now rejected locally.
+    Lost {
+        exchange_account_id: String,
+        client_order_id: String,
+    },
+    /// The local net position is set to the exchange's.
+    Position {
+        exchange_account_id: String,
+        instrument: Instrument,
+        local: Decimal,
+        exchange: Decimal,
+    },
+    /// A coin's balance moved more than `balance_tolerance_bps` since the last pass.
+    Balance {
+        exchange_account_id: String,
+        coin: String,
+        previous: Decimal,
+        exchange: Decimal,
+    },
+}
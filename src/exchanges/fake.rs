@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+
+use super::error::{ExchangeError, ExchangeErrorType, Result};
+use super::instrument::Instrument;
+use super::r#trait::{
+    Candle, ExchangeBalance, ExchangeBalancesAndPositions, ExchangeClient, Order, OrderCanceledId,
+    OrderStatus, PlaceOrder, Position, Side,
+};
+
+/// In-memory exchange for tests, placed orders rest until canceled and never fill. Like a real
+/// exchange `get_order` only lists the open ones, `get_order_by_client_id` finds any.
+#[derive(Default)]
+pub struct FakeClient {
+    pub orders: Mutex<Vec<Order>>,
+    pub positions: Mutex<Vec<Position>>,
+    pub marks: Mutex<HashMap<Instrument, Decimal>>,
+    pub balances: Mutex<HashMap<String, ExchangeBalance>>,
+    /// Served to every instrument and interval.
+    pub candles: Mutex<Vec<Candle>>,
+    /// The next this many orders are placed but answered with a timeout.
+    pub lost_answers: Mutex<usize>,
+    placed: Mutex<usize>,
+    client_ids: Mutex<Vec<String>>,
+}
+
+impl FakeClient {
+    pub fn set_mark(&self, instrument: Instrument, mark: Decimal) {
+        self.marks.lock().unwrap().insert(instrument, mark);
+    }
+
+    pub fn set_position(&self, instrument: Instrument, side: Side, size: Decimal) {
+        let mut positions = self.positions.lock().unwrap();
+        positions.retain(|p| p.instrument != instrument);
+        positions.push(Position {
+            instrument,
+            side,
+            size,
+            avg_price: Decimal::ZERO,
+        });
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for FakeClient {
+    async fn get_balance(&self, _: Option<String>) -> Result<ExchangeBalancesAndPositions> {
+        Ok(ExchangeBalancesAndPositions {
+            balances: self.balances.lock().unwrap().clone(),
+            positions: None,
+        })
+    }
+
+    async fn place_order(&self, order: PlaceOrder) -> Result<Order> {
+        if let Some(id) = &order.client_order_id {
+            let mut client_ids = self.client_ids.lock().unwrap();
+            if client_ids.contains(id) {
+                return Err(ExchangeError::new(
+                    ExchangeErrorType::DuplicateOrder,
+                    id.clone(),
+                    None,
+                ));
+            }
+            client_ids.push(id.clone());
+        }
+        let mut placed = self.placed.lock().unwrap();
+        *placed += 1;
+        let order = Order {
+            order_id: format!("fake-{}", placed),
+            client_order_id: order.client_order_id,
+            instrument: order.instrument,
+            side: order.side,
+            order_type: order.order_type,
+            price: order.price.unwrap_or_default(),
+            qty: order.qty,
+            order_status: OrderStatus::New,
+            filled_qty: Decimal::ZERO,
+            avg_price: Decimal::ZERO,
+        };
+        self.orders.lock().unwrap().push(order.clone());
+        let mut lost = self.lost_answers.lock().unwrap();
+        if *lost > 0 {
+            *lost -= 1;
+            return Err(ExchangeError::new(
+                ExchangeErrorType::RequestError,
+                "operation timed out".to_string(),
+                None,
+            ));
+        }
+        Ok(order)
+    }
+
+    async fn get_order(&self, instrument: Instrument) -> Result<Vec<Order>> {
+        let orders = self.orders.lock().unwrap();
+        Ok(orders
+            .iter()
+            .filter(|o| o.instrument == instrument)
+            .filter(|o| {
+                matches!(
+                    o.order_status,
+                    OrderStatus::New | OrderStatus::PartiallyFilled
+                )
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_order_by_client_id(
+        &self,
+        instrument: Instrument,
+        client_order_id: String,
+    ) -> Result<Option<Order>> {
+        let orders = self.orders.lock().unwrap();
+        Ok(orders
+            .iter()
+            .find(|o| {
+                o.instrument == instrument && o.client_order_id.as_ref() == Some(&client_order_id)
+            })
+            .cloned())
+    }
+
+    async fn cancel_by_client_id(
+        &self,
+        instrument: Instrument,
+        client_order_id: String,
+    ) -> Result<OrderCanceledId> {
+        match self
+            .get_order_by_client_id(instrument.clone(), client_order_id.clone())
+            .await?
+        {
+            Some(order) => self.cancel_order(instrument, order.order_id).await,
+            None => Err(ExchangeError::new(
+                ExchangeErrorType::OrderNotFound,
+                client_order_id,
+                None,
+            )),
+        }
+    }
+
+    async fn cancel_order(&self, _: Instrument, order_id: String) -> Result<OrderCanceledId> {
+        let mut orders = self.orders.lock().unwrap();
+        let before = orders.len();
+        orders.retain(|o| o.order_id != order_id);
+        match orders.len() < before {
+            true => Ok(OrderCanceledId { order_id }),
+            false => Err(ExchangeError::new(
+                ExchangeErrorType::OrderNotFound,
+                order_id,
+                None,
+            )),
+        }
+    }
+
+    async fn get_positions(&self, instrument: Instrument) -> Result<Vec<Position>> {
+        let positions = self.positions.lock().unwrap();
+        Ok(positions
+            .iter()
+            .filter(|p| p.instrument == instrument && !p.size.is_zero())
+            .cloned()
+            .collect())
+    }
+
+    async fn get_mark_price(&self, instrument: Instrument) -> Result<Decimal> {
+        self.marks
+            .lock()
+            .unwrap()
+            .get(&instrument)
+            .copied()
+            .ok_or_else(|| ExchangeError::unknown_error(&format!("no mark for {}", instrument)))
+    }
+
+    async fn get_candles(
+        &self,
+        _: Instrument,
+        _: u64,
+        start: u128,
+        end: u128,
+    ) -> Result<Vec<Candle>> {
+        let candles = self.candles.lock().unwrap();
+        Ok(candles
+            .iter()
+            .filter(|c| c.open_time >= start && c.open_time <= end)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::error::{ExchangeError, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InstrumentKind {
+    Spot,
+    #[default]
+    #[serde(rename = "perp")]
+    Perpetual,
+    Future,
+    Option,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptionRight {
+    #[serde(rename = "C")]
+    Call,
+    #[serde(rename = "P")]
+    Put,
+}
+
+/// A tradable thing independent of any venue, clients translate it with their `SymbolCodec`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instrument {
+    /// Uppercase, `BTC`
+    pub base: String,
+    /// Uppercase, `USDT`. Inverse contracts are quoted in `USD`.
+    pub quote: String,
+    pub kind: InstrumentKind,
+    /// Futures and options only.
+    pub expiry: Option<NaiveDate>,
+    /// Options only.
+    pub strike: Option<Decimal>,
+    /// Options only.
+    pub right: Option<OptionRight>,
+}
+
+#[allow(dead_code)]
+impl Instrument {
+    pub fn spot(base: &str, quote: &str) -> Self {
+        Self::new(base, quote, InstrumentKind::Spot)
+    }
+
+    pub fn perpetual(base: &str, quote: &str) -> Self {
+        Self::new(base, quote, InstrumentKind::Perpetual)
+    }
+
+    pub fn future(base: &str, quote: &str, expiry: NaiveDate) -> Self {
+        Self {
+            expiry: Some(expiry),
+            ..Self::new(base, quote, InstrumentKind::Future)
+        }
+    }
+
+    pub fn option(
+        base: &str,
+        quote: &str,
+        expiry: NaiveDate,
+        strike: Decimal,
+        right: OptionRight,
+    ) -> Self {
+        Self {
+            expiry: Some(expiry),
+            strike: Some(strike),
+            right: Some(right),
+            ..Self::new(base, quote, InstrumentKind::Option)
+        }
+    }
+
+    fn new(base: &str, quote: &str, kind: InstrumentKind) -> Self {
+        Self {
+            base: base.to_uppercase(),
+            quote: quote.to_uppercase(),
+            kind,
+            expiry: None,
+            strike: None,
+            right: None,
+        }
+    }
+
+    /// Checks that exactly the fields the kind needs are set.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, currency) in [("base", &self.base), ("quote", &self.quote)] {
+            if currency.is_empty() || !currency.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("{:?} is not a {} currency", currency, name));
+            }
+        }
+        let dated = matches!(self.kind, InstrumentKind::Future | InstrumentKind::Option);
+        if dated != self.expiry.is_some() {
+            return Err(match dated {
+                true => format!("a {:?} needs an expiry", self.kind),
+                false => format!("a {:?} has no expiry", self.kind),
+            });
+        }
+        let option = self.kind == InstrumentKind::Option;
+        if option != (self.strike.is_some() && self.right.is_some())
+            || (!option && (self.strike.is_some() || self.right.is_some()))
+        {
+            return Err(match option {
+                true => "an option needs a strike and a right".to_string(),
+                false => format!("a {:?} has no strike or right", self.kind),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `BTC/USDT`, `BTC/USDT:perp`, `BTC/USD:future:2023-03-31`, `BTC/USDC:option:2022-12-30:18000:C`
+impl fmt::Display for Instrument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)?;
+        match self.kind {
+            InstrumentKind::Spot => Ok(()),
+            InstrumentKind::Perpetual => write!(f, ":perp"),
+            InstrumentKind::Future => write!(f, ":future:{}", fmt_option(&self.expiry)),
+            InstrumentKind::Option => write!(
+                f,
+                ":option:{}:{}:{}",
+                fmt_option(&self.expiry),
+                fmt_option(&self.strike),
+                match self.right {
+                    Some(OptionRight::Call) => "C",
+                    Some(OptionRight::Put) => "P",
+                    None => "?",
+                }
+            ),
+        }
+    }
+}
+
+fn fmt_option<T: fmt::Display>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| "?".to_string(), |v| v.to_string())
+}
+
+/// Turns instruments into a venue's symbols and back.
+pub trait SymbolCodec: Send + Sync {
+    fn encode(&self, instrument: &Instrument) -> Result<String>;
+    #[allow(dead_code)]
+    fn decode(&self, symbol: &str) -> Result<Instrument>;
+}
+
+pub fn unsupported(venue: &str, instrument: &Instrument) -> ExchangeError {
+    ExchangeError::configuration_error(format!("{} doesn't list {}", venue, instrument))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Instrument::spot("btc", "usdt").to_string(), "BTC/USDT");
+        assert_eq!(
+            Instrument::perpetual("btc", "usdt").to_string(),
+            "BTC/USDT:perp"
+        );
+        assert_eq!(
+            Instrument::future("btc", "usd", date(2023, 3, 31)).to_string(),
+            "BTC/USD:future:2023-03-31"
+        );
+        assert_eq!(
+            Instrument::option(
+                "btc",
+                "usdc",
+                date(2022, 12, 30),
+                dec!(18000),
+                OptionRight::Call
+            )
+            .to_string(),
+            "BTC/USDC:option:2022-12-30:18000:C"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Instrument::spot("btc", "usdt").validate().is_ok());
+        assert!(Instrument::spot("btc", "us-dt").validate().is_err());
+        assert!(Instrument::future("btc", "usd", date(2023, 3, 31))
+            .validate()
+            .is_ok());
+
+        let mut future = Instrument::future("btc", "usd", date(2023, 3, 31));
+        future.expiry = None;
+        assert!(future.validate().is_err());
+        let mut perp = Instrument::perpetual("btc", "usdt");
+        perp.strike = Some(dec!(1));
+        assert!(perp.validate().is_err());
+        let mut option = Instrument::option(
+            "btc",
+            "usdc",
+            date(2022, 12, 30),
+            dec!(18000),
+            OptionRight::Put,
+        );
+        option.right = None;
+        assert!(option.validate().is_err());
+    }
+}
@@ -1,5 +1,10 @@
 pub mod bybit;
 pub mod error;
+pub mod event;
+#[cfg(test)]
+pub mod fake;
+pub mod instrument;
+pub mod paper;
 pub mod rest_client;
 pub mod r#trait;
 pub mod util;
@@ -0,0 +1,769 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rust_decimal::Decimal;
+
+use crate::exchanges::error::{ExchangeError, ExchangeErrorType, Result};
+use crate::exchanges::event::{ExchangeEvent, OrderUpdate, Trade};
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{
+    ExchangeBalance, Order, OrderStatus, OrderType, PlaceOrder, Position, Side, TimeInForce,
+};
+use crate::market::order_book::{Level, OrderBook};
+use crate::settings::settings::PaperSettings;
+
+const BPS: Decimal = Decimal::from_parts(10_000, 0, 0, false, 0);
+
+/// An order on its way to or resting in the simulated book.
+#[derive(Debug, Clone)]
+struct Working {
+    order: Order,
+    time_in_force: TimeInForce,
+    reduce_only: bool,
+    filled: Decimal,
+    /// Of what's filled, for the average price
+    notional: Decimal,
+    /// Reaches the book at this time, the latency after it was sent
+    arrives: u128,
+    arrived: bool,
+    /// Size estimated ahead of it at its price, traded away before it fills
+    queue_ahead: Decimal,
+}
+
+impl Working {
+    fn left(&self) -> Decimal {
+        self.order.qty - self.filled
+    }
+
+    fn avg_price(&self) -> Decimal {
+        match self.filled.is_zero() {
+            true => Decimal::ZERO,
+            false => self.notional / self.filled,
+        }
+    }
+}
+
+/// A fill of the simulated exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaperFill {
+    pub order_id: String,
+    pub instrument: Instrument,
+    pub side: Side,
+    pub qty: Decimal,
+    pub price: Decimal,
+    /// In the quote coin
+    pub fee: Decimal,
+    pub maker: bool,
+    pub timestamp: u128,
+}
+
+/// Simulated exchange of one account. Market data moves its clock and fills the orders: takers
+/// against the book's depth (the last trade's price without a book), resting orders once the
+/// estimated size ahead of them has traded or the price went through them. Every instrument
+/// settles linearly in its quote coin, cancels take effect at once and the book isn't depleted
+/// by the fills.
+#[derive(Debug)]
+pub struct MatchingEngine {
+    account: String,
+    settings: PaperSettings,
+    books: HashMap<Instrument, OrderBook>,
+    last_trades: HashMap<Instrument, Decimal>,
+    /// Open orders, oldest first
+    working: Vec<Working>,
+    /// Finished orders by order id
+    done: HashMap<String, Order>,
+    client_ids: HashSet<String>,
+    balances: BTreeMap<String, Decimal>,
+    /// Net qty and average entry price
+    positions: HashMap<Instrument, (Decimal, Decimal)>,
+    fills: Vec<PaperFill>,
+    next_id: u64,
+    now: u128,
+}
+
+impl MatchingEngine {
+    pub fn new(account: &str, settings: PaperSettings) -> Self {
+        let balances = settings
+            .balances
+            .iter()
+            .map(|(coin, balance)| (coin.to_uppercase(), *balance))
+            .collect();
+        Self {
+            account: account.to_string(),
+            settings,
+            books: HashMap::new(),
+            last_trades: HashMap::new(),
+            working: vec![],
+            done: HashMap::new(),
+            client_ids: HashSet::new(),
+            balances,
+            positions: HashMap::new(),
+            fills: vec![],
+            next_id: 0,
+            now: 0,
+        }
+    }
+
+    pub fn fills(&self) -> &[PaperFill] {
+        &self.fills
+    }
+
+    pub fn place(&mut self, order: PlaceOrder) -> Result<Order> {
+        let invalid = |message: &str| {
+            ExchangeError::new(ExchangeErrorType::InvalidOrder, message.to_string(), None)
+        };
+        if order.qty <= Decimal::ZERO {
+            return Err(invalid("qty has to be above 0"));
+        }
+        let price = match (order.order_type, order.price) {
+            (OrderType::Limit, Some(price)) if price > Decimal::ZERO => price,
+            (OrderType::Limit, _) => return Err(invalid("a limit order needs a price above 0")),
+            (OrderType::Market, _) => Decimal::ZERO,
+        };
+        if order.reduce_only && !self.reduces(&order.instrument, order.side, order.qty) {
+            return Err(invalid("reduce only order would not reduce the position"));
+        }
+        if !order.reduce_only && self.balance(&order.instrument.quote) <= Decimal::ZERO {
+            return Err(ExchangeError::new(
+                ExchangeErrorType::InsufficientFunds,
+                format!("no {} left", order.instrument.quote),
+                None,
+            ));
+        }
+        if let Some(id) = &order.client_order_id {
+            if !self.client_ids.insert(id.clone()) {
+                return Err(ExchangeError::new(
+                    ExchangeErrorType::DuplicateOrder,
+                    id.clone(),
+                    None,
+                ));
+            }
+        }
+        self.next_id += 1;
+        let placed = Order {
+            order_id: format!("paper-{}", self.next_id),
+            client_order_id: order.client_order_id,
+            instrument: order.instrument,
+            side: order.side,
+            order_type: order.order_type,
+            price,
+            qty: order.qty,
+            order_status: OrderStatus::New,
+            filled_qty: Decimal::ZERO,
+            avg_price: Decimal::ZERO,
+        };
+        self.working.push(Working {
+            order: placed.clone(),
+            time_in_force: order.time_in_force,
+            reduce_only: order.reduce_only,
+            filled: Decimal::ZERO,
+            notional: Decimal::ZERO,
+            arrives: self.now + u128::from(self.settings.latency_ms),
+            arrived: false,
+            queue_ahead: Decimal::ZERO,
+        });
+        Ok(placed)
+    }
+
+    /// Cancels the open order `matches` picks out.
+    pub fn cancel(&mut self, matches: impl Fn(&Order) -> bool) -> Result<(Order, OrderUpdate)> {
+        let i = self
+            .working
+            .iter()
+            .position(|w| matches(&w.order))
+            .ok_or_else(|| {
+                ExchangeError::new(
+                    ExchangeErrorType::OrderNotFound,
+                    "no such open order".to_string(),
+                    None,
+                )
+            })?;
+        let mut working = self.working.remove(i);
+        working.order.order_status = OrderStatus::Canceled;
+        let update = self.update(&working);
+        self.done
+            .insert(working.order.order_id.clone(), working.order.clone());
+        Ok((working.order, update))
+    }
+
+    pub fn open_orders(&self, instrument: &Instrument) -> Vec<Order> {
+        self.working
+            .iter()
+            .filter(|w| w.order.instrument == *instrument)
+            .map(|w| w.order.clone())
+            .collect()
+    }
+
+    pub fn order_by_client_id(&self, client_order_id: &str) -> Option<Order> {
+        let id = Some(client_order_id.to_string());
+        self.working
+            .iter()
+            .map(|w| &w.order)
+            .chain(self.done.values())
+            .find(|o| o.client_order_id == id)
+            .cloned()
+    }
+
+    /// Feeds one event of market data, returns the order updates it caused.
+    pub fn on_event(&mut self, event: &ExchangeEvent) -> Vec<OrderUpdate> {
+        match event {
+            ExchangeEvent::Trade(trade) if trade.exchange == self.settings.venue => {
+                self.now = self.now.max(trade.timestamp);
+                self.last_trades
+                    .insert(trade.instrument.clone(), trade.price);
+                let mut updates = self.arrivals();
+                updates.extend(self.on_trade(trade));
+                updates
+            }
+            ExchangeEvent::Book(book) if book.exchange == self.settings.venue => {
+                self.now = self.now.max(book.message.timestamp());
+                let instrument = book.instrument.clone();
+                let ob = self
+                    .books
+                    .entry(instrument.clone())
+                    .or_insert_with(|| OrderBook::new(instrument.clone()));
+                // out of sync until the next snapshot, takers fall back to the last trade
+                let _ = ob.apply(book.message.clone());
+                let mut updates = self.arrivals();
+                updates.extend(self.on_book(&instrument));
+                updates
+            }
+            _ => vec![],
+        }
+    }
+
+    // market data moves the clock everywhere but the tests
+    #[allow(dead_code)]
+    /// Moves the clock on without market data, orders whose latency passed reach the book.
+    pub fn advance(&mut self, now: u128) -> Vec<OrderUpdate> {
+        self.now = self.now.max(now);
+        self.arrivals()
+    }
+
+    fn arrivals(&mut self) -> Vec<OrderUpdate> {
+        let mut updates = vec![];
+        let mut i = 0;
+        while i < self.working.len() {
+            if self.working[i].arrived || self.working[i].arrives > self.now {
+                i += 1;
+                continue;
+            }
+            self.working[i].arrived = true;
+            let mut working = self.working[i].clone();
+            updates.extend(self.take(&mut working));
+            let rests = working.left() > Decimal::ZERO
+                && working.order.order_type == OrderType::Limit
+                && working.time_in_force == TimeInForce::GoodTillCancel;
+            if rests {
+                working.queue_ahead = self
+                    .own_level(&working.order)
+                    .map_or(Decimal::ZERO, |l| l.size);
+                self.working[i] = working;
+                i += 1;
+                continue;
+            }
+            self.working.remove(i);
+            if working.left() > Decimal::ZERO {
+                working.order.order_status = OrderStatus::Canceled;
+                updates.push(self.update(&working));
+            }
+            self.done
+                .insert(working.order.order_id.clone(), working.order);
+        }
+        updates
+    }
+
+    /// Fills what it can of an arriving order against the other side.
+    fn take(&mut self, working: &mut Working) -> Vec<OrderUpdate> {
+        let order = &working.order;
+        let limit = |price: Decimal| match (order.order_type, order.side) {
+            (OrderType::Market, _) => true,
+            (OrderType::Limit, Side::Buy) => price <= order.price,
+            (OrderType::Limit, Side::Sell) => price >= order.price,
+        };
+        let against = match order.side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        let levels: Vec<Level> = match self.books.get(&order.instrument) {
+            Some(book) if book.is_synced() => book.depth(against, usize::MAX),
+            // the whole order at the last trade
+            _ => self
+                .last_trades
+                .get(&order.instrument)
+                .map(|price| Level {
+                    price: *price,
+                    size: order.qty,
+                })
+                .into_iter()
+                .collect(),
+        };
+        let mut left = self.capped(working);
+        let mut takes = vec![];
+        for level in levels.into_iter().take_while(|l| limit(l.price)) {
+            let qty = left.min(level.size);
+            takes.push((qty, level.price));
+            left -= qty;
+            if left.is_zero() {
+                break;
+            }
+        }
+        if working.time_in_force == TimeInForce::FillOrKill && !left.is_zero() {
+            return vec![];
+        }
+        takes
+            .into_iter()
+            .map(|(qty, price)| self.fill(working, qty, price, false))
+            .collect()
+    }
+
+    /// Resting orders the trade went through, or reached once the size ahead traded.
+    fn on_trade(&mut self, trade: &Trade) -> Vec<OrderUpdate> {
+        let mut updates = vec![];
+        let mut i = 0;
+        while i < self.working.len() {
+            let mut working = self.working[i].clone();
+            let order = &working.order;
+            // the trade's side is the taker's
+            let hit = working.arrived
+                && order.instrument == trade.instrument
+                && order.order_type == OrderType::Limit
+                && order.side != trade.side
+                && match order.side {
+                    Side::Buy => trade.price <= order.price,
+                    Side::Sell => trade.price >= order.price,
+                };
+            if !hit {
+                i += 1;
+                continue;
+            }
+            let qty = match trade.price == order.price {
+                true => {
+                    let through = trade.qty - working.queue_ahead;
+                    working.queue_ahead = (-through).max(Decimal::ZERO);
+                    through.max(Decimal::ZERO).min(self.capped(&working))
+                }
+                false => self.capped(&working),
+            };
+            let price = working.order.price;
+            if !qty.is_zero() {
+                updates.push(self.fill(&mut working, qty, price, true));
+            }
+            i += self.settle(i, working);
+        }
+        updates
+    }
+
+    /// Resting orders the book crossed, and less size ahead where the level shrank.
+    fn on_book(&mut self, instrument: &Instrument) -> Vec<OrderUpdate> {
+        let book = match self.books.get(instrument) {
+            Some(book) if book.is_synced() => book,
+            _ => return vec![],
+        };
+        let (bid, ask) = (book.best_bid(), book.best_ask());
+        let mut updates = vec![];
+        let mut i = 0;
+        while i < self.working.len() {
+            let mut working = self.working[i].clone();
+            if !working.arrived || working.order.instrument != *instrument {
+                i += 1;
+                continue;
+            }
+            working.queue_ahead = working.queue_ahead.min(
+                self.own_level(&working.order)
+                    .map_or(Decimal::ZERO, |l| l.size),
+            );
+            let price = working.order.price;
+            let crossed = match working.order.side {
+                Side::Buy => ask.is_some_and(|a| a.price <= price),
+                Side::Sell => bid.is_some_and(|b| b.price >= price),
+            };
+            if crossed {
+                let qty = self.capped(&working);
+                updates.push(self.fill(&mut working, qty, price, true));
+            }
+            i += self.settle(i, working);
+        }
+        updates
+    }
+
+    /// Puts a resting order back, or moves it to `done` once it's over. Returns how far to step.
+    fn settle(&mut self, i: usize, working: Working) -> usize {
+        match working.order.order_status {
+            OrderStatus::Filled | OrderStatus::Canceled => {
+                self.working.remove(i);
+                self.done
+                    .insert(working.order.order_id.clone(), working.order);
+                0
+            }
+            _ => {
+                self.working[i] = working;
+                1
+            }
+        }
+    }
+
+    /// Level at the order's own price on its own side.
+    fn own_level(&self, order: &Order) -> Option<Level> {
+        let book = self.books.get(&order.instrument)?;
+        book.depth(order.side, usize::MAX)
+            .into_iter()
+            .find(|l| l.price == order.price)
+    }
+
+    /// What's left of the order, no more than the position for reduce only ones.
+    fn capped(&self, working: &Working) -> Decimal {
+        let left = working.left();
+        if !working.reduce_only {
+            return left;
+        }
+        let (qty, _) = self.position(&working.order.instrument);
+        match working.order.side {
+            Side::Buy if qty < Decimal::ZERO => left.min(-qty),
+            Side::Sell if qty > Decimal::ZERO => left.min(qty),
+            _ => Decimal::ZERO,
+        }
+    }
+
+    fn reduces(&self, instrument: &Instrument, side: Side, qty: Decimal) -> bool {
+        let (position, _) = self.position(instrument);
+        qty > Decimal::ZERO
+            && match side {
+                Side::Buy => position < Decimal::ZERO,
+                Side::Sell => position > Decimal::ZERO,
+            }
+    }
+
+    fn fill(
+        &mut self,
+        working: &mut Working,
+        qty: Decimal,
+        price: Decimal,
+        maker: bool,
+    ) -> OrderUpdate {
+        working.filled += qty;
+        working.notional += qty * price;
+        let avg_price = working.avg_price();
+        let order = &mut working.order;
+        order.filled_qty = working.filled;
+        order.avg_price = avg_price;
+        order.order_status = match working.filled >= order.qty {
+            true => OrderStatus::Filled,
+            false => OrderStatus::PartiallyFilled,
+        };
+        let bps = match maker {
+            true => self.settings.maker_fee_bps,
+            false => self.settings.taker_fee_bps,
+        };
+        let fee = qty * price * bps / BPS;
+        let coin = order.instrument.quote.clone();
+
+        let (position, avg) = self.position(&order.instrument);
+        let signed = match order.side {
+            Side::Buy => qty,
+            Side::Sell => -qty,
+        };
+        let mut realized = Decimal::ZERO;
+        let avg = if position.is_zero() || position.is_sign_positive() == signed.is_sign_positive()
+        {
+            (position.abs() * avg + qty * price) / (position.abs() + qty)
+        } else {
+            let closed = position.abs().min(qty);
+            realized = match position.is_sign_positive() {
+                true => closed * (price - avg),
+                false => closed * (avg - price),
+            };
+            match qty > position.abs() {
+                // flipped, the rest opened at this price
+                true => price,
+                false => avg,
+            }
+        };
+        let position = position + signed;
+        self.positions.insert(
+            order.instrument.clone(),
+            match position.is_zero() {
+                true => (Decimal::ZERO, Decimal::ZERO),
+                false => (position, avg),
+            },
+        );
+        *self.balances.entry(coin).or_default() += realized - fee;
+
+        self.fills.push(PaperFill {
+            order_id: order.order_id.clone(),
+            instrument: order.instrument.clone(),
+            side: order.side,
+            qty,
+            price,
+            fee,
+            maker,
+            timestamp: self.now,
+        });
+        self.update(working)
+    }
+
+    fn update(&self, working: &Working) -> OrderUpdate {
+        OrderUpdate {
+            exchange_account_id: self.account.clone(),
+            order_id: working.order.order_id.clone(),
+            client_order_id: working.order.client_order_id.clone(),
+            instrument: working.order.instrument.clone(),
+            status: working.order.order_status,
+            filled_qty: working.filled,
+            avg_price: working.avg_price(),
+            timestamp: self.now,
+        }
+    }
+
+    /// Net qty and average entry price, 0 when flat.
+    pub fn position(&self, instrument: &Instrument) -> (Decimal, Decimal) {
+        self.positions
+            .get(instrument)
+            .copied()
+            .unwrap_or((Decimal::ZERO, Decimal::ZERO))
+    }
+
+    pub fn positions(&self, instrument: &Instrument) -> Vec<Position> {
+        let (qty, avg_price) = self.position(instrument);
+        if qty.is_zero() {
+            return vec![];
+        }
+        vec![Position {
+            instrument: instrument.clone(),
+            side: match qty.is_sign_positive() {
+                true => Side::Buy,
+                false => Side::Sell,
+            },
+            size: qty.abs(),
+            avg_price,
+        }]
+    }
+
+    /// The mid, or the last trade without a book.
+    pub fn mark(&self, instrument: &Instrument) -> Option<Decimal> {
+        self.books
+            .get(instrument)
+            .and_then(OrderBook::mid)
+            .or_else(|| self.last_trades.get(instrument).copied())
+    }
+
+    fn balance(&self, coin: &str) -> Decimal {
+        self.balances
+            .get(&coin.to_uppercase())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Per coin, equity adds the unrealised pnl of the positions settling in it.
+    pub fn balances(&self) -> HashMap<String, ExchangeBalance> {
+        let mut balances: HashMap<String, ExchangeBalance> = self
+            .balances
+            .iter()
+            .map(|(coin, balance)| {
+                (
+                    coin.clone(),
+                    ExchangeBalance {
+                        balance: *balance,
+                        equity: *balance,
+                    },
+                )
+            })
+            .collect();
+        for (instrument, (qty, avg)) in &self.positions {
+            let mark = match self.mark(instrument) {
+                Some(mark) => mark,
+                None => continue,
+            };
+            let coin = balances
+                .entry(instrument.quote.clone())
+                .or_insert(ExchangeBalance {
+                    balance: Decimal::ZERO,
+                    equity: Decimal::ZERO,
+                });
+            coin.equity += *qty * (mark - avg);
+        }
+        balances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::event::BookUpdate;
+    use crate::market::order_book::{BookMessage, LevelChange};
+
+    fn btc() -> Instrument {
+        Instrument::perpetual("btc", "usdt")
+    }
+
+    fn engine() -> MatchingEngine {
+        MatchingEngine::new(
+            "paper",
+            PaperSettings {
+                maker_fee_bps: dec!(1),
+                taker_fee_bps: dec!(10),
+                latency_ms: 10,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn book(timestamp: u128) -> ExchangeEvent {
+        let level = |side, price, size| LevelChange { side, price, size };
+        ExchangeEvent::Book(BookUpdate {
+            exchange: "bybit".to_string(),
+            instrument: btc(),
+            message: BookMessage::Snapshot {
+                levels: vec![
+                    level(Side::Buy, dec!(99), dec!(5)),
+                    level(Side::Buy, dec!(100), dec!(2)),
+                    level(Side::Sell, dec!(101), dec!(1)),
+                    level(Side::Sell, dec!(102), dec!(3)),
+                ],
+                seq: timestamp as u64,
+                timestamp,
+            },
+            received: timestamp,
+        })
+    }
+
+    fn trade(side: Side, price: Decimal, qty: Decimal, timestamp: u128) -> ExchangeEvent {
+        ExchangeEvent::Trade(Trade {
+            exchange: "bybit".to_string(),
+            instrument: btc(),
+            side,
+            price,
+            qty,
+            timestamp,
+            received: timestamp,
+        })
+    }
+
+    fn order(
+        side: Side,
+        order_type: OrderType,
+        qty: Decimal,
+        price: Option<Decimal>,
+    ) -> PlaceOrder {
+        PlaceOrder {
+            client_order_id: None,
+            side,
+            instrument: btc(),
+            order_type,
+            qty,
+            price,
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        }
+    }
+
+    #[test]
+    fn takers_walk_the_depth_after_the_latency() {
+        let mut engine = engine();
+        engine.on_event(&book(1_000));
+        engine
+            .place(order(Side::Buy, OrderType::Market, dec!(2), None))
+            .unwrap();
+        // still on its way
+        assert!(engine.on_event(&book(1_005)).is_empty());
+
+        let updates = engine.advance(1_010);
+        let last = updates.last().unwrap();
+        assert_eq!(last.status, OrderStatus::Filled);
+        assert_eq!(last.avg_price, dec!(101.5));
+        assert_eq!(engine.position(&btc()), (dec!(2), dec!(101.5)));
+        // 10 bps of 203
+        let usdt = &engine.balances()["USDT"];
+        assert_eq!(usdt.balance, dec!(10000) - dec!(0.203));
+        // marked at the mid of 100.5
+        assert_eq!(usdt.equity, usdt.balance - dec!(2));
+    }
+
+    #[test]
+    fn passive_orders_wait_for_the_queue_ahead() {
+        let mut engine = engine();
+        engine.on_event(&book(1_000));
+        let placed = engine
+            .place(order(Side::Buy, OrderType::Limit, dec!(1), Some(dec!(100))))
+            .unwrap();
+        engine.advance(1_010);
+        assert_eq!(engine.open_orders(&btc()).len(), 1);
+
+        // 2 ahead at 100
+        assert!(engine
+            .on_event(&trade(Side::Sell, dec!(100), dec!(1.5), 1_020))
+            .is_empty());
+        let updates = engine.on_event(&trade(Side::Sell, dec!(100), dec!(1), 1_030));
+        assert_eq!(updates[0].status, OrderStatus::PartiallyFilled);
+        assert_eq!(updates[0].filled_qty, dec!(0.5));
+        // through its price
+        let updates = engine.on_event(&trade(Side::Sell, dec!(99.5), dec!(0.1), 1_040));
+        assert_eq!(updates[0].status, OrderStatus::Filled);
+        assert_eq!(updates[0].order_id, placed.order_id);
+        assert!(engine.fills().iter().all(|f| f.maker));
+        assert!(engine.open_orders(&btc()).is_empty());
+
+        // sold back higher, the pnl is realised
+        engine
+            .place(order(
+                Side::Sell,
+                OrderType::Limit,
+                dec!(1),
+                Some(dec!(100.5)),
+            ))
+            .unwrap();
+        engine.advance(1_050);
+        assert_eq!(engine.position(&btc()), (dec!(1), dec!(100)));
+        engine.on_event(&trade(Side::Buy, dec!(100.5), dec!(1), 1_060));
+        assert_eq!(engine.position(&btc()), (dec!(0), dec!(0)));
+        assert_eq!(engine.fills().last().unwrap().price, dec!(100.5));
+        // 0.5 made, less 1 bps of 100 to buy and of 100.5 to sell
+        let usdt = &engine.balances()["USDT"];
+        assert_eq!(
+            usdt.balance,
+            dec!(10000) + dec!(0.5) - dec!(0.01) - dec!(0.01005)
+        );
+        assert_eq!(usdt.equity, usdt.balance);
+    }
+
+    #[test]
+    fn time_in_force_and_reduce_only() {
+        let mut engine = engine();
+        engine.on_event(&book(1_000));
+        let mut fok = order(Side::Buy, OrderType::Limit, dec!(2), Some(dec!(101)));
+        fok.time_in_force = TimeInForce::FillOrKill;
+        engine.place(fok).unwrap();
+        let mut ioc = order(Side::Buy, OrderType::Limit, dec!(2), Some(dec!(101)));
+        ioc.time_in_force = TimeInForce::ImmediateOrCancel;
+        engine.place(ioc).unwrap();
+        let updates = engine.advance(1_010);
+        let statuses: Vec<(OrderStatus, Decimal)> =
+            updates.iter().map(|u| (u.status, u.filled_qty)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (OrderStatus::Canceled, dec!(0)),
+                (OrderStatus::PartiallyFilled, dec!(1)),
+                (OrderStatus::Canceled, dec!(1)),
+            ]
+        );
+
+        let mut reduce = order(Side::Buy, OrderType::Market, dec!(1), None);
+        reduce.reduce_only = true;
+        assert_eq!(
+            engine.place(reduce).unwrap_err().error_type,
+            ExchangeErrorType::InvalidOrder
+        );
+        let mut dup = order(Side::Buy, OrderType::Market, dec!(1), None);
+        dup.client_order_id = Some("a".to_string());
+        engine.place(dup.clone()).unwrap();
+        assert_eq!(
+            engine.place(dup).unwrap_err().error_type,
+            ExchangeErrorType::DuplicateOrder
+        );
+        assert!(engine.order_by_client_id("a").is_some());
+    }
+}
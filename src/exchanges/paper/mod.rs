@@ -0,0 +1,3 @@
+pub mod matching;
+#[allow(clippy::module_inception)]
+pub mod paper;
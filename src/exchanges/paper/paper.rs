@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::matching::MatchingEngine;
+use crate::exchanges::error::{ExchangeError, ExchangeErrorType, Result};
+use crate::exchanges::event::{ExchangeEvent, OrderUpdate};
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{
+    Candle, ExchangeBalancesAndPositions, ExchangeClient, Order, OrderCanceledId, PlaceOrder,
+    Position,
+};
+use crate::settings::settings::PaperSettings;
+
+/// Trades an account on a `MatchingEngine` fed with the trades and books of the event stream, live
+/// or replayed. Fills come back on the stream as order updates, like a venue's private feed.
+pub struct PaperClient {
+    engine: Arc<Mutex<MatchingEngine>>,
+    events: broadcast::Sender<ExchangeEvent>,
+}
+
+impl PaperClient {
+    /// Starts feeding the engine from `events`, runs until the channel closes.
+    pub fn start(
+        exchange_account_id: &str,
+        settings: PaperSettings,
+        events: broadcast::Sender<ExchangeEvent>,
+    ) -> Arc<Self> {
+        let client = Arc::new(Self::new(exchange_account_id, settings, events.clone()));
+        tokio::spawn(run(client.engine.clone(), events.subscribe(), events));
+        client
+    }
+
+    pub fn new(
+        exchange_account_id: &str,
+        settings: PaperSettings,
+        events: broadcast::Sender<ExchangeEvent>,
+    ) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(MatchingEngine::new(
+                exchange_account_id,
+                settings,
+            ))),
+            events,
+        }
+    }
+
+    pub fn engine(&self) -> &Mutex<MatchingEngine> {
+        &self.engine
+    }
+
+    fn cancel(&self, matches: impl Fn(&Order) -> bool) -> Result<OrderCanceledId> {
+        let (order, update) = self.engine.lock().unwrap().cancel(matches)?;
+        send(&self.events, vec![update]);
+        Ok(OrderCanceledId {
+            order_id: order.order_id,
+        })
+    }
+}
+
+fn send(events: &broadcast::Sender<ExchangeEvent>, updates: Vec<OrderUpdate>) {
+    for update in updates {
+        let _ = events.send(ExchangeEvent::OrderUpdate(update));
+    }
+}
+
+/// Matches the engine's orders against every trade and book update, sends what filled.
+pub async fn run(
+    engine: Arc<Mutex<MatchingEngine>>,
+    mut market: broadcast::Receiver<ExchangeEvent>,
+    events: broadcast::Sender<ExchangeEvent>,
+) {
+    loop {
+        match market.recv().await {
+            Ok(event @ (ExchangeEvent::Trade(_) | ExchangeEvent::Book(_))) => {
+                let updates = engine.lock().unwrap().on_event(&event);
+                send(&events, updates);
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => println!("paper: missed {} events", missed),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for PaperClient {
+    async fn get_balance(&self, _: Option<String>) -> Result<ExchangeBalancesAndPositions> {
+        Ok(ExchangeBalancesAndPositions {
+            balances: self.engine.lock().unwrap().balances(),
+            positions: None,
+        })
+    }
+
+    async fn place_order(&self, order: PlaceOrder) -> Result<Order> {
+        self.engine.lock().unwrap().place(order)
+    }
+
+    async fn get_order(&self, instrument: Instrument) -> Result<Vec<Order>> {
+        Ok(self.engine.lock().unwrap().open_orders(&instrument))
+    }
+
+    async fn get_order_by_client_id(
+        &self,
+        _: Instrument,
+        client_order_id: String,
+    ) -> Result<Option<Order>> {
+        Ok(self
+            .engine
+            .lock()
+            .unwrap()
+            .order_by_client_id(&client_order_id))
+    }
+
+    async fn cancel_order(&self, _: Instrument, order_id: String) -> Result<OrderCanceledId> {
+        self.cancel(|o| o.order_id == order_id)
+    }
+
+    async fn cancel_by_client_id(
+        &self,
+        _: Instrument,
+        client_order_id: String,
+    ) -> Result<OrderCanceledId> {
+        self.cancel(|o| o.client_order_id.as_ref() == Some(&client_order_id))
+    }
+
+    async fn get_positions(&self, instrument: Instrument) -> Result<Vec<Position>> {
+        Ok(self.engine.lock().unwrap().positions(&instrument))
+    }
+
+    async fn get_mark_price(&self, instrument: Instrument) -> Result<Decimal> {
+        self.engine
+            .lock()
+            .unwrap()
+            .mark(&instrument)
+            .ok_or_else(|| {
+                ExchangeError::new(
+                    ExchangeErrorType::ServiceUnavailable,
+                    format!("no market data for {} yet", instrument),
+                    None,
+                )
+            })
+    }
+
+    /// No history to serve, bars start from the trades.
+    async fn get_candles(&self, _: Instrument, _: u64, _: u128, _: u128) -> Result<Vec<Candle>> {
+        Ok(vec![])
+    }
+}
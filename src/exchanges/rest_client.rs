@@ -1,28 +1,185 @@
-use crate::settings::settings::Settings;
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::{bybit::bybit::BybitClient, error::Result, r#trait::ExchangeClient};
+use reqwest::Client;
+use tokio::sync::broadcast;
 
+use crate::settings::settings::{Environment, ExchangeSettings, Settings};
+
+use super::{
+    bybit::bybit::BybitClient,
+    error::{ExchangeError, Result},
+    event::ExchangeEvent,
+    paper::paper::PaperClient,
+    r#trait::ExchangeClient,
+};
+
+#[allow(dead_code)]
 pub enum ExchangeType {
     Bybit,
     Binance,
     Ftx,
+    /// Simulated, see `PaperClient`
+    Paper,
 }
 
 pub fn exchange_from_string(exchange: &str) -> Result<ExchangeType, String> {
-    match exchange {
+    match exchange.to_lowercase().as_str() {
         "bybit" => Ok(ExchangeType::Bybit),
-        "FTX" => Ok(ExchangeType::Ftx),
-        "Ftx" => Ok(ExchangeType::Ftx),
-        "Binance" => Ok(ExchangeType::Binance),
+        "ftx" => Ok(ExchangeType::Ftx),
+        "binance" => Ok(ExchangeType::Binance),
+        "paper" => Ok(ExchangeType::Paper),
         whatever => Err(format!("{} <- is not a exchange type", whatever)),
     }
 }
 
-pub fn init_exchange_client(e_type: ExchangeType, settings: Settings) -> impl ExchangeClient {
-    match e_type {
-        // @TODO propegate recv_window (200)
-        ExchangeType::Bybit => BybitClient::new(settings, 200),
-        ExchangeType::Binance => todo!(),
-        ExchangeType::Ftx => todo!(),
+/// Default REST and WebSocket urls of a venue.
+pub struct VenueUrls {
+    pub mainnet_rest: &'static str,
+    pub mainnet_ws: &'static str,
+    pub testnet_rest: &'static str,
+    pub testnet_ws: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    pub rest_url: String,
+    pub ws_url: String,
+}
+
+/// Picks the urls for the configured environment, refuses mainnet unless `confirm_mainnet` is set.
+pub fn resolve_endpoints(
+    name: &str,
+    conn: &ExchangeSettings,
+    urls: &VenueUrls,
+) -> Result<Endpoints> {
+    match conn.environment {
+        Environment::Testnet => Ok(Endpoints {
+            rest_url: urls.testnet_rest.to_string(),
+            ws_url: urls.testnet_ws.to_string(),
+        }),
+        Environment::Mainnet if !conn.confirm_mainnet => {
+            Err(ExchangeError::configuration_error(format!(
+                "{} is set to mainnet but confirm_mainnet is not set, refusing to trade real funds",
+                name
+            )))
+        }
+        Environment::Mainnet => Ok(Endpoints {
+            rest_url: urls.mainnet_rest.to_string(),
+            ws_url: urls.mainnet_ws.to_string(),
+        }),
+        Environment::Custom => match (&conn.base_url, &conn.ws_url) {
+            (Some(rest_url), Some(ws_url)) => Ok(Endpoints {
+                rest_url: rest_url.clone(),
+                ws_url: ws_url.clone(),
+            }),
+            _ => Err(ExchangeError::configuration_error(format!(
+                "{} uses a custom environment, both base_url and ws_url are required",
+                name
+            ))),
+        },
+    }
+}
+
+/// Builds the http client shared by all requests to one exchange.
+pub fn build_http_client(conn: &ExchangeSettings) -> Result<Client> {
+    let builder = Client::builder()
+        .timeout(Duration::from_millis(conn.request_timeout_ms))
+        .connect_timeout(Duration::from_millis(conn.connect_timeout_ms))
+        .pool_max_idle_per_host(conn.pool_max_idle_per_host)
+        .tcp_keepalive(conn.tcp_keepalive_ms.map(Duration::from_millis));
+
+    builder
+        .build()
+        .map_err(|e| ExchangeError::unknown_error(&format!("Could not build http client: {}", e)))
+}
+
+/// Builds the client for an account from credentials.toml. Paper clients trade against the
+/// market data on `events` and send their order updates there.
+pub fn init_exchange_client(
+    settings: &Settings,
+    exchange_account_id: &str,
+    events: &broadcast::Sender<ExchangeEvent>,
+) -> Result<Arc<dyn ExchangeClient>> {
+    let (name, credentials) = settings.account(exchange_account_id).ok_or_else(|| {
+        ExchangeError::configuration_error(format!(
+            "No credentials for account {}",
+            exchange_account_id
+        ))
+    })?;
+    let conn = settings.exchanges.get(name).cloned().unwrap_or_default();
+    match exchange_from_string(conn.exchange_type(name))
+        .map_err(ExchangeError::configuration_error)?
+    {
+        ExchangeType::Bybit => Ok(Arc::new(BybitClient::new(name, &conn, credentials)?)),
+        ExchangeType::Paper => Ok(PaperClient::start(
+            exchange_account_id,
+            settings.paper.clone(),
+            events.clone(),
+        )),
+        ExchangeType::Binance | ExchangeType::Ftx => Err(ExchangeError::configuration_error(
+            format!("{} is not implemented yet", conn.exchange_type(name)),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::error::ExchangeErrorType;
+
+    const URLS: VenueUrls = VenueUrls {
+        mainnet_rest: "https://main",
+        mainnet_ws: "wss://main",
+        testnet_rest: "https://test",
+        testnet_ws: "wss://test",
+    };
+
+    #[test]
+    fn testnet_is_the_default() {
+        let endpoints = resolve_endpoints("bybit", &ExchangeSettings::default(), &URLS).unwrap();
+        assert_eq!(endpoints.rest_url, "https://test");
+        assert_eq!(endpoints.ws_url, "wss://test");
+    }
+
+    #[test]
+    fn mainnet_requires_confirmation() {
+        let mut conn = ExchangeSettings {
+            environment: Environment::Mainnet,
+            ..Default::default()
+        };
+        let err = resolve_endpoints("bybit", &conn, &URLS).unwrap_err();
+        assert_eq!(err.error_type, ExchangeErrorType::Configuration);
+
+        conn.confirm_mainnet = true;
+        let endpoints = resolve_endpoints("bybit", &conn, &URLS).unwrap();
+        assert_eq!(endpoints.rest_url, "https://main");
+    }
+
+    #[test]
+    fn custom_requires_both_urls() {
+        let mut conn = ExchangeSettings {
+            environment: Environment::Custom,
+            base_url: Some("http://localhost:8080".to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_endpoints("bybit", &conn, &URLS).is_err());
+
+        conn.ws_url = Some("ws://localhost:8081".to_string());
+        let endpoints = resolve_endpoints("bybit", &conn, &URLS).unwrap();
+        assert_eq!(endpoints.ws_url, "ws://localhost:8081");
+    }
+
+    #[test]
+    fn http_client_from_settings() {
+        assert!(build_http_client(&ExchangeSettings::default()).is_ok());
+        let conn = ExchangeSettings {
+            request_timeout_ms: 1,
+            connect_timeout_ms: 1,
+            pool_max_idle_per_host: 0,
+            tcp_keepalive_ms: Some(30_000),
+            ..Default::default()
+        };
+        assert!(build_http_client(&conn).is_ok());
     }
 }
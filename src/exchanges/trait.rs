@@ -5,54 +5,76 @@ use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use super::error::Result;
+use super::instrument::Instrument;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OrderType {
     Limit,
     Market,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Side {
     Buy,
     Sell,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeInForce {
     GoodTillCancel,
     FillOrKill,
     ImmediateOrCancel,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Where an order is on the exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PlaceOrder {
+    /// Set by the oms when left out. An exchange accepts an id only once, so an order whose
+    /// answer got lost can be sent again or looked up with it.
+    #[serde(default)]
+    pub client_order_id: Option<String>,
     pub side: Side,
-    pub symbol: String,
+    pub instrument: Instrument,
     pub order_type: OrderType,
     pub qty: Decimal,
-    pub price: Option<i32>,
+    pub price: Option<Decimal>,
     pub time_in_force: TimeInForce,
     pub reduce_only: bool,
     pub close_on_trigger: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Order {
-    pub user_id: i32,
     pub order_id: String,
-    pub symbol: String,
+    pub client_order_id: Option<String>,
+    pub instrument: Instrument,
     pub side: Side,
     pub order_type: OrderType,
-    pub price: i32,
+    pub price: Decimal,
     pub qty: Decimal,
-    // @TODO Type order status?
-    pub order_status: String,
+    pub order_status: OrderStatus,
+    /// Cumulative, 0 in the answer to a place
+    #[serde(default)]
+    pub filled_qty: Decimal,
+    /// Over everything filled so far, 0 before the first fill
+    #[serde(default)]
+    pub avg_price: Decimal,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExchangeBalance {
     pub balance: Decimal,
+    /// `balance` plus unrealised pnl
+    pub equity: Decimal,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -61,6 +83,28 @@ pub struct ExchangeBalancesAndPositions {
     pub positions: Option<HashMap<String, ExchangeBalance>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Position {
+    pub instrument: Instrument,
+    pub side: Side,
+    pub size: Decimal,
+    pub avg_price: Decimal,
+}
+
+/// One OHLCV candle from the exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Candle {
+    /// ms timestamp
+    pub open_time: u128,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    /// Traded value in the quote currency
+    pub turnover: Decimal,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OrderCanceledId {
     pub order_id: String,
@@ -68,9 +112,36 @@ pub struct OrderCanceledId {
 
 // Rest client
 #[async_trait]
-pub trait ExchangeClient {
+pub trait ExchangeClient: Send + Sync {
     async fn get_balance(&self, symbol: Option<String>) -> Result<ExchangeBalancesAndPositions>;
     async fn place_order(&self, order: PlaceOrder) -> Result<Order>;
-    async fn get_order(&self, symbol: String) -> Result<Vec<Order>>;
-    async fn cancel_order(&self, symbol: String, order_id: String) -> Result<OrderCanceledId>;
+    async fn get_order(&self, instrument: Instrument) -> Result<Vec<Order>>;
+    /// The order sent with `client_order_id`, None if the exchange never accepted it.
+    async fn get_order_by_client_id(
+        &self,
+        instrument: Instrument,
+        client_order_id: String,
+    ) -> Result<Option<Order>>;
+    async fn cancel_order(
+        &self,
+        instrument: Instrument,
+        order_id: String,
+    ) -> Result<OrderCanceledId>;
+    async fn cancel_by_client_id(
+        &self,
+        instrument: Instrument,
+        client_order_id: String,
+    ) -> Result<OrderCanceledId>;
+    /// Open positions on `instrument`, flat ones are left out.
+    async fn get_positions(&self, instrument: Instrument) -> Result<Vec<Position>>;
+    /// Mark price of `instrument`, the last trade for spot.
+    async fn get_mark_price(&self, instrument: Instrument) -> Result<Decimal>;
+    /// Candles of `interval_ms` opening from `start` to `end` (ms), oldest first.
+    async fn get_candles(
+        &self,
+        instrument: Instrument,
+        interval_ms: u64,
+        start: u128,
+        end: u128,
+    ) -> Result<Vec<Candle>>;
 }
@@ -1 +1,489 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::exchanges::error::{ExchangeErrorType, Result};
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{
+    ExchangeClient, Order, OrderCanceledId, OrderType, PlaceOrder, Side, TimeInForce,
+};
+use crate::oms::oms::{self, Oms, OmsError, SharedOms};
+use crate::risk::kill_switch::KillSwitch;
+use crate::risk::manager::{Rejection, RiskManager};
+use crate::settings::settings::{self, RiskSettings, StrategySettings};
+use crate::storage::store::Store;
+use crate::strategy::strategy::{self, Command, Context, Strategy};
+
+/// Sent to a running instance from outside the event stream.
+#[derive(Debug)]
+pub enum Control {
+    /// New settings for the same entry, only `parameters` and `max_amount` may differ.
+    Update(StrategySettings),
+    /// Events are dropped until `Resume`, orders already placed stay.
+    Pause,
+    Resume,
+}
+
+/// Control senders keyed by strategy id.
+pub type Controls = HashMap<String, mpsc::UnboundedSender<Control>>;
+
+/// A running `[[strategies]]` entry.
+pub struct Instance {
+    pub id: String,
+    pub handle: JoinHandle<()>,
+    pub control: mpsc::UnboundedSender<Control>,
+}
+
+/// Runs the strategy instances against the clients of their accounts.
+pub struct Executor {
+    /// Keyed by exchange_account_id
+    pub clients: HashMap<String, Arc<dyn ExchangeClient>>,
+    /// Keyed by exchange_account_id like `clients`
+    pub oms: HashMap<String, SharedOms>,
+    /// Applied to every account on its own.
+    risk: RiskSettings,
+    kill_switch: Arc<KillSwitch>,
+    events: broadcast::Sender<ExchangeEvent>,
+    /// Where the oms writes orders and fills, if anywhere.
+    store: Option<Store>,
+    /// Applying the order updates of each account
+    oms_tasks: Vec<JoinHandle<()>>,
+}
+
+impl Executor {
+    pub fn new(
+        clients: HashMap<String, Arc<dyn ExchangeClient>>,
+        risk: RiskSettings,
+        kill_switch: Arc<KillSwitch>,
+        events: broadcast::Sender<ExchangeEvent>,
+    ) -> Self {
+        let oms = clients
+            .keys()
+            .map(|account| (account.clone(), Oms::shared()))
+            .collect();
+        Self {
+            clients,
+            oms,
+            risk,
+            kill_switch,
+            events,
+            store: None,
+            oms_tasks: vec![],
+        }
+    }
+
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Stops applying order updates, the executor's store sender is gone once this returns.
+    pub async fn stop(mut self) {
+        for task in self.oms_tasks.drain(..) {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    /// Starts one instance per entry, nothing is started if any of them can't be built.
+    pub fn launch(&mut self, strategies: &[StrategySettings]) -> Result<Vec<Instance>, String> {
+        let mut built = vec![];
+        for settings in strategies {
+            let client = self
+                .clients
+                .get(&settings.exchange_account_id)
+                .ok_or_else(|| {
+                    format!(
+                        "{} <- no client for account {}",
+                        settings.id, settings.exchange_account_id
+                    )
+                })?;
+            let strategy =
+                strategy::build(settings).map_err(|e| format!("{}: {}", settings.id, e))?;
+            built.push((settings.clone(), strategy, client.clone()));
+        }
+
+        // one risk manager per account, shared by the instances trading on it
+        let risk: HashMap<String, Arc<RiskManager>> = settings::instruments_by_account(strategies)
+            .into_iter()
+            .map(|(account, instruments)| {
+                let manager = RiskManager::new(self.risk.clone(), instruments);
+                (account, Arc::new(manager))
+            })
+            .collect();
+
+        for (account, oms) in &self.oms {
+            self.oms_tasks.push(tokio::spawn(oms::run(
+                oms.clone(),
+                account.clone(),
+                self.events.subscribe(),
+                self.store.clone(),
+                self.kill_switch.clone(),
+            )));
+        }
+
+        Ok(built
+            .into_iter()
+            .map(|(settings, strategy, client)| {
+                let (control, controls) = mpsc::unbounded_channel();
+                let risk = risk[&settings.exchange_account_id].clone();
+                let oms = self.oms[&settings.exchange_account_id].clone();
+                Instance {
+                    id: settings.id.clone(),
+                    handle: tokio::spawn(run(
+                        strategy,
+                        Context::new(settings, oms),
+                        client,
+                        risk,
+                        self.kill_switch.clone(),
+                        self.events.subscribe(),
+                        controls,
+                    )),
+                    control,
+                }
+            })
+            .collect())
+    }
+}
+
+async fn run(
+    mut strategy: Box<dyn Strategy>,
+    mut ctx: Context,
+    client: Arc<dyn ExchangeClient>,
+    risk: Arc<RiskManager>,
+    kill_switch: Arc<KillSwitch>,
+    mut events: broadcast::Receiver<ExchangeEvent>,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+) {
+    let mut paused = false;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(_) if paused => {}
+                Ok(event) => {
+                    strategy.on_event(&event, &mut ctx);
+                    let commands = ctx.take_commands();
+                    execute(&ctx, client.as_ref(), &risk, &kill_switch, commands).await;
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    println!("[{}] lagging behind, missed {} events", ctx.id(), missed)
+                }
+                Err(RecvError::Closed) => break,
+            },
+            Some(control) = controls.recv() => {
+                apply(strategy.as_mut(), &mut ctx, &mut paused, control)
+            }
+        }
+    }
+}
+
+fn apply(strategy: &mut dyn Strategy, ctx: &mut Context, paused: &mut bool, control: Control) {
+    match control {
+        Control::Update(settings) => match strategy.update_parameters(&settings.parameters) {
+            Ok(()) => {
+                ctx.settings = settings;
+                println!("[{}] settings updated", ctx.id());
+            }
+            Err(e) => println!("[{}] kept the old settings: {}", ctx.id(), e),
+        },
+        Control::Pause => {
+            *paused = true;
+            println!("[{}] paused", ctx.id());
+        }
+        Control::Resume => {
+            *paused = false;
+            println!("[{}] resumed", ctx.id());
+        }
+    }
+}
+
+/// What an account wide operation did, order by order.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub done: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Cancels every open order on `instruments`.
+pub async fn cancel_all(client: &dyn ExchangeClient, instruments: &[Instrument]) -> Report {
+    let mut report = Report::default();
+    for instrument in instruments {
+        let orders = match client.get_order(instrument.clone()).await {
+            Ok(orders) => orders,
+            Err(e) => {
+                report
+                    .failed
+                    .push(format!("{}: could not get open orders: {}", instrument, e));
+                continue;
+            }
+        };
+        for order in orders {
+            match client
+                .cancel_order(instrument.clone(), order.order_id)
+                .await
+            {
+                Ok(canceled) => report
+                    .done
+                    .push(format!("{}: canceled {}", instrument, canceled.order_id)),
+                Err(e) => report.failed.push(format!("{}: {}", instrument, e)),
+            }
+        }
+    }
+    report
+}
+
+/// Cancels every open order on `instruments`, then closes their positions with reduce-only
+/// market orders.
+pub async fn flatten(client: &dyn ExchangeClient, instruments: &[Instrument]) -> Report {
+    let mut report = cancel_all(client, instruments).await;
+    for instrument in instruments {
+        let positions = match client.get_positions(instrument.clone()).await {
+            Ok(positions) => positions,
+            Err(e) => {
+                report
+                    .failed
+                    .push(format!("{}: could not get positions: {}", instrument, e));
+                continue;
+            }
+        };
+        for position in positions.into_iter().filter(|p| !p.size.is_zero()) {
+            let close = PlaceOrder {
+                client_order_id: None,
+                side: match position.side {
+                    Side::Buy => Side::Sell,
+                    Side::Sell => Side::Buy,
+                },
+                instrument: instrument.clone(),
+                order_type: OrderType::Market,
+                qty: position.size,
+                price: None,
+                time_in_force: TimeInForce::ImmediateOrCancel,
+                reduce_only: true,
+                close_on_trigger: false,
+            };
+            match client.place_order(close).await {
+                Ok(order) => report.done.push(format!(
+                    "{}: closing {:?} {} with {}",
+                    instrument, position.side, position.size, order.order_id
+                )),
+                Err(e) => report
+                    .failed
+                    .push(format!("{}: could not close position: {}", instrument, e)),
+            }
+        }
+    }
+    report
+}
+
+/// `flatten` or just `cancel_all` on every account of `instruments`, reports by account.
+pub async fn halt(
+    clients: &HashMap<String, Arc<dyn ExchangeClient>>,
+    instruments: HashMap<String, Vec<Instrument>>,
+    flatten: bool,
+) -> BTreeMap<String, Report> {
+    let mut reports = BTreeMap::new();
+    for (account, instruments) in instruments {
+        let client = match clients.get(&account) {
+            Some(client) => client.as_ref(),
+            None => continue,
+        };
+        let report = match flatten {
+            true => self::flatten(client, &instruments).await,
+            false => cancel_all(client, &instruments).await,
+        };
+        reports.insert(account, report);
+    }
+    reports
+}
+
+/// Sends `order` again when its answer got lost, the exchange refuses a client order id it already
+/// took so that can't fill twice. A refused duplicate is looked up instead.
+async fn place(client: &dyn ExchangeClient, order: PlaceOrder) -> Result<Order> {
+    let client_order_id = match &order.client_order_id {
+        Some(id) => id.clone(),
+        None => return client.place_order(order).await,
+    };
+    let instrument = order.instrument.clone();
+    let e = match client.place_order(order.clone()).await {
+        Err(e) if e.outcome_unknown() => e,
+        placed => return placed,
+    };
+    println!("{}: {}, sending it again", client_order_id, e);
+    match client.place_order(order).await {
+        Err(e) if e.outcome_unknown() || e.error_type == ExchangeErrorType::DuplicateOrder => {
+            match client
+                .get_order_by_client_id(instrument, client_order_id)
+                .await
+            {
+                Ok(Some(order)) => Ok(order),
+                _ => Err(e),
+            }
+        }
+        placed => placed,
+    }
+}
+
+/// Runs the commands of one callback: risk checks, placing and canceling, tracked by the oms.
+pub async fn execute(
+    ctx: &Context,
+    client: &dyn ExchangeClient,
+    risk: &RiskManager,
+    kill_switch: &KillSwitch,
+    commands: Vec<Command>,
+) {
+    let id = ctx.id();
+    // validated to be a positive finite number
+    let max_amount = Decimal::try_from(ctx.settings.max_amount).unwrap_or_default();
+    // the oms is never locked across an await
+    let track = |result: Result<(), OmsError>| {
+        if let Err(e) = result {
+            println!("[{}] oms: {}", id, e);
+        }
+    };
+    for command in commands {
+        match command {
+            Command::PlaceOrder(mut order) => {
+                let client_order_id = match ctx.oms.lock().unwrap().record(id, order.clone()) {
+                    Ok(client_order_id) => client_order_id,
+                    Err(e) => {
+                        println!("[{}] order not sent: {}", id, e);
+                        continue;
+                    }
+                };
+                order.client_order_id = Some(client_order_id.clone());
+                let checked = match kill_switch.tripped() {
+                    Some(trip) => Err(Rejection::Halted(trip)),
+                    None => risk.check(client, &ctx.oms, &order, max_amount).await,
+                };
+                if let Err(rejection) = checked {
+                    println!("[{}] order rejected: {} {:?}", id, rejection, order);
+                    track(
+                        ctx.oms
+                            .lock()
+                            .unwrap()
+                            .reject(&client_order_id, rejection.to_string()),
+                    );
+                    continue;
+                }
+                let placed = place(client, order).await;
+                kill_switch.record_result(&placed);
+                match placed {
+                    Ok(order) => {
+                        println!("[{}] placed {:?}", id, order);
+                        track(ctx.oms.lock().unwrap().placed(&client_order_id, &order));
+                    }
+                    Err(e) if e.outcome_unknown() => {
+                        println!(
+                            "[{}] {} left pending, the reconciler will find it: {}",
+                            id, client_order_id, e
+                        );
+                    }
+                    Err(e) => {
+                        println!("[{}] could not place order: {}", id, e);
+                        track(
+                            ctx.oms
+                                .lock()
+                                .unwrap()
+                                .reject(&client_order_id, e.to_string()),
+                        );
+                    }
+                }
+            }
+            Command::CancelOrder {
+                instrument,
+                order_id,
+            } => {
+                let client_order_id = ctx
+                    .oms
+                    .lock()
+                    .unwrap()
+                    .by_order_id(&order_id)
+                    .map(|o| o.client_order_id.clone());
+                if let Some(client_order_id) = &client_order_id {
+                    track(ctx.oms.lock().unwrap().cancel_requested(client_order_id));
+                }
+                let canceled = client.cancel_order(instrument, order_id).await;
+                canceled_or_not(ctx, kill_switch, client_order_id, canceled);
+            }
+            Command::CancelByClientId {
+                instrument,
+                client_order_id,
+            } => {
+                track(ctx.oms.lock().unwrap().cancel_requested(&client_order_id));
+                let canceled = client
+                    .cancel_by_client_id(instrument, client_order_id.clone())
+                    .await;
+                canceled_or_not(ctx, kill_switch, Some(client_order_id), canceled);
+            }
+        }
+    }
+}
+
+fn canceled_or_not(
+    ctx: &Context,
+    kill_switch: &KillSwitch,
+    client_order_id: Option<String>,
+    canceled: Result<OrderCanceledId>,
+) {
+    let id = ctx.id();
+    kill_switch.record_result(&canceled);
+    let tracked = match &canceled {
+        Ok(canceled) => {
+            println!("[{}] canceled {:?}", id, canceled);
+            client_order_id.map(|c| ctx.oms.lock().unwrap().canceled(&c))
+        }
+        Err(e) => {
+            println!("[{}] could not cancel order: {}", id, e);
+            client_order_id.map(|c| ctx.oms.lock().unwrap().cancel_failed(&c))
+        }
+    };
+    if let Some(Err(e)) = tracked {
+        println!("[{}] oms: {}", id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::fake::FakeClient;
+
+    fn order(client_order_id: Option<&str>) -> PlaceOrder {
+        PlaceOrder {
+            client_order_id: client_order_id.map(str::to_string),
+            side: Side::Buy,
+            instrument: Instrument::perpetual("btc", "usdt"),
+            order_type: OrderType::Limit,
+            qty: dec!(1),
+            price: Some(dec!(100)),
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_answers_never_place_twice() {
+        let fake = FakeClient::default();
+        *fake.lost_answers.lock().unwrap() = 1;
+        let placed = place(&fake, order(Some("mm-1"))).await.unwrap();
+        assert_eq!(placed.client_order_id.as_deref(), Some("mm-1"));
+        assert_eq!(fake.orders.lock().unwrap().len(), 1);
+
+        // without a client id the order can't be told apart, it's not sent again
+        *fake.lost_answers.lock().unwrap() = 1;
+        assert!(place(&fake, order(None))
+            .await
+            .is_err_and(|e| e.outcome_unknown()));
+        assert_eq!(fake.orders.lock().unwrap().len(), 2);
+    }
+}
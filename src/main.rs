@@ -1,46 +1,307 @@
+mod backtest;
+mod cli;
+mod control;
 mod exchanges;
+mod executor;
+mod market;
+mod oms;
+mod risk;
 mod settings;
+mod storage;
+mod strategy;
 
-use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use clap::Parser;
 use settings::settings::Settings;
+use settings::watcher::Watcher;
+use tokio::sync::broadcast;
+
+use crate::control::server::ControlApi;
+use crate::exchanges::rest_client::init_exchange_client;
+use crate::executor::Executor;
+use crate::market::bars::{self, BarBuilder};
+use crate::market::recorder::{self, Recorder};
+use crate::market::replay::{self, Replayer, Speed};
+use crate::oms::reconcile::{self, Reconciler};
+use crate::risk::kill_switch::{self, KillSwitch};
+use crate::storage::store::{self, Store};
 
-use crate::exchanges::{
-    r#trait::{ExchangeClient, OrderType, PlaceOrder, Side, TimeInForce},
-    rest_client::{init_exchange_client, ExchangeType},
-};
+/// Room for events in the broadcast channel before the slowest receiver lags.
+const EVENTS_CAPACITY: usize = 1024;
 
 #[tokio::main]
-async fn main() -> () {
+async fn main() {
     //init settings
-    let set = Settings::new();
-    println!("{:?}", set);
-    //init client
-    let client = init_exchange_client(ExchangeType::Bybit, set);
-    println!("1");
-    let x = client.get_balance(None).await.unwrap();
-    println!("2");
-    let order = PlaceOrder {
-        side: Side::Sell,
-        symbol: "BTCUSDT".to_string(),
-        order_type: OrderType::Limit,
-        qty: dec!(0.001),
-        price: Some(22200),
-        time_in_force: TimeInForce::GoodTillCancel,
-        reduce_only: false,
-        close_on_trigger: false,
+    let cli = cli::Cli::parse();
+    let backtest = match &cli.command {
+        Some(cli::Command::Credentials(command)) => {
+            if let Err(e) = cli::run_credentials(command) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(cli::Command::Backtest) => true,
+        None => false,
+    };
+    let mut options = cli.settings_options();
+    if let Err(e) = options.unlock() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let set = match Settings::load_with(&options) {
+        Ok(set) => set,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
-    let y = client.place_order(order).await.unwrap();
-    let order_id = &y.order_id;
-    let z = client.get_order("BTCUSDT".to_string()).await.unwrap();
-    let cancel = client
-        .cancel_order("BTCUSDT".to_string(), order_id.to_string())
-        .await
-        .unwrap();
-    println!("3");
-    println!("{:#?}", x);
-    println!("{:#?}", y);
-    println!("{:#?}", z);
-    println!("{:#?}", cancel);
-    //init server for settings updates (@TODO l8r on)
+    println!("{}", set.describe_sources());
+
+    //a backtest runs on simulated time and accounts, none of the engine is started
+    if backtest {
+        if let Err(e) = cli::run_backtest(&set).await {
+            eprintln!("backtest: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    //paper accounts trade against the market data on the event stream
+    let (events_sender, _) = broadcast::channel(EVENTS_CAPACITY);
+
+    //init a client per account the strategies trade on
+    let mut clients = HashMap::new();
+    for strategy in &set.strategies {
+        let account = &strategy.exchange_account_id;
+        if clients.contains_key(account) {
+            continue;
+        }
+        match init_exchange_client(&set, account, &events_sender) {
+            Ok(client) => {
+                match client.get_balance(None).await {
+                    Ok(balance) => println!("{}: {:#?}", account, balance),
+                    Err(e) => println!("{}: could not get the balance: {}", account, e),
+                }
+                clients.insert(account.clone(), client);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", account, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    //open the database before anything can fill
+    let (store, store_writer) = match set.storage.enabled {
+        false => (None, None),
+        true => match Store::open(&set.storage.path) {
+            Ok((store, writer)) => (Some(store), Some(writer)),
+            Err(e) => {
+                eprintln!("storage: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
     //start exectuor
+    let feed = events_sender.subscribe();
+    let stored_events = events_sender.subscribe();
+    let mut kill_switch_settings = set.kill_switch.clone();
+    if !set.has_market_feed() && kill_switch_settings.feed_timeout_ms.take().is_some() {
+        println!("kill switch: no market data feed without [replay], feed_timeout_ms is ignored");
+    }
+    let kill_switch = Arc::new(KillSwitch::new(kill_switch_settings));
+    let mut executor = Executor::new(
+        clients,
+        set.risk.clone(),
+        kill_switch.clone(),
+        events_sender.clone(),
+    );
+    if let Some(store) = &store {
+        executor = executor.with_store(store.clone());
+    }
+    let instances = match executor.launch(&set.strategies) {
+        Ok(instances) => instances,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for strategy in &set.strategies {
+        let client = &executor.clients[&strategy.exchange_account_id];
+        for pair in &strategy.pairs {
+            match client.get_order(pair.instrument()).await {
+                Ok(orders) => println!("[{}] open orders: {:#?}", strategy.id, orders),
+                Err(e) => println!("[{}] could not get open orders: {}", strategy.id, e),
+            }
+        }
+    }
+    for instance in &instances {
+        println!("started {}", instance.id);
+    }
+
+    //cancel everything when the kill switch trips
+    let instruments = settings::settings::instruments_by_account(&set.strategies);
+    let breaker = tokio::spawn(kill_switch::run(
+        kill_switch.clone(),
+        executor.clients.clone(),
+        instruments.clone(),
+        feed,
+    ));
+
+    //compare the local orders and positions with the exchange
+    let reconcilers: Vec<_> = executor
+        .clients
+        .iter()
+        .map(|(account, client)| {
+            tokio::spawn(reconcile::run(Reconciler::new(
+                account.clone(),
+                client.clone(),
+                executor.oms[account].clone(),
+                instruments.get(account).cloned().unwrap_or_default(),
+                set.reconcile.clone(),
+                events_sender.clone(),
+            )))
+        })
+        .collect();
+
+    //write events and snapshots
+    let snapshots = store.clone().map(|store| {
+        tokio::spawn(store::run(
+            store,
+            executor.oms.clone(),
+            set.storage.snapshot_interval_ms,
+            stored_events,
+        ))
+    });
+
+    //record the market data
+    let recorder = match set.recorder.enabled {
+        false => None,
+        true => {
+            let (sender, writer) =
+                recorder::start(Recorder::new(&set.recorder.dir, set.recorder.level));
+            let task = tokio::spawn(recorder::run(sender, events_sender.subscribe()));
+            Some((task, writer))
+        }
+    };
+
+    //build bars from the trades, the first time bars start from the exchange's klines. Without a
+    //feed there are no trades to build them from
+    let mut builders: Vec<BarBuilder> = vec![];
+    let now = exchanges::util::millseconds().unwrap_or_default();
+    for strategy in set.strategies.iter().filter(|_| set.has_market_feed()) {
+        let client = &executor.clients[&strategy.exchange_account_id];
+        for pair in &strategy.pairs {
+            for spec in strategy.bar_specs() {
+                let instrument = pair.instrument();
+                if builders
+                    .iter()
+                    .any(|b| b.instrument == instrument && b.spec == spec)
+                {
+                    continue;
+                }
+                let mut builder = BarBuilder::new(instrument, spec, bars::TOLERANCE_MS);
+                if let Err(e) = builder.backfill(client.as_ref(), now).await {
+                    println!("[{}] could not backfill {} bars: {}", strategy.id, spec, e);
+                }
+                builders.push(builder);
+            }
+        }
+    }
+    if !set.has_market_feed() && set.strategies.iter().any(|s| !s.bar_specs().is_empty()) {
+        println!("bars: no market data feed without [replay], no bars are built");
+    }
+    let bar_builder = tokio::spawn(bars::run(
+        builders,
+        events_sender.subscribe(),
+        events_sender.clone(),
+    ));
+
+    //play a recording in, once everything listens
+    let replay = match set.replay.enabled {
+        false => None,
+        true => {
+            let replayer = match Replayer::open(&set.replay.dir) {
+                Ok(replayer) => replayer,
+                Err(e) => {
+                    eprintln!("replay: {}: {}", set.replay.dir, e);
+                    std::process::exit(1);
+                }
+            };
+            let speed = set.replay.speed.parse().unwrap_or(Speed::Realtime);
+            let sender = events_sender.clone();
+            Some(tokio::spawn(async move {
+                match replay::run(replayer, speed, sender, EVENTS_CAPACITY).await {
+                    Ok(sent) => println!("replay: done, {} events", sent),
+                    Err(e) => println!("replay: stopped: {}", e),
+                }
+            }))
+        }
+    };
+
+    //reload strategy parameters when the settings files change
+    let controls: executor::Controls = instances
+        .iter()
+        .map(|i| (i.id.clone(), i.control.clone()))
+        .collect();
+    let api_enabled = set.control_api.enabled;
+    let set = Arc::new(RwLock::new(set));
+    let watcher = tokio::spawn(Watcher::new(options, set.clone(), controls.clone()).run());
+
+    //local control api
+    let api = match api_enabled {
+        false => None,
+        true => match ControlApi::new(set, controls, executor.clients.clone(), kill_switch) {
+            Ok(api) => Some(tokio::spawn(async move {
+                if let Err(e) = api.serve().await {
+                    eprintln!("control api stopped: {}", e);
+                }
+            })),
+            Err(e) => {
+                eprintln!("control api: could not open the audit log: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    tokio::signal::ctrl_c().await.unwrap();
+    watcher.abort();
+    breaker.abort();
+    bar_builder.abort();
+    if let Some(replay) = replay {
+        replay.abort();
+    }
+    for reconciler in reconcilers {
+        reconciler.abort();
+    }
+    if let Some(api) = api {
+        api.abort();
+    }
+    for instance in instances {
+        instance.handle.abort();
+    }
+    //the files are finished once the sender is gone
+    if let Some((task, writer)) = recorder {
+        task.abort();
+        let _ = task.await;
+        let _ = tokio::task::spawn_blocking(|| writer.join()).await;
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.abort();
+        let _ = snapshots.await;
+    }
+    executor.stop().await;
+    //the writer is done once every sender is gone
+    if let Some(store) = store {
+        if let Err(e) = store.flush().await {
+            eprintln!("storage: {}", e);
+        }
+    }
+    if let Some(writer) = store_writer {
+        let _ = tokio::task::spawn_blocking(|| writer.join()).await;
+    }
 }
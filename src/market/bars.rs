@@ -0,0 +1,501 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::exchanges::error::Result;
+use crate::exchanges::event::{ExchangeEvent, Trade};
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{Candle, ExchangeClient};
+
+/// How late a trade may arrive and still go into its bar, in ms.
+pub const TOLERANCE_MS: u128 = 2_000;
+
+const SECOND: u64 = 1_000;
+const DAY: u64 = 86_400 * SECOND;
+
+/// What closes a bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BarSpec {
+    /// Every interval of this many ms, from 1s to 1d.
+    Time(u64),
+    /// Every this many trades.
+    Tick(u64),
+    /// Once this much qty traded.
+    Volume(Decimal),
+    /// Once this much qty times price traded.
+    Dollar(Decimal),
+}
+
+/// `1s`, `15m`, `4h`, `1d`, `tick:100`, `volume:10`, `dollar:100000`
+impl FromStr for BarSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let threshold = |raw: &str| match raw.parse::<Decimal>() {
+            Ok(t) if t > Decimal::ZERO => Ok(t),
+            _ => Err(format!("{:?} is not a threshold above 0", raw)),
+        };
+        match s.split_once(':') {
+            Some(("tick", n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(BarSpec::Tick(n)),
+                _ => Err(format!("{:?} is not a trade count above 0", n)),
+            },
+            Some(("volume", t)) => Ok(BarSpec::Volume(threshold(t)?)),
+            Some(("dollar", t)) => Ok(BarSpec::Dollar(threshold(t)?)),
+            Some(_) => Err(format!(
+                "{:?} is not a bar, expected tick:, volume: or dollar:",
+                s
+            )),
+            None => {
+                let unit = match s.chars().last() {
+                    Some('s') => SECOND,
+                    Some('m') => 60 * SECOND,
+                    Some('h') => 3_600 * SECOND,
+                    Some('d') => DAY,
+                    _ => return Err(format!("{:?} is not an interval like 15m", s)),
+                };
+                match s[..s.len() - 1].parse::<u64>() {
+                    Ok(n) if n > 0 && n * unit <= DAY => Ok(BarSpec::Time(n * unit)),
+                    _ => Err(format!("{:?} is not an interval from 1s to 1d", s)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for BarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BarSpec::Time(ms) => {
+                let (n, unit) = [(DAY, "d"), (3_600 * SECOND, "h"), (60 * SECOND, "m")]
+                    .into_iter()
+                    .find(|(unit, _)| ms % unit == 0)
+                    .map_or((ms / SECOND, "s"), |(size, unit)| (ms / size, unit));
+                write!(f, "{}{}", n, unit)
+            }
+            BarSpec::Tick(n) => write!(f, "tick:{}", n),
+            BarSpec::Volume(t) => write!(f, "volume:{}", t),
+            BarSpec::Dollar(t) => write!(f, "dollar:{}", t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Bar {
+    pub instrument: Instrument,
+    pub spec: BarSpec,
+    /// ms, the interval's start for time bars and the first trade for the others
+    pub open_time: u128,
+    /// ms, the interval's end (exclusive) for time bars and the last trade for the others
+    pub close_time: u128,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    /// qty times price
+    pub notional: Decimal,
+    pub trades: u64,
+}
+
+/// A bar still taking trades, open and close go by the trade timestamps so out of order trades
+/// land where they belong.
+#[derive(Debug, Clone)]
+struct Partial {
+    bar: Bar,
+    first: u128,
+    last: u128,
+}
+
+impl Partial {
+    fn new(instrument: &Instrument, spec: BarSpec, open_time: u128, trade: &Trade) -> Self {
+        Self {
+            bar: Bar {
+                instrument: instrument.clone(),
+                spec,
+                open_time,
+                close_time: trade.timestamp,
+                open: trade.price,
+                high: trade.price,
+                low: trade.price,
+                close: trade.price,
+                volume: Decimal::ZERO,
+                notional: Decimal::ZERO,
+                trades: 0,
+            },
+            first: trade.timestamp,
+            last: trade.timestamp,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        let bar = &mut self.bar;
+        if trade.timestamp < self.first {
+            self.first = trade.timestamp;
+            bar.open = trade.price;
+        }
+        if trade.timestamp >= self.last {
+            self.last = trade.timestamp;
+            bar.close = trade.price;
+        }
+        bar.high = bar.high.max(trade.price);
+        bar.low = bar.low.min(trade.price);
+        bar.volume += trade.qty;
+        bar.notional += trade.qty * trade.price;
+        bar.trades += 1;
+    }
+
+    fn full(&self) -> bool {
+        match self.bar.spec {
+            BarSpec::Time(_) => false,
+            BarSpec::Tick(n) => self.bar.trades >= n,
+            BarSpec::Volume(t) => self.bar.volume >= t,
+            BarSpec::Dollar(t) => self.bar.notional >= t,
+        }
+    }
+}
+
+/// Turns one instrument's trades into bars of one spec. Time bars close once the newest trade
+/// (or `advance`) is `tolerance` past their end, the others as soon as they're full. A trade
+/// more than `tolerance` older than the newest one, or for a bar that already closed, is dropped.
+#[derive(Debug)]
+pub struct BarBuilder {
+    pub instrument: Instrument,
+    pub spec: BarSpec,
+    tolerance: u128,
+    /// Time bars by their start, a single one for the others
+    open: BTreeMap<u128, Partial>,
+    /// Trades before this are too late, as are those `tolerance` before `newest`
+    closed_until: u128,
+    /// Newest trade timestamp seen
+    newest: u128,
+    /// The kline the first bar was seeded with covers trades up to here
+    seeded_until: Option<(u128, u128)>,
+    pub dropped: u64,
+}
+
+impl BarBuilder {
+    pub fn new(instrument: Instrument, spec: BarSpec, tolerance: u128) -> Self {
+        Self {
+            instrument,
+            spec,
+            tolerance,
+            open: BTreeMap::new(),
+            closed_until: 0,
+            newest: 0,
+            seeded_until: None,
+            dropped: 0,
+        }
+    }
+
+    /// Adds a trade, returns the bars it closed.
+    pub fn push(&mut self, trade: &Trade) -> Vec<Bar> {
+        if trade.instrument != self.instrument {
+            return vec![];
+        }
+        if trade.timestamp < self.closed_until || trade.timestamp + self.tolerance < self.newest {
+            self.dropped += 1;
+            return vec![];
+        }
+        self.newest = self.newest.max(trade.timestamp);
+        match self.spec {
+            BarSpec::Time(interval) => {
+                let start = trade.timestamp - trade.timestamp % u128::from(interval);
+                if let Some((bar, until)) = self.seeded_until {
+                    if bar == start && trade.timestamp <= until {
+                        return self.advance(self.newest);
+                    }
+                }
+                let (instrument, spec) = (&self.instrument, self.spec);
+                self.open
+                    .entry(start)
+                    .or_insert_with(|| Partial::new(instrument, spec, start, trade))
+                    .add(trade);
+                self.advance(self.newest)
+            }
+            _ => {
+                let (instrument, spec) = (&self.instrument, self.spec);
+                let partial = self
+                    .open
+                    .entry(0)
+                    .or_insert_with(|| Partial::new(instrument, spec, trade.timestamp, trade));
+                partial.add(trade);
+                if !partial.full() {
+                    return vec![];
+                }
+                let mut partial = self.open.remove(&0).unwrap();
+                partial.bar.open_time = partial.first;
+                partial.bar.close_time = partial.last;
+                self.closed_until = partial.last.saturating_sub(self.tolerance);
+                vec![partial.bar]
+            }
+        }
+    }
+
+    /// Closes the time bars that ended `tolerance` before `now`, for quiet markets.
+    pub fn advance(&mut self, now: u128) -> Vec<Bar> {
+        let interval = match self.spec {
+            BarSpec::Time(interval) => u128::from(interval),
+            _ => return vec![],
+        };
+        let watermark = now.saturating_sub(self.tolerance);
+        let mut closed = vec![];
+        while let Some(entry) = self.open.first_entry() {
+            let end = entry.key() + interval;
+            if end > watermark {
+                break;
+            }
+            let mut partial = entry.remove();
+            partial.bar.close_time = end;
+            self.closed_until = self.closed_until.max(end);
+            closed.push(partial.bar);
+        }
+        closed
+    }
+
+    /// Starts the current time bar from the exchange's candle of it, as it was at `now`, so it
+    /// isn't missing what traded before the feed started. Other bars can't be backfilled.
+    pub fn seed(&mut self, candle: &Candle, now: u128) {
+        let interval = match self.spec {
+            BarSpec::Time(interval) => u128::from(interval),
+            _ => return,
+        };
+        if now < candle.open_time || now >= candle.open_time + interval {
+            return;
+        }
+        self.open.insert(
+            candle.open_time,
+            Partial {
+                bar: Bar {
+                    instrument: self.instrument.clone(),
+                    spec: self.spec,
+                    open_time: candle.open_time,
+                    close_time: now,
+                    open: candle.open,
+                    high: candle.high,
+                    low: candle.low,
+                    close: candle.close,
+                    volume: candle.volume,
+                    notional: candle.turnover,
+                    // a kline doesn't say
+                    trades: 0,
+                },
+                first: candle.open_time,
+                last: now,
+            },
+        );
+        self.seeded_until = Some((candle.open_time, now));
+    }
+
+    /// `seed` from the client's candle of the current interval.
+    pub async fn backfill(&mut self, client: &dyn ExchangeClient, now: u128) -> Result<()> {
+        let interval = match self.spec {
+            BarSpec::Time(interval) => interval,
+            _ => return Ok(()),
+        };
+        let start = now - now % u128::from(interval);
+        let candles = client
+            .get_candles(self.instrument.clone(), interval, start, now)
+            .await?;
+        if let Some(candle) = candles.iter().find(|c| c.open_time == start) {
+            self.seed(candle, now);
+        }
+        Ok(())
+    }
+}
+
+/// Feeds the trades to `builders` and sends every bar they close as an `ExchangeEvent::Bar`.
+/// Quiet markets are advanced by the trades' time, the newest trade's timestamp moved on by the
+/// time since it came in, so a replayed recording closes its bars like the live feed did.
+pub async fn run(
+    mut builders: Vec<BarBuilder>,
+    mut events: broadcast::Receiver<ExchangeEvent>,
+    sender: broadcast::Sender<ExchangeEvent>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut newest: Option<(u128, Instant)> = None;
+    loop {
+        let bars: Vec<Bar> = tokio::select! {
+            event = events.recv() => match event {
+                Ok(ExchangeEvent::Trade(trade)) => {
+                    let timestamp = newest.map_or(0, |(t, _)| t).max(trade.timestamp);
+                    newest = Some((timestamp, Instant::now()));
+                    builders.iter_mut().flat_map(|b| b.push(&trade)).collect()
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    println!("bars: missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = interval.tick() => match newest {
+                Some((timestamp, at)) => {
+                    let now = timestamp + at.elapsed().as_millis();
+                    builders.iter_mut().flat_map(|b| b.advance(now)).collect()
+                }
+                None => continue,
+            }
+        };
+        for bar in bars {
+            // nobody listening is fine
+            let _ = sender.send(ExchangeEvent::Bar(bar));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::r#trait::Side;
+
+    fn btc() -> Instrument {
+        Instrument::perpetual("btc", "usdt")
+    }
+
+    fn trade(timestamp: u128, price: Decimal, qty: Decimal) -> Trade {
+        Trade {
+            exchange: "bybit".to_string(),
+            instrument: btc(),
+            side: Side::Buy,
+            price,
+            qty,
+            timestamp,
+            received: timestamp,
+        }
+    }
+
+    #[test]
+    fn specs() {
+        for (raw, spec) in [
+            ("1s", BarSpec::Time(1_000)),
+            ("15m", BarSpec::Time(900_000)),
+            ("4h", BarSpec::Time(14_400_000)),
+            ("1d", BarSpec::Time(86_400_000)),
+            ("tick:100", BarSpec::Tick(100)),
+            ("volume:2.5", BarSpec::Volume(dec!(2.5))),
+            ("dollar:100000", BarSpec::Dollar(dec!(100000))),
+        ] {
+            assert_eq!(raw.parse::<BarSpec>(), Ok(spec));
+            assert_eq!(spec.to_string(), raw);
+        }
+        for bad in ["2d", "0s", "5x", "tick:0", "volume:-1", "renko:5", ""] {
+            assert!(bad.parse::<BarSpec>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn time_bars_take_late_trades() {
+        let mut builder = BarBuilder::new(btc(), BarSpec::Time(1_000), 500);
+        assert!(builder.push(&trade(1_600, dec!(10), dec!(1))).is_empty());
+        assert!(builder.push(&trade(1_900, dec!(12), dec!(1))).is_empty());
+        // out of order, still inside the tolerance
+        assert!(builder.push(&trade(1_450, dec!(9), dec!(2))).is_empty());
+        assert!(builder.push(&trade(2_200, dec!(11), dec!(1))).is_empty());
+
+        let closed = builder.push(&trade(2_600, dec!(13), dec!(1)));
+        assert_eq!(closed.len(), 1);
+        let bar = &closed[0];
+        assert_eq!((bar.open_time, bar.close_time), (1_000, 2_000));
+        assert_eq!(
+            (bar.open, bar.high, bar.low, bar.close),
+            (dec!(9), dec!(12), dec!(9), dec!(12))
+        );
+        assert_eq!(bar.volume, dec!(4));
+        assert_eq!(bar.notional, dec!(40));
+        assert_eq!(bar.trades, 3);
+
+        // its bar has closed
+        assert!(builder.push(&trade(1_999, dec!(1), dec!(1))).is_empty());
+        assert_eq!(builder.dropped, 1);
+
+        let closed = builder.advance(4_000);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].close, dec!(13));
+    }
+
+    #[test]
+    fn threshold_bars() {
+        let mut ticks = BarBuilder::new(btc(), BarSpec::Tick(2), 500);
+        assert!(ticks.push(&trade(1, dec!(10), dec!(1))).is_empty());
+        let closed = ticks.push(&trade(2, dec!(11), dec!(1)));
+        assert_eq!(closed[0].trades, 2);
+        assert_eq!((closed[0].open_time, closed[0].close_time), (1, 2));
+
+        let mut dollars = BarBuilder::new(btc(), BarSpec::Dollar(dec!(100)), 500);
+        assert!(dollars.push(&trade(1, dec!(10), dec!(5))).is_empty());
+        let closed = dollars.push(&trade(2, dec!(10), dec!(6)));
+        assert_eq!(closed[0].notional, dec!(110));
+
+        let mut volume = BarBuilder::new(btc(), BarSpec::Volume(dec!(3)), 500);
+        assert!(volume.push(&trade(1, dec!(10), dec!(2))).is_empty());
+        assert_eq!(volume.push(&trade(2, dec!(10), dec!(1)))[0].volume, dec!(3));
+    }
+
+    #[test]
+    fn seeded_from_a_candle() {
+        let mut builder = BarBuilder::new(btc(), BarSpec::Time(60_000), 500);
+        builder.seed(
+            &Candle {
+                open_time: 60_000,
+                open: dec!(10),
+                high: dec!(15),
+                low: dec!(8),
+                close: dec!(12),
+                volume: dec!(7),
+                turnover: dec!(70),
+            },
+            90_000,
+        );
+        // already in the candle
+        builder.push(&trade(89_000, dec!(20), dec!(1)));
+        builder.push(&trade(95_000, dec!(7), dec!(1)));
+        let closed = builder.advance(121_000);
+        assert_eq!(closed.len(), 1);
+        let bar = &closed[0];
+        assert_eq!(
+            (bar.open, bar.high, bar.low, bar.close),
+            (dec!(10), dec!(15), dec!(7), dec!(7))
+        );
+        assert_eq!(bar.volume, dec!(8));
+    }
+
+    #[tokio::test]
+    async fn old_trades_close_by_their_own_time() {
+        let builders = vec![BarBuilder::new(btc(), BarSpec::Time(60_000), TOLERANCE_MS)];
+        let (events, _) = broadcast::channel(16);
+        let (sender, mut bars) = broadcast::channel(16);
+        let task = tokio::spawn(run(builders, events.subscribe(), sender));
+
+        // a recording from long ago, a tick passes in between
+        let at = 1_680_271_200_000;
+        events
+            .send(ExchangeEvent::Trade(trade(at, dec!(100), dec!(1))))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1_100)).await;
+        events
+            .send(ExchangeEvent::Trade(trade(at + 10_000, dec!(110), dec!(1))))
+            .unwrap();
+        events
+            .send(ExchangeEvent::Trade(trade(at + 62_000, dec!(120), dec!(1))))
+            .unwrap();
+
+        let bar = match tokio::time::timeout(Duration::from_secs(1), bars.recv()).await {
+            Ok(Ok(ExchangeEvent::Bar(bar))) => bar,
+            other => panic!("expected a bar, got {:?}", other),
+        };
+        task.abort();
+        assert_eq!(bar.open_time, at);
+        assert_eq!(bar.volume, dec!(2));
+        assert_eq!(bar.close, dec!(110));
+    }
+}
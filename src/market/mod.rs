@@ -0,0 +1,4 @@
+pub mod bars;
+pub mod order_book;
+pub mod recorder;
+pub mod replay;
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::Side;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Level {
+    pub price: Decimal,
+    pub size: Decimal,
+}
+
+/// One level of a snapshot or delta, `size` is ignored for deletes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelChange {
+    pub side: Side,
+    pub price: Decimal,
+    pub size: Decimal,
+}
+
+/// What a venue's book stream sends, translated by the venue's client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookMessage {
+    Snapshot {
+        levels: Vec<LevelChange>,
+        seq: u64,
+        /// Exchange timestamp in ms
+        timestamp: u128,
+    },
+    Delta {
+        delete: Vec<LevelChange>,
+        update: Vec<LevelChange>,
+        insert: Vec<LevelChange>,
+        seq: u64,
+        timestamp: u128,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum BookError {
+    #[error("no snapshot since the last resync")]
+    NotSynced,
+    #[error("sequence went from {last} to {got}")]
+    Sequence { last: u64, got: u64 },
+    #[error("no {side:?} level at {price} to change, a message was missed")]
+    MissingLevel { side: Side, price: Decimal },
+    #[error("a {side:?} level at {price} is already there")]
+    LevelExists { side: Side, price: Decimal },
+    #[error("crossed, best bid {bid} at or above best ask {ask}")]
+    Crossed { bid: Decimal, ask: Decimal },
+}
+
+impl BookMessage {
+    pub fn timestamp(&self) -> u128 {
+        match self {
+            BookMessage::Snapshot { timestamp, .. } | BookMessage::Delta { timestamp, .. } => {
+                *timestamp
+            }
+        }
+    }
+}
+
+/// Local L2 book of one instrument. Once a message doesn't fit, a sequence number that isn't
+/// above the last one, a level that should or shouldn't be there or a crossed book, it's emptied
+/// and asks for a resync until the next snapshot. Sequences may skip numbers, so a gap by itself
+/// only shows as a missing or unexpected level.
+#[derive(Debug)]
+pub struct OrderBook {
+    pub instrument: Instrument,
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+    /// None until the first snapshot and after every failure
+    seq: Option<u64>,
+    /// ms timestamp of the last message applied
+    pub timestamp: u128,
+    resync: Option<mpsc::UnboundedSender<Instrument>>,
+}
+
+impl OrderBook {
+    pub fn new(instrument: Instrument) -> Self {
+        Self {
+            instrument,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            seq: None,
+            timestamp: 0,
+            resync: None,
+        }
+    }
+
+    /// The instrument is sent on `resync` every time the book falls out of sync.
+    #[allow(dead_code)]
+    pub fn with_resync(mut self, resync: mpsc::UnboundedSender<Instrument>) -> Self {
+        self.resync = Some(resync);
+        self
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.seq.is_some()
+    }
+
+    pub fn apply(&mut self, message: BookMessage) -> Result<(), BookError> {
+        let applied = self.try_apply(message).and_then(|_| self.check_crossed());
+        if applied.is_err() {
+            let requested = self.is_synced();
+            self.bids.clear();
+            self.asks.clear();
+            self.seq = None;
+            // once per failure, deltas before the next snapshot fail quietly
+            if let (true, Some(resync)) = (requested, &self.resync) {
+                let _ = resync.send(self.instrument.clone());
+            }
+        }
+        applied
+    }
+
+    fn try_apply(&mut self, message: BookMessage) -> Result<(), BookError> {
+        match message {
+            BookMessage::Snapshot {
+                levels,
+                seq,
+                timestamp,
+            } => {
+                self.bids.clear();
+                self.asks.clear();
+                for level in levels {
+                    if !level.size.is_zero() {
+                        self.side_mut(level.side).insert(level.price, level.size);
+                    }
+                }
+                self.seq = Some(seq);
+                self.timestamp = timestamp;
+            }
+            BookMessage::Delta {
+                delete,
+                update,
+                insert,
+                seq,
+                timestamp,
+            } => {
+                let last = self.seq.ok_or(BookError::NotSynced)?;
+                if seq <= last {
+                    return Err(BookError::Sequence { last, got: seq });
+                }
+                for level in delete {
+                    self.side_mut(level.side).remove(&level.price).ok_or(
+                        BookError::MissingLevel {
+                            side: level.side,
+                            price: level.price,
+                        },
+                    )?;
+                }
+                for level in update {
+                    let size = self.side_mut(level.side).get_mut(&level.price).ok_or(
+                        BookError::MissingLevel {
+                            side: level.side,
+                            price: level.price,
+                        },
+                    )?;
+                    *size = level.size;
+                }
+                for level in insert {
+                    if self
+                        .side_mut(level.side)
+                        .insert(level.price, level.size)
+                        .is_some()
+                    {
+                        return Err(BookError::LevelExists {
+                            side: level.side,
+                            price: level.price,
+                        });
+                    }
+                }
+                self.seq = Some(seq);
+                self.timestamp = timestamp;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_crossed(&self) -> Result<(), BookError> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) if bid.price >= ask.price => Err(BookError::Crossed {
+                bid: bid.price,
+                ask: ask.price,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Decimal, Decimal> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Level> {
+        self.depth(Side::Buy, 1).into_iter().next()
+    }
+
+    pub fn best_ask(&self) -> Option<Level> {
+        self.depth(Side::Sell, 1).into_iter().next()
+    }
+
+    /// Up to `levels` levels of one side, best first.
+    pub fn depth(&self, side: Side, levels: usize) -> Vec<Level> {
+        self.levels(side).take(levels).collect()
+    }
+
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        let level = |(price, size): (&Decimal, &Decimal)| Level {
+            price: *price,
+            size: *size,
+        };
+        match side {
+            Side::Buy => Box::new(self.bids.iter().rev().map(level)),
+            Side::Sell => Box::new(self.asks.iter().map(level)),
+        }
+    }
+
+    pub fn mid(&self) -> Option<Decimal> {
+        let (bid, ask) = (self.best_bid()?, self.best_ask()?);
+        Some((bid.price + ask.price) / Decimal::TWO)
+    }
+
+    /// The mid weighted towards the side with less size on top, where the price is more
+    /// likely to go.
+    #[allow(dead_code)]
+    pub fn microprice(&self) -> Option<Decimal> {
+        let (bid, ask) = (self.best_bid()?, self.best_ask()?);
+        let size = bid.size + ask.size;
+        if size.is_zero() {
+            return self.mid();
+        }
+        Some((bid.price * ask.size + ask.price * bid.size) / size)
+    }
+
+    /// Average price a `side` order of `qty` would fill at taking the book, None if the book
+    /// isn't that deep.
+    #[allow(dead_code)]
+    pub fn vwap(&self, side: Side, qty: Decimal) -> Option<Decimal> {
+        if qty <= Decimal::ZERO {
+            return None;
+        }
+        let against = match side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        let (mut left, mut notional) = (qty, Decimal::ZERO);
+        for level in self.levels(against) {
+            let taken = left.min(level.size);
+            notional += taken * level.price;
+            left -= taken;
+            if left.is_zero() {
+                return Some(notional / qty);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    fn level(side: Side, price: Decimal, size: Decimal) -> LevelChange {
+        LevelChange { side, price, size }
+    }
+
+    fn snapshot() -> BookMessage {
+        BookMessage::Snapshot {
+            levels: vec![
+                level(Side::Buy, dec!(99), dec!(3)),
+                level(Side::Buy, dec!(100), dec!(1)),
+                level(Side::Sell, dec!(101), dec!(3)),
+                level(Side::Sell, dec!(102), dec!(5)),
+            ],
+            seq: 10,
+            timestamp: 1,
+        }
+    }
+
+    fn delta(
+        seq: u64,
+        delete: Vec<LevelChange>,
+        update: Vec<LevelChange>,
+        insert: Vec<LevelChange>,
+    ) -> BookMessage {
+        BookMessage::Delta {
+            delete,
+            update,
+            insert,
+            seq,
+            timestamp: 2,
+        }
+    }
+
+    fn book() -> OrderBook {
+        let mut book = OrderBook::new(Instrument::perpetual("btc", "usd"));
+        book.apply(snapshot()).unwrap();
+        book
+    }
+
+    #[test]
+    fn queries() {
+        let book = book();
+        assert_eq!(
+            book.best_bid(),
+            Some(Level {
+                price: dec!(100),
+                size: dec!(1)
+            })
+        );
+        assert_eq!(book.best_ask().unwrap().price, dec!(101));
+        assert_eq!(book.depth(Side::Sell, 5).len(), 2);
+        assert_eq!(book.mid(), Some(dec!(100.5)));
+        // less on the bid, the price leans towards it
+        assert_eq!(book.microprice(), Some(dec!(100.25)));
+        assert_eq!(book.vwap(Side::Buy, dec!(3)), Some(dec!(101)));
+        assert_eq!(book.vwap(Side::Buy, dec!(4)), Some(dec!(101.25)));
+        assert_eq!(book.vwap(Side::Sell, dec!(2)), Some(dec!(99.5)));
+        assert_eq!(book.vwap(Side::Buy, dec!(9)), None);
+    }
+
+    #[test]
+    fn deltas() {
+        let mut book = book();
+        book.apply(delta(
+            11,
+            vec![level(Side::Buy, dec!(100), dec!(0))],
+            vec![level(Side::Sell, dec!(101), dec!(2))],
+            vec![level(Side::Buy, dec!(100.5), dec!(4))],
+        ))
+        .unwrap();
+        assert_eq!(book.best_bid().unwrap().price, dec!(100.5));
+        assert_eq!(book.best_ask().unwrap().size, dec!(2));
+        assert_eq!(book.timestamp, 2);
+    }
+
+    #[test]
+    fn gaps_and_crosses_ask_for_a_resync() {
+        let (sender, mut requests) = mpsc::unbounded_channel();
+        let mut book = book().with_resync(sender);
+
+        assert_eq!(
+            book.apply(delta(10, vec![], vec![], vec![])),
+            Err(BookError::Sequence { last: 10, got: 10 })
+        );
+        assert!(!book.is_synced());
+        assert!(book.best_bid().is_none());
+        assert_eq!(
+            book.apply(delta(12, vec![], vec![], vec![])),
+            Err(BookError::NotSynced)
+        );
+        assert!(requests.try_recv().is_ok());
+        assert!(requests.try_recv().is_err());
+
+        book.apply(snapshot()).unwrap();
+        assert_eq!(
+            book.apply(delta(
+                11,
+                vec![],
+                vec![level(Side::Buy, dec!(98), dec!(1))],
+                vec![]
+            )),
+            Err(BookError::MissingLevel {
+                side: Side::Buy,
+                price: dec!(98)
+            })
+        );
+
+        book.apply(snapshot()).unwrap();
+        assert_eq!(
+            book.apply(delta(
+                11,
+                vec![],
+                vec![],
+                vec![level(Side::Buy, dec!(101.5), dec!(1))]
+            )),
+            Err(BookError::Crossed {
+                bid: dec!(101.5),
+                ask: dec!(101)
+            })
+        );
+        assert_eq!(requests.try_recv().unwrap(), book.instrument);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::util;
+
+const HOUR: u128 = 3_600_000;
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recorded {
+    /// Exchange type, `bybit`
+    pub venue: String,
+    pub instrument: Instrument,
+    /// `trade`, `book`
+    pub channel: String,
+    /// Local ms when it came in
+    pub received: u128,
+    /// Exchange ms, if the message has one
+    pub exchange: Option<u128>,
+    /// The message as it came off the socket. Nothing hands them over yet, there is no websocket
+    /// feed, only the normalized events are recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    /// What it was translated into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized: Option<Value>,
+}
+
+impl Recorded {
+    pub fn new(
+        venue: &str,
+        instrument: Instrument,
+        channel: &str,
+        received: u128,
+        exchange: Option<u128>,
+    ) -> Self {
+        Self {
+            venue: venue.to_string(),
+            instrument,
+            channel: channel.to_string(),
+            received,
+            exchange,
+            raw: None,
+            normalized: None,
+        }
+    }
+
+    pub fn normalized<T: Serialize>(mut self, normalized: &T) -> Self {
+        self.normalized = serde_json::to_value(normalized).ok();
+        self
+    }
+}
+
+struct Open {
+    hour: u128,
+    encoder: zstd::Encoder<'static, File>,
+}
+
+/// Writes recordings as zstd compressed ndjson, one file per venue, instrument and hour of
+/// receipt: `<dir>/bybit/BTC_USDT_perp/2023-03-31/14.ndjson.zst`. An hour that has a file
+/// already, after a restart, goes on in a new part `14.1.ndjson.zst`, so a file left unfinished
+/// by a crash is never appended to.
+pub struct Recorder {
+    dir: PathBuf,
+    level: i32,
+    open: HashMap<(String, Instrument), Open>,
+}
+
+impl Recorder {
+    pub fn new(dir: impl AsRef<Path>, level: i32) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            level,
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, recorded: &Recorded) -> io::Result<()> {
+        let key = (recorded.venue.clone(), recorded.instrument.clone());
+        let hour = recorded.received / HOUR;
+        if self.open.get(&key).is_some_and(|open| open.hour != hour) {
+            self.open.remove(&key).unwrap().encoder.finish()?;
+        }
+        let open = match self.open.entry(key) {
+            Entry::Occupied(open) => open.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = (0..)
+                    .map(|part| path(&self.dir, &recorded.venue, &recorded.instrument, hour, part))
+                    .find(|path| !path.exists())
+                    .unwrap();
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let file = OpenOptions::new().create_new(true).write(true).open(path)?;
+                entry.insert(Open {
+                    hour,
+                    encoder: zstd::Encoder::new(file, self.level)?,
+                })
+            }
+        };
+        serde_json::to_writer(&mut open.encoder, recorded)?;
+        open.encoder.write_all(b"\n")
+    }
+
+    /// Flushes what's buffered and finishes the files of hours before `now`.
+    pub fn rotate(&mut self, now: u128) -> io::Result<()> {
+        let hour = now / HOUR;
+        let ended: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(_, open)| open.hour < hour)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in ended {
+            self.open.remove(&key).unwrap().encoder.finish()?;
+        }
+        for open in self.open.values_mut() {
+            open.encoder.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes every file.
+    pub fn finish(self) -> io::Result<()> {
+        for (_, open) in self.open {
+            open.encoder.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// `<dir>/<venue>/<instrument>/<day>/<hour>.ndjson.zst` in UTC, later parts of the hour
+/// `<hour>.<part>.ndjson.zst`.
+pub fn path(dir: &Path, venue: &str, instrument: &Instrument, hour: u128, part: u32) -> PathBuf {
+    let start = Utc
+        .timestamp_millis_opt((hour * HOUR) as i64)
+        .single()
+        .unwrap_or_default();
+    let instrument: String = instrument
+        .to_string()
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    dir.join(venue)
+        .join(instrument)
+        .join(start.format("%Y-%m-%d").to_string())
+        .join(match part {
+            0 => format!("{}.ndjson.zst", start.format("%H")),
+            part => format!("{}.{}.ndjson.zst", start.format("%H"), part),
+        })
+}
+
+/// Sorts a recording's files by time: directory, hour, then part.
+pub fn file_order(path: &Path) -> (PathBuf, String, u32) {
+    let name = path
+        .file_name()
+        .map(|name| {
+            name.to_string_lossy()
+                .trim_end_matches(".ndjson.zst")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let (hour, part) = match name.split_once('.') {
+        Some((hour, part)) => (hour.to_string(), part.parse().unwrap_or(u32::MAX)),
+        None => (name, 0),
+    };
+    (
+        path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        hour,
+        part,
+    )
+}
+
+/// Reads a recording back, line by line. A file the recorder never finished reads up to the
+/// last line it flushed.
+#[cfg(test)]
+pub fn read(path: &Path) -> io::Result<Vec<Recorded>> {
+    use std::io::{BufRead, BufReader};
+
+    let mut records = vec![];
+    for line in BufReader::new(zstd::Decoder::new(File::open(path)?)?).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if !line.is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+/// Writes what's sent on the returned sender on its own thread, flushing every second. The
+/// files are finished once every sender is dropped.
+pub fn start(mut recorder: Recorder) -> (mpsc::Sender<Recorded>, thread::JoinHandle<()>) {
+    let (sender, messages) = mpsc::channel::<Recorded>();
+    let writer = thread::spawn(move || {
+        let mut rotated = Instant::now();
+        loop {
+            let mut written = match messages.recv_timeout(Duration::from_secs(1)) {
+                Ok(recorded) => recorder.write(&recorded),
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            if rotated.elapsed() >= Duration::from_secs(1) {
+                rotated = Instant::now();
+                written = written.and(recorder.rotate(util::millseconds().unwrap_or_default()));
+            }
+            if let Err(e) = written {
+                println!("recorder: {}", e);
+            }
+        }
+        if let Err(e) = recorder.finish() {
+            println!("recorder: {}", e);
+        }
+    });
+    (sender, writer)
+}
+
+/// Records the normalized trades and books off the event stream, stamped with the time they came
+/// off the socket.
+pub async fn run(sender: mpsc::Sender<Recorded>, mut events: broadcast::Receiver<ExchangeEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(ExchangeEvent::Trade(trade)) => {
+                let recorded = Recorded::new(
+                    &trade.exchange,
+                    trade.instrument.clone(),
+                    "trade",
+                    trade.received,
+                    Some(trade.timestamp),
+                )
+                .normalized(&trade);
+                if sender.send(recorded).is_err() {
+                    break;
+                }
+            }
+            Ok(ExchangeEvent::Book(book)) => {
+                let recorded = Recorded::new(
+                    &book.exchange,
+                    book.instrument.clone(),
+                    "book",
+                    book.received,
+                    Some(book.message.timestamp()),
+                )
+                .normalized(&book.message);
+                if sender.send(recorded).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => println!("recorder: missed {} events", missed),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(instrument: Instrument, received: u128, raw: &str) -> Recorded {
+        Recorded {
+            venue: "bybit".to_string(),
+            instrument,
+            channel: "book".to_string(),
+            received,
+            exchange: Some(received - 5),
+            raw: Some(raw.to_string()),
+            normalized: None,
+        }
+    }
+
+    #[test]
+    fn partitioned_by_hour_and_part() {
+        let dir =
+            std::env::temp_dir().join(format!("decay-recorder-{}", util::millseconds().unwrap()));
+        let btc = Instrument::perpetual("btc", "usdt");
+        let eth = Instrument::perpetual("eth", "usdt");
+        // 2023-03-31 14:00 UTC
+        let at = 1_680_271_200_000;
+
+        let mut recorder = Recorder::new(&dir, 3);
+        recorder.write(&recorded(btc.clone(), at, "a")).unwrap();
+        recorder.write(&recorded(eth.clone(), at + 1, "b")).unwrap();
+        recorder.write(&recorded(btc.clone(), at + 2, "c")).unwrap();
+        recorder
+            .write(&recorded(btc.clone(), at + HOUR, "d"))
+            .unwrap();
+        recorder.finish().unwrap();
+        // a restart within the same hour
+        let mut recorder = Recorder::new(&dir, 3);
+        recorder.write(&recorded(btc.clone(), at + 3, "e")).unwrap();
+        recorder.finish().unwrap();
+
+        let raws = |path: &Path| -> Vec<String> {
+            read(path)
+                .unwrap()
+                .into_iter()
+                .filter_map(|r| r.raw)
+                .collect()
+        };
+        let first = path(&dir, "bybit", &btc, at / HOUR, 0);
+        assert!(first.ends_with("bybit/BTC_USDT_perp/2023-03-31/14.ndjson.zst"));
+        assert_eq!(raws(&first), vec!["a", "c"]);
+        let restarted = path(&dir, "bybit", &btc, at / HOUR, 1);
+        assert!(restarted.ends_with("2023-03-31/14.1.ndjson.zst"));
+        assert_eq!(raws(&restarted), vec!["e"]);
+        assert_eq!(
+            raws(&path(&dir, "bybit", &btc, at / HOUR + 1, 0)),
+            vec!["d"]
+        );
+        let eth = read(&path(&dir, "bybit", &eth, at / HOUR, 0)).unwrap();
+        assert_eq!(eth, vec![recorded(eth[0].instrument.clone(), at + 1, "b")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_crash_leaves_what_was_flushed() {
+        let dir = std::env::temp_dir().join(format!(
+            "decay-recorder-crash-{}",
+            util::millseconds().unwrap()
+        ));
+        let btc = Instrument::perpetual("btc", "usdt");
+        let at = 1_680_271_200_000;
+
+        let mut recorder = Recorder::new(&dir, 3);
+        recorder.write(&recorded(btc.clone(), at, "a")).unwrap();
+        recorder.rotate(at).unwrap();
+        recorder
+            .write(&recorded(btc.clone(), at + 1, "lost"))
+            .unwrap();
+        // no finish(), the frame is left open
+        drop(recorder);
+        let mut recorder = Recorder::new(&dir, 3);
+        recorder.write(&recorded(btc.clone(), at + 2, "b")).unwrap();
+        recorder.finish().unwrap();
+
+        let crashed = read(&path(&dir, "bybit", &btc, at / HOUR, 0)).unwrap();
+        assert_eq!(crashed, vec![recorded(btc.clone(), at, "a")]);
+        let mut replayer = crate::market::replay::Replayer::open(&dir).unwrap();
+        let mut raws = vec![];
+        while let Some(recorded) = replayer.next().unwrap() {
+            raws.extend(recorded.raw);
+        }
+        assert_eq!(raws, vec!["a", "b"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use super::order_book::BookMessage;
+use super::recorder::{self, Recorded};
+use crate::exchanges::event::{BookUpdate, ExchangeEvent, Trade};
+
+/// How fast recorded time passes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// As it was received
+    Realtime,
+    /// This many times faster
+    Accelerated(f64),
+    /// No waiting, only for the receivers to keep up
+    Max,
+}
+
+/// `realtime`, `10x`, `max`
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" => Ok(Speed::Realtime),
+            "max" => Ok(Speed::Max),
+            _ => match s.strip_suffix('x').map(str::parse::<f64>) {
+                Some(Ok(factor)) if factor.is_finite() && factor > 0.0 => {
+                    Ok(Speed::Accelerated(factor))
+                }
+                _ => Err(format!(
+                    "{:?} is not a speed, expected realtime, max or a factor like 10x",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Realtime => write!(f, "realtime"),
+            Speed::Accelerated(factor) => write!(f, "{}x", factor),
+            Speed::Max => write!(f, "max"),
+        }
+    }
+}
+
+type Decoded = Lines<BufReader<zstd::Decoder<'static, BufReader<File>>>>;
+
+/// One venue and instrument's files, hour after hour.
+struct Partition {
+    files: VecDeque<PathBuf>,
+    lines: Option<(PathBuf, Decoded)>,
+}
+
+impl Partition {
+    fn next(&mut self) -> io::Result<Option<Recorded>> {
+        loop {
+            if let Some((path, lines)) = &mut self.lines {
+                match lines.next() {
+                    Some(Ok(line)) if line.is_empty() => continue,
+                    Some(Ok(line)) => {
+                        return serde_json::from_str(&line)
+                            .map(Some)
+                            .map_err(io::Error::from);
+                    }
+                    // a part the recorder never finished, the next part follows on
+                    Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        println!("replay: {} ends early: {}", path.display(), e);
+                        self.lines = None;
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => self.lines = None,
+                }
+            }
+            let path = match self.files.pop_front() {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            let decoder = zstd::Decoder::new(File::open(&path)?)?;
+            self.lines = Some((path, BufReader::new(decoder).lines()));
+        }
+    }
+}
+
+/// Where a partition's next record sorts: receive time, then venue, instrument and the
+/// partition's place, so two runs over the same files always interleave them the same way.
+type Key = (u128, String, String, usize);
+
+/// Reads a recorder directory back in receive order across every venue and instrument.
+pub struct Replayer {
+    partitions: Vec<Partition>,
+    heads: BinaryHeap<Reverse<Key>>,
+    pending: Vec<Option<Recorded>>,
+}
+
+impl Replayer {
+    /// Every `.ndjson.zst` under `dir`, a partition per directory holding the day directories.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut files = vec![];
+        walk(dir.as_ref(), &mut files)?;
+        // `<venue>/<instrument>/<day>/<hour>[.<part>]` sorts by time within a partition
+        files.sort_by_key(|file| recorder::file_order(file));
+        let mut partitions: Vec<(PathBuf, Partition)> = vec![];
+        for file in files {
+            let partition = file
+                .parent()
+                .and_then(Path::parent)
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            match partitions.last_mut() {
+                Some((last, p)) if *last == partition => p.files.push_back(file),
+                _ => partitions.push((
+                    partition,
+                    Partition {
+                        files: VecDeque::from([file]),
+                        lines: None,
+                    },
+                )),
+            }
+        }
+        let mut replayer = Self {
+            pending: partitions.iter().map(|_| None).collect(),
+            partitions: partitions.into_iter().map(|(_, p)| p).collect(),
+            heads: BinaryHeap::new(),
+        };
+        for i in 0..replayer.partitions.len() {
+            replayer.advance(i)?;
+        }
+        Ok(replayer)
+    }
+
+    fn advance(&mut self, i: usize) -> io::Result<()> {
+        if let Some(recorded) = self.partitions[i].next()? {
+            let key = (
+                recorded.received,
+                recorded.venue.clone(),
+                recorded.instrument.to_string(),
+                i,
+            );
+            self.heads.push(Reverse(key));
+            self.pending[i] = Some(recorded);
+        }
+        Ok(())
+    }
+
+    /// The next record of all the partitions, None once they're all read.
+    pub fn next(&mut self) -> io::Result<Option<Recorded>> {
+        let i = match self.heads.pop() {
+            Some(Reverse((_, _, _, i))) => i,
+            None => return Ok(None),
+        };
+        let recorded = self.pending[i].take();
+        self.advance(i)?;
+        Ok(recorded)
+    }
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else if path.to_string_lossy().ends_with(".ndjson.zst") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The event a live feed would have sent for `recorded`, None for what has no event (raw only
+/// messages, tickers).
+pub fn event(recorded: &Recorded) -> Option<ExchangeEvent> {
+    let normalized = recorded.normalized.clone()?;
+    match recorded.channel.as_str() {
+        "trade" => serde_json::from_value::<Trade>(normalized)
+            .ok()
+            .map(|trade| {
+                ExchangeEvent::Trade(Trade {
+                    received: recorded.received,
+                    ..trade
+                })
+            }),
+        "book" => serde_json::from_value::<BookMessage>(normalized)
+            .ok()
+            .map(|message| {
+                ExchangeEvent::Book(BookUpdate {
+                    exchange: recorded.venue.clone(),
+                    instrument: recorded.instrument.clone(),
+                    message,
+                    received: recorded.received,
+                })
+            }),
+        _ => None,
+    }
+}
+
+/// Sends the replayed events on `sender` at `speed`, paced by the receive times. Returns how many
+/// were sent. At `Max` it waits for the slowest receiver to catch up before the channel fills, so
+/// nothing is skipped.
+pub async fn run(
+    mut replayer: Replayer,
+    speed: Speed,
+    sender: broadcast::Sender<ExchangeEvent>,
+    capacity: usize,
+) -> io::Result<usize> {
+    let started = Instant::now();
+    let mut first = None;
+    let mut sent = 0;
+    while let Some(recorded) = replayer.next()? {
+        let event = match event(&recorded) {
+            Some(event) => event,
+            None => continue,
+        };
+        let first = *first.get_or_insert(recorded.received);
+        let elapsed = Duration::from_millis(recorded.received.saturating_sub(first) as u64);
+        match speed {
+            Speed::Realtime => tokio::time::sleep_until(started + elapsed).await,
+            Speed::Accelerated(factor) => {
+                tokio::time::sleep_until(started + elapsed.div_f64(factor)).await
+            }
+            Speed::Max => {
+                while sender.len() >= capacity / 2 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+        // nobody listening is fine
+        let _ = sender.send(event);
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::instrument::Instrument;
+    use crate::exchanges::r#trait::Side;
+    use crate::exchanges::util;
+    use crate::market::recorder::Recorder;
+
+    fn trade(instrument: Instrument, timestamp: u128) -> Recorded {
+        let trade = Trade {
+            exchange: "bybit".to_string(),
+            instrument: instrument.clone(),
+            side: Side::Buy,
+            price: dec!(100),
+            qty: dec!(1),
+            timestamp,
+            received: timestamp + 10,
+        };
+        Recorded {
+            venue: "bybit".to_string(),
+            instrument,
+            channel: "trade".to_string(),
+            received: timestamp + 10,
+            exchange: Some(timestamp),
+            raw: Some("{}".to_string()),
+            normalized: None,
+        }
+        .normalized(&trade)
+    }
+
+    #[test]
+    fn speeds() {
+        assert_eq!("realtime".parse(), Ok(Speed::Realtime));
+        assert_eq!("2.5x".parse(), Ok(Speed::Accelerated(2.5)));
+        assert_eq!("max".parse(), Ok(Speed::Max));
+        assert!("0x".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+
+    #[tokio::test]
+    async fn merged_in_receive_order() {
+        let dir =
+            std::env::temp_dir().join(format!("decay-replay-{}", util::millseconds().unwrap()));
+        let btc = Instrument::perpetual("btc", "usdt");
+        let eth = Instrument::perpetual("eth", "usdt");
+        let at = 1_680_271_200_000;
+        let mut recorder = Recorder::new(&dir, 3);
+        for recorded in [
+            trade(btc.clone(), at),
+            trade(eth.clone(), at + 5),
+            trade(btc.clone(), at + 5),
+            // the next hour's file
+            trade(btc.clone(), at + 3_600_000),
+            trade(eth.clone(), at + 7),
+        ] {
+            recorder.write(&recorded).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let (sender, mut events) = broadcast::channel(4);
+        let sent = run(Replayer::open(&dir).unwrap(), Speed::Max, sender, 4);
+        let received = async {
+            let mut received = vec![];
+            while let Ok(ExchangeEvent::Trade(trade)) = events.recv().await {
+                received.push((trade.instrument.base.clone(), trade.timestamp - at));
+            }
+            received
+        };
+        let (sent, received) = tokio::join!(sent, received);
+        assert_eq!(sent.unwrap(), 5);
+        // same time, btc before eth
+        assert_eq!(
+            received,
+            vec![
+                ("BTC".to_string(), 0),
+                ("BTC".to_string(), 5),
+                ("ETH".to_string(), 5),
+                ("ETH".to_string(), 7),
+                ("BTC".to_string(), 3_600_000),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
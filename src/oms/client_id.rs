@@ -0,0 +1,97 @@
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Longest client order id every venue takes, bybit's `orderLinkId` limit.
+pub const MAX_LEN: usize = 36;
+
+/// Makes client order ids like `mm-lf3k2a9x-1-1x9zq4`: the strategy id, the ms time, a counter
+/// and a random part, the numbers in base 36. The time and the random part keep ids apart across
+/// restarts and processes trading the same account, the counter within one.
+#[derive(Debug, Default)]
+pub struct ClientIds {
+    counter: u64,
+}
+
+impl ClientIds {
+    /// `now` is the ms time of the oms' clock.
+    pub fn next(&mut self, strategy_id: &str, now: u128) -> String {
+        self.counter += 1;
+        let mut random = [0u8; 4];
+        // all zeros if the os has no randomness, the time and counter still differ
+        let _ = SystemRandom::new().fill(&mut random);
+        let suffix = format!(
+            "-{}-{}-{}",
+            base36(now),
+            base36(self.counter.into()),
+            base36(u32::from_be_bytes(random).into())
+        );
+        let prefix: String = strategy_id
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '_' {
+                true => c,
+                false => '_',
+            })
+            .take(MAX_LEN.saturating_sub(suffix.len()))
+            .collect();
+        prefix + &suffix
+    }
+}
+
+/// The (possibly shortened) strategy id an id from `ClientIds` carries.
+pub fn strategy_of(client_order_id: &str) -> Option<&str> {
+    let mut parts = client_order_id.rsplitn(4, '-');
+    let random = parts.next()?;
+    let counter = parts.next()?;
+    let time = parts.next()?;
+    let strategy = parts.next()?;
+    let numbers = [random, counter, time]
+        .iter()
+        .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_alphanumeric()));
+    Some(strategy).filter(|s| numbers && !s.is_empty())
+}
+
+fn base36(mut n: u128) -> String {
+    const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let mut out = vec![];
+    loop {
+        out.push(DIGITS[(n % 36) as usize]);
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    out.reverse();
+    String::from_utf8(out).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use crate::exchanges::util;
+
+    #[test]
+    fn unique_and_carry_the_strategy() {
+        let mut ids = ClientIds::default();
+        let now = util::millseconds().unwrap();
+        let made: HashSet<String> = (0..1000).map(|_| ids.next("mm-btc", now)).collect();
+        assert_eq!(made.len(), 1000);
+        for id in &made {
+            assert!(id.len() <= MAX_LEN, "{}", id);
+            assert_eq!(strategy_of(id), Some("mm_btc"));
+        }
+        // another process starting at the same ms
+        assert!(!made.contains(&ClientIds::default().next("mm-btc", now)));
+    }
+
+    #[test]
+    fn long_strategy_ids_are_shortened() {
+        let id = ClientIds::default().next(&"x".repeat(50), 0);
+        assert!(id.len() <= MAX_LEN);
+        assert!(strategy_of(&id).unwrap().starts_with("xxxx"));
+        assert_eq!(strategy_of("nobody's"), None);
+        assert_eq!(base36(0), "0");
+        assert_eq!(base36(36 * 36 + 35), "10z");
+    }
+}
@@ -0,0 +1,5 @@
+pub mod client_id;
+#[allow(clippy::module_inception)]
+pub mod oms;
+pub mod reconcile;
+pub mod state;
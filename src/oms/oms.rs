@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::client_id::ClientIds;
+use super::state::OrderState;
+use crate::exchanges::event::{ExchangeEvent, OrderUpdate};
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{ExchangeBalance, Order, OrderStatus, PlaceOrder, Side};
+use crate::exchanges::util::Clock;
+use crate::risk::kill_switch::{KillSwitch, Trip};
+use crate::storage::store::{Record, Store};
+
+/// One per account, shared by its instances and the task applying the private stream.
+pub type SharedOms = Arc<Mutex<Oms>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum OmsError {
+    #[error("no order {0}")]
+    UnknownOrder(String),
+    #[error("client order id {0} was used before")]
+    Duplicate(String),
+    #[error("order {client_order_id} can't go from {from:?} to {to:?}")]
+    Transition {
+        client_order_id: String,
+        from: OrderState,
+        to: OrderState,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fill {
+    pub client_order_id: String,
+    pub strategy_id: String,
+    pub instrument: Instrument,
+    pub side: Side,
+    pub qty: Decimal,
+    pub price: Decimal,
+    /// Exchange timestamp in ms
+    pub timestamp: u128,
+}
+
+/// What an order update changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Applied {
+    /// Set if the cumulative qty grew.
+    pub fill: Option<Fill>,
+    /// The update's status was one the order can't move to, its fill counts all the same.
+    pub refused: Option<OmsError>,
+}
+
+/// An order intent and everything that happened to it since.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackedOrder {
+    pub client_order_id: String,
+    /// Set once the exchange acknowledged it.
+    pub order_id: Option<String>,
+    pub strategy_id: String,
+    pub intent: PlaceOrder,
+    pub state: OrderState,
+    pub filled_qty: Decimal,
+    pub avg_fill_price: Decimal,
+    /// Why it was rejected, by the risk checks or the exchange.
+    pub reason: Option<String>,
+    /// ms timestamp of the last change
+    pub updated: u128,
+    /// Where a refused cancel goes back to.
+    #[serde(skip)]
+    cancel_from: Option<OrderState>,
+}
+
+impl TrackedOrder {
+    /// What's left to fill, 0 once the order is done.
+    pub fn working_qty(&self) -> Decimal {
+        match self.state.is_open() {
+            true => (self.intent.qty - self.filled_qty).max(Decimal::ZERO),
+            false => Decimal::ZERO,
+        }
+    }
+
+    fn transition(&mut self, to: OrderState, now: u128) -> Result<(), OmsError> {
+        if !self.state.can_become(to) {
+            return Err(OmsError::Transition {
+                client_order_id: self.client_order_id.clone(),
+                from: self.state,
+                to,
+            });
+        }
+        self.state = to;
+        self.updated = now;
+        Ok(())
+    }
+}
+
+/// Local book of every order an account's strategies sent.
+#[derive(Debug, Default)]
+pub struct Oms {
+    /// Keyed by client order id
+    orders: HashMap<String, TrackedOrder>,
+    /// Exchange order id to client order id
+    ids: HashMap<String, String>,
+    fills: Vec<Fill>,
+    /// Net position per instrument, kept up by the fills and set by the reconciler.
+    positions: HashMap<Instrument, Decimal>,
+    /// As of the last reconciliation
+    balances: HashMap<String, ExchangeBalance>,
+    client_ids: ClientIds,
+    clock: Clock,
+}
+
+impl Oms {
+    pub fn shared() -> SharedOms {
+        Self::shared_with(Clock::default())
+    }
+
+    /// Stamps orders and client ids with the time of `clock`.
+    pub fn shared_with(clock: Clock) -> SharedOms {
+        Arc::new(Mutex::new(Self {
+            clock,
+            ..Default::default()
+        }))
+    }
+
+    /// Records what a strategy wants to send before anything checks it, returns its client id.
+    /// A new one is made unless the intent brings its own, which can't have been used before.
+    pub fn record(
+        &mut self,
+        strategy_id: &str,
+        mut intent: PlaceOrder,
+    ) -> Result<String, OmsError> {
+        let client_order_id = match intent.client_order_id.take() {
+            Some(id) if self.orders.contains_key(&id) => return Err(OmsError::Duplicate(id)),
+            Some(id) => id,
+            None => self.client_ids.next(strategy_id, self.clock.now()),
+        };
+        intent.client_order_id = Some(client_order_id.clone());
+        self.orders.insert(
+            client_order_id.clone(),
+            TrackedOrder {
+                client_order_id: client_order_id.clone(),
+                order_id: None,
+                strategy_id: strategy_id.to_string(),
+                intent,
+                state: OrderState::PendingNew,
+                filled_qty: Decimal::ZERO,
+                avg_fill_price: Decimal::ZERO,
+                reason: None,
+                updated: self.clock.now(),
+                cancel_from: None,
+            },
+        );
+        Ok(client_order_id)
+    }
+
+    pub fn reject(&mut self, client_order_id: &str, reason: String) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        let order = self.order_mut(client_order_id)?;
+        order.transition(OrderState::Rejected, now)?;
+        order.reason = Some(reason);
+        Ok(())
+    }
+
+    /// Applies what `place_order` answered.
+    pub fn placed(&mut self, client_order_id: &str, placed: &Order) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        let order = self.order_mut(client_order_id)?;
+        order.transition(placed.order_status.into(), now)?;
+        order.order_id = Some(placed.order_id.clone());
+        self.ids
+            .insert(placed.order_id.clone(), client_order_id.to_string());
+        Ok(())
+    }
+
+    pub fn cancel_requested(&mut self, client_order_id: &str) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        let order = self.order_mut(client_order_id)?;
+        let from = order.state;
+        order.transition(OrderState::PendingCancel, now)?;
+        order.cancel_from = Some(from);
+        Ok(())
+    }
+
+    pub fn canceled(&mut self, client_order_id: &str) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        self.order_mut(client_order_id)?
+            .transition(OrderState::Canceled, now)
+    }
+
+    /// The cancel was refused, the order is back where it was unless an update moved it on.
+    pub fn cancel_failed(&mut self, client_order_id: &str) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        let order = self.order_mut(client_order_id)?;
+        match (order.state, order.cancel_from.take()) {
+            (OrderState::PendingCancel, Some(from)) => order.transition(from, now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies a private stream update. A fill is recorded even when the status is refused, e.g.
+    /// a late fill of an order already taken as canceled. Only an unknown order is an error.
+    pub fn apply(&mut self, update: &OrderUpdate) -> Result<Applied, OmsError> {
+        let client_order_id = update
+            .client_order_id
+            .clone()
+            .filter(|id| self.orders.contains_key(id))
+            .or_else(|| self.ids.get(&update.order_id).cloned())
+            .ok_or_else(|| OmsError::UnknownOrder(update.order_id.clone()))?;
+        let now = self.clock.now();
+        let order = self.orders.get_mut(&client_order_id).unwrap();
+
+        let refused = order
+            .transition(next_state(order.state, update.status), now)
+            .err();
+        if order.order_id.is_none() {
+            order.order_id = Some(update.order_id.clone());
+            self.ids
+                .insert(update.order_id.clone(), client_order_id.clone());
+        }
+
+        if update.filled_qty > order.filled_qty {
+            let qty = update.filled_qty - order.filled_qty;
+            let price = (update.avg_price * update.filled_qty
+                - order.avg_fill_price * order.filled_qty)
+                / qty;
+            order.filled_qty = update.filled_qty;
+            order.avg_fill_price = update.avg_price;
+            order.updated = now;
+            // a position nobody reconciled stays unknown, a fill doesn't make it known
+            if let Some(position) = self.positions.get_mut(&order.intent.instrument) {
+                match order.intent.side {
+                    Side::Buy => *position += qty,
+                    Side::Sell => *position -= qty,
+                }
+            }
+            let fill = Fill {
+                client_order_id,
+                strategy_id: order.strategy_id.clone(),
+                instrument: order.intent.instrument.clone(),
+                side: order.intent.side,
+                qty,
+                price,
+                timestamp: update.timestamp,
+            };
+            self.fills.push(fill.clone());
+            return Ok(Applied {
+                fill: Some(fill),
+                refused,
+            });
+        }
+        Ok(Applied {
+            fill: None,
+            refused,
+        })
+    }
+
+    /// Moves an order on to the status the exchange reports, returns where it was if it moved.
+    pub fn sync_status(
+        &mut self,
+        order_id: &str,
+        status: OrderStatus,
+    ) -> Result<Option<OrderState>, OmsError> {
+        let now = self.clock.now();
+        let order = self.by_order_id_mut(order_id)?;
+        let from = order.state;
+        order.transition(next_state(from, status), now)?;
+        Ok(Some(from).filter(|from| *from != order.state))
+    }
+
+    /// The exchange doesn't know the order any more.
+    pub fn gone(&mut self, order_id: &str) -> Result<(), OmsError> {
+        let now = self.clock.now();
+        let order = self.by_order_id_mut(order_id)?;
+        order.transition(OrderState::Canceled, now)?;
+        order.reason = Some("gone from the exchange".to_string());
+        Ok(())
+    }
+
+    /// Net position, long above 0. None until the reconciler set it or a fill came in.
+    pub fn position(&self, instrument: &Instrument) -> Option<Decimal> {
+        self.positions.get(instrument).copied()
+    }
+
+    pub fn set_position(&mut self, instrument: Instrument, position: Decimal) {
+        self.positions.insert(instrument, position);
+    }
+
+    #[allow(dead_code)]
+    pub fn balance(&self, coin: &str) -> Option<&ExchangeBalance> {
+        self.balances.get(coin)
+    }
+
+    pub fn set_balances(&mut self, balances: HashMap<String, ExchangeBalance>) {
+        self.balances = balances;
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = (&Instrument, &Decimal)> {
+        self.positions.iter()
+    }
+
+    pub fn balances(&self) -> impl Iterator<Item = (&String, &ExchangeBalance)> {
+        self.balances.iter()
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.orders.values()
+    }
+
+    pub fn get(&self, client_order_id: &str) -> Option<&TrackedOrder> {
+        self.orders.get(client_order_id)
+    }
+
+    pub fn by_order_id(&self, order_id: &str) -> Option<&TrackedOrder> {
+        self.ids.get(order_id).and_then(|id| self.orders.get(id))
+    }
+
+    pub fn open_orders(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.orders.values().filter(|o| o.state.is_open())
+    }
+
+    /// Unfilled qty of the open orders on one side of `instrument`.
+    #[allow(dead_code)]
+    pub fn working_qty(&self, instrument: &Instrument, side: Side) -> Decimal {
+        self.open_orders()
+            .filter(|o| o.intent.instrument == *instrument && o.intent.side == side)
+            .map(TrackedOrder::working_qty)
+            .sum()
+    }
+
+    /// Oldest first.
+    pub fn fills(&self) -> &[Fill] {
+        &self.fills
+    }
+
+    fn order_mut(&mut self, client_order_id: &str) -> Result<&mut TrackedOrder, OmsError> {
+        self.orders
+            .get_mut(client_order_id)
+            .ok_or_else(|| OmsError::UnknownOrder(client_order_id.to_string()))
+    }
+
+    fn by_order_id_mut(&mut self, order_id: &str) -> Result<&mut TrackedOrder, OmsError> {
+        let client_order_id = self
+            .ids
+            .get(order_id)
+            .ok_or_else(|| OmsError::UnknownOrder(order_id.to_string()))?;
+        self.orders
+            .get_mut(client_order_id)
+            .ok_or_else(|| OmsError::UnknownOrder(order_id.to_string()))
+    }
+}
+
+// a pending cancel stays pending until the exchange says how it ended
+fn next_state(from: OrderState, status: OrderStatus) -> OrderState {
+    match (from, OrderState::from(status)) {
+        (OrderState::PendingCancel, OrderState::New | OrderState::PartiallyFilled) => {
+            OrderState::PendingCancel
+        }
+        (_, to) => to,
+    }
+}
+
+/// Applies the order updates of `account` from the event stream until it closes, with a `store`
+/// each changed order is written and each fill committed before the next update. A fill that
+/// can't be committed trips the kill switch.
+pub async fn run(
+    oms: SharedOms,
+    account: String,
+    mut events: broadcast::Receiver<ExchangeEvent>,
+    store: Option<Store>,
+    kill_switch: Arc<KillSwitch>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(ExchangeEvent::OrderUpdate(update)) if update.exchange_account_id == account => {
+                let applied = {
+                    let mut oms = oms.lock().unwrap();
+                    oms.apply(&update)
+                        .map(|applied| (applied, oms.by_order_id(&update.order_id).cloned()))
+                };
+                let (Applied { fill, refused }, order) = match applied {
+                    Ok(applied) => applied,
+                    Err(e) => {
+                        println!("{}: order update not applied: {}", account, e);
+                        continue;
+                    }
+                };
+                if let Some(e) = refused {
+                    println!("{}: order status not applied: {}", account, e);
+                }
+                let store = match &store {
+                    Some(store) => store,
+                    None => continue,
+                };
+                if let Some(order) = order {
+                    store.write(Record::Order {
+                        exchange_account_id: account.clone(),
+                        order,
+                    });
+                }
+                // the next update waits until the fill is on disk
+                if let Some(fill) = fill {
+                    let record = Record::Fill {
+                        exchange_account_id: account.clone(),
+                        fill,
+                    };
+                    if let Err(e) = store.write_durable(record).await {
+                        println!("{}: fill not stored: {}", account, e);
+                        kill_switch.trip(Trip::Storage {
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                println!("{}: oms missed {} events", account, missed)
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::r#trait::{OrderStatus, OrderType, TimeInForce};
+
+    fn btc() -> Instrument {
+        Instrument::perpetual("btc", "usdt")
+    }
+
+    fn intent(qty: Decimal) -> PlaceOrder {
+        PlaceOrder {
+            client_order_id: None,
+            side: Side::Buy,
+            instrument: btc(),
+            order_type: OrderType::Limit,
+            qty,
+            price: Some(dec!(100)),
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        }
+    }
+
+    fn ack(order_id: &str, status: OrderStatus) -> Order {
+        Order {
+            order_id: order_id.to_string(),
+            client_order_id: None,
+            instrument: btc(),
+            side: Side::Buy,
+            order_type: OrderType::Limit,
+            price: dec!(100),
+            qty: dec!(3),
+            order_status: status,
+            filled_qty: Decimal::ZERO,
+            avg_price: Decimal::ZERO,
+        }
+    }
+
+    fn update(status: OrderStatus, filled_qty: Decimal, avg_price: Decimal) -> OrderUpdate {
+        OrderUpdate {
+            exchange_account_id: "main".to_string(),
+            order_id: "x1".to_string(),
+            client_order_id: None,
+            instrument: btc(),
+            status,
+            filled_qty,
+            avg_price,
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn fills_and_working_qty() {
+        let mut oms = Oms::default();
+        let id = oms.record("mm", intent(dec!(3))).unwrap();
+        assert_eq!(oms.get(&id).unwrap().state, OrderState::PendingNew);
+        oms.placed(&id, &ack("x1", OrderStatus::New)).unwrap();
+        oms.set_position(btc(), dec!(-1));
+        assert_eq!(oms.working_qty(&btc(), Side::Buy), dec!(3));
+
+        oms.apply(&update(OrderStatus::PartiallyFilled, dec!(1), dec!(100)))
+            .unwrap();
+        oms.apply(&update(OrderStatus::PartiallyFilled, dec!(2), dec!(101)))
+            .unwrap();
+        // a repeated update fills nothing
+        oms.apply(&update(OrderStatus::PartiallyFilled, dec!(2), dec!(101)))
+            .unwrap();
+        assert_eq!(oms.working_qty(&btc(), Side::Buy), dec!(1));
+        assert_eq!(oms.working_qty(&btc(), Side::Sell), dec!(0));
+
+        let fills: Vec<(Decimal, Decimal)> = oms.fills().iter().map(|f| (f.qty, f.price)).collect();
+        assert_eq!(fills, vec![(dec!(1), dec!(100)), (dec!(1), dec!(102))]);
+
+        oms.apply(&update(OrderStatus::Filled, dec!(3), dec!(101)))
+            .unwrap();
+        assert_eq!(oms.open_orders().count(), 0);
+        assert_eq!(oms.by_order_id("x1").unwrap().client_order_id, id);
+        assert_eq!(oms.position(&btc()), Some(dec!(2)));
+    }
+
+    #[test]
+    fn impossible_transitions() {
+        let mut oms = Oms::default();
+        let id = oms.record("mm", intent(dec!(1))).unwrap();
+        oms.placed(&id, &ack("x1", OrderStatus::New)).unwrap();
+        oms.apply(&update(OrderStatus::Filled, dec!(1), dec!(100)))
+            .unwrap();
+
+        assert_eq!(
+            oms.apply(&update(OrderStatus::New, dec!(1), dec!(100))),
+            Ok(Applied {
+                fill: None,
+                refused: Some(OmsError::Transition {
+                    client_order_id: id.clone(),
+                    from: OrderState::Filled,
+                    to: OrderState::New
+                })
+            })
+        );
+        assert!(oms.cancel_requested(&id).is_err());
+        assert_eq!(
+            oms.record(
+                "mm",
+                PlaceOrder {
+                    client_order_id: Some(id.clone()),
+                    ..intent(dec!(1))
+                }
+            ),
+            Err(OmsError::Duplicate(id.clone()))
+        );
+        assert_eq!(
+            oms.apply(&OrderUpdate {
+                order_id: "x2".to_string(),
+                ..update(OrderStatus::New, dec!(0), dec!(0))
+            }),
+            Err(OmsError::UnknownOrder("x2".to_string()))
+        );
+    }
+
+    #[test]
+    fn late_fill_of_a_canceled_order() {
+        let mut oms = Oms::default();
+        let id = oms.record("mm", intent(dec!(3))).unwrap();
+        oms.placed(&id, &ack("x1", OrderStatus::New)).unwrap();
+        oms.gone("x1").unwrap();
+
+        let applied = oms
+            .apply(&update(OrderStatus::PartiallyFilled, dec!(2), dec!(100)))
+            .unwrap();
+        assert_eq!(applied.fill.unwrap().qty, dec!(2));
+        assert!(matches!(
+            applied.refused,
+            Some(OmsError::Transition {
+                from: OrderState::Canceled,
+                ..
+            })
+        ));
+        let order = oms.get(&id).unwrap();
+        assert_eq!(order.state, OrderState::Canceled);
+        assert_eq!(
+            (order.filled_qty, order.avg_fill_price),
+            (dec!(2), dec!(100))
+        );
+        // no position was reconciled yet, it stays unknown
+        assert_eq!(oms.position(&btc()), None);
+        assert_eq!(oms.fills().len(), 1);
+
+        oms.set_position(btc(), dec!(0));
+        let applied = oms
+            .apply(&update(OrderStatus::Filled, dec!(3), dec!(100)))
+            .unwrap();
+        assert_eq!(applied.fill.unwrap().qty, dec!(1));
+        assert_eq!(oms.position(&btc()), Some(dec!(1)));
+    }
+
+    #[test]
+    fn cancels() {
+        let mut oms = Oms::default();
+        let id = oms.record("mm", intent(dec!(1))).unwrap();
+        oms.placed(&id, &ack("x1", OrderStatus::New)).unwrap();
+
+        oms.cancel_requested(&id).unwrap();
+        // still resting until the cancel is confirmed
+        oms.apply(&update(OrderStatus::New, dec!(0), dec!(0)))
+            .unwrap();
+        assert_eq!(oms.get(&id).unwrap().state, OrderState::PendingCancel);
+        assert_eq!(oms.working_qty(&btc(), Side::Buy), dec!(1));
+        oms.cancel_failed(&id).unwrap();
+        assert_eq!(oms.get(&id).unwrap().state, OrderState::New);
+
+        oms.cancel_requested(&id).unwrap();
+        oms.canceled(&id).unwrap();
+        assert_eq!(oms.working_qty(&btc(), Side::Buy), dec!(0));
+
+        let rejected = oms.record("mm", intent(dec!(1))).unwrap();
+        oms.reject(&rejected, "too big".to_string()).unwrap();
+        assert_eq!(
+            oms.get(&rejected).unwrap().reason.as_deref(),
+            Some("too big")
+        );
+    }
+}
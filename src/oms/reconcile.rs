@@ -0,0 +1,511 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use tokio::sync::broadcast;
+
+use super::client_id;
+use super::oms::{Oms, SharedOms};
+use super::state::OrderState;
+use crate::exchanges::error::Result;
+use crate::exchanges::event::{Discrepancy, ExchangeEvent, OrderUpdate};
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{ExchangeClient, Order, OrderStatus, Side};
+use crate::exchanges::util;
+use crate::settings::settings::ReconcileSettings;
+
+/// What the previous pass found. Orphans, ghosts, lost orders and positions are only acted on
+/// when a pass finds them again, so orders and fills still on their way aren't mistaken for them.
+#[derive(Default)]
+struct Suspects {
+    orphans: HashSet<String>,
+    ghosts: HashSet<String>,
+    /// Client ids of orders without an answer that the exchange didn't know
+    lost: HashSet<String>,
+    /// The exchange position that differed
+    positions: HashMap<Instrument, Decimal>,
+}
+
+/// Brings one account's oms in line with the exchange, see `reconcile`.
+pub struct Reconciler {
+    account: String,
+    client: Arc<dyn ExchangeClient>,
+    oms: SharedOms,
+    instruments: Vec<Instrument>,
+    settings: ReconcileSettings,
+    events: broadcast::Sender<ExchangeEvent>,
+    suspects: Suspects,
+    /// Orphans already raised, left alone after that
+    reported: HashSet<String>,
+}
+
+impl Reconciler {
+    pub fn new(
+        account: String,
+        client: Arc<dyn ExchangeClient>,
+        oms: SharedOms,
+        instruments: Vec<Instrument>,
+        settings: ReconcileSettings,
+        events: broadcast::Sender<ExchangeEvent>,
+    ) -> Self {
+        Self {
+            account,
+            client,
+            oms,
+            instruments,
+            settings,
+            events,
+            suspects: Suspects::default(),
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Applies what the exchange says about an order sent from here like a private stream update.
+    fn apply(&self, oms: &mut Oms, order: &Order) {
+        let update = OrderUpdate {
+            exchange_account_id: self.account.clone(),
+            order_id: order.order_id.clone(),
+            client_order_id: order.client_order_id.clone(),
+            instrument: order.instrument.clone(),
+            status: order.order_status,
+            filled_qty: order.filled_qty,
+            avg_price: order.avg_price,
+            timestamp: util::millseconds().unwrap_or_default(),
+        };
+        match oms.apply(&update) {
+            Ok(applied) => {
+                if let Some(e) = applied.refused {
+                    println!("{}: could not reconcile: {}", self.account, e);
+                }
+                if let Some(fill) = applied.fill {
+                    println!("{}: reconciled fill {:?}", self.account, fill);
+                }
+            }
+            Err(e) => println!("{}: could not reconcile: {}", self.account, e),
+        }
+    }
+
+    /// One pass over the orders, positions and balances. The oms takes the exchange's word for
+    /// everything, each discrepancy is also sent as an `ExchangeEvent::Discrepancy`.
+    pub async fn reconcile(&mut self) -> Result<Vec<Discrepancy>> {
+        let mut found = vec![];
+        let mut suspects = Suspects::default();
+        for instrument in &self.instruments {
+            let orders = self.client.get_order(instrument.clone()).await?;
+            let exchange_position: Decimal = self
+                .client
+                .get_positions(instrument.clone())
+                .await?
+                .iter()
+                .map(|p| match p.side {
+                    Side::Buy => p.size,
+                    Side::Sell => -p.size,
+                })
+                .sum();
+
+            let (orphans, unanswered) = {
+                let mut oms = self.oms.lock().unwrap();
+                let mut orphans: Vec<Order> = vec![];
+                for order in &orders {
+                    if oms.by_order_id(&order.order_id).is_some() {
+                        match oms.sync_status(&order.order_id, order.order_status) {
+                            Ok(Some(from)) => println!(
+                                "{}: reconciled {} from {:?} to {:?}",
+                                self.account, order.order_id, from, order.order_status
+                            ),
+                            Ok(None) => {}
+                            Err(e) => println!("{}: could not reconcile: {}", self.account, e),
+                        }
+                        continue;
+                    }
+                    // sent from here, the answer got lost
+                    if order
+                        .client_order_id
+                        .as_ref()
+                        .is_some_and(|id| oms.get(id).is_some())
+                    {
+                        self.apply(&mut oms, order);
+                        continue;
+                    }
+                    let open = matches!(
+                        order.order_status,
+                        OrderStatus::New | OrderStatus::PartiallyFilled
+                    );
+                    if !open || self.reported.contains(&order.order_id) {
+                        continue;
+                    }
+                    if self.suspects.orphans.contains(&order.order_id) {
+                        orphans.push(order.clone());
+                    }
+                    suspects.orphans.insert(order.order_id.clone());
+                }
+                // the answer to placing them got lost and they aren't open, or never got there
+                let unanswered: Vec<String> = oms
+                    .orders()
+                    .filter(|o| o.intent.instrument == *instrument)
+                    .filter(|o| o.state == OrderState::PendingNew && o.order_id.is_none())
+                    .map(|o| o.client_order_id.clone())
+                    .collect();
+
+                let on_exchange: HashSet<&str> =
+                    orders.iter().map(|o| o.order_id.as_str()).collect();
+                let missing: Vec<(String, String)> = oms
+                    .open_orders()
+                    .filter(|o| o.intent.instrument == *instrument)
+                    .filter_map(|o| Some((o.client_order_id.clone(), o.order_id.clone()?)))
+                    .filter(|(_, order_id)| !on_exchange.contains(order_id.as_str()))
+                    .collect();
+                for (client_order_id, order_id) in missing {
+                    if !self.suspects.ghosts.contains(&order_id) {
+                        suspects.ghosts.insert(order_id);
+                        continue;
+                    }
+                    match oms.gone(&order_id) {
+                        Ok(()) => found.push(Discrepancy::Ghost {
+                            exchange_account_id: self.account.clone(),
+                            client_order_id,
+                            order_id,
+                        }),
+                        Err(e) => println!("{}: could not reconcile: {}", self.account, e),
+                    }
+                }
+
+                match oms.position(instrument) {
+                    None => oms.set_position(instrument.clone(), exchange_position),
+                    Some(local) if local != exchange_position => {
+                        if self.suspects.positions.get(instrument) == Some(&exchange_position) {
+                            oms.set_position(instrument.clone(), exchange_position);
+                            found.push(Discrepancy::Position {
+                                exchange_account_id: self.account.clone(),
+                                instrument: instrument.clone(),
+                                local,
+                                exchange: exchange_position,
+                            });
+                        } else {
+                            suspects
+                                .positions
+                                .insert(instrument.clone(), exchange_position);
+                        }
+                    }
+                    Some(_) => {}
+                }
+                (orphans, unanswered)
+            };
+
+            for client_order_id in unanswered {
+                let order = self
+                    .client
+                    .get_order_by_client_id(instrument.clone(), client_order_id.clone())
+                    .await?;
+                let mut oms = self.oms.lock().unwrap();
+                match order {
+                    Some(order) => self.apply(&mut oms, &order),
+                    // still on its way unless the last pass didn't find it either
+                    None if !self.suspects.lost.contains(&client_order_id) => {
+                        suspects.lost.insert(client_order_id);
+                    }
+                    None => match oms
+                        .reject(&client_order_id, "never reached the exchange".to_string())
+                    {
+                        Ok(()) => found.push(Discrepancy::Lost {
+                            exchange_account_id: self.account.clone(),
+                            client_order_id,
+                        }),
+                        Err(e) => println!("{}: could not reconcile: {}", self.account, e),
+                    },
+                }
+            }
+
+            for order in orphans {
+                let canceled = match self.settings.cancel_orphans {
+                    false => false,
+                    true => match self
+                        .client
+                        .cancel_order(instrument.clone(), order.order_id.clone())
+                        .await
+                    {
+                        Ok(_) => true,
+                        Err(e) => {
+                            println!(
+                                "{}: could not cancel orphan {}: {}",
+                                self.account, order.order_id, e
+                            );
+                            // tried again next pass
+                            continue;
+                        }
+                    },
+                };
+                self.reported.insert(order.order_id.clone());
+                let strategy_id = order
+                    .client_order_id
+                    .as_deref()
+                    .and_then(client_id::strategy_of)
+                    .map(str::to_string);
+                found.push(Discrepancy::Orphan {
+                    exchange_account_id: self.account.clone(),
+                    order,
+                    strategy_id,
+                    canceled,
+                });
+            }
+        }
+
+        let balances = self.client.get_balance(None).await?.balances;
+        {
+            let mut oms = self.oms.lock().unwrap();
+            // the first pass has nothing to compare with
+            let previous: HashMap<String, Decimal> = oms
+                .balances()
+                .map(|(c, b)| (c.clone(), b.balance))
+                .collect();
+            if !previous.is_empty() {
+                let tolerance = self.settings.balance_tolerance_bps / Decimal::from(10_000);
+                let mut coins: Vec<&String> = previous.keys().chain(balances.keys()).collect();
+                coins.sort();
+                coins.dedup();
+                for coin in coins {
+                    let previous = previous.get(coin).copied().unwrap_or_default();
+                    let exchange = balances.get(coin).map(|b| b.balance).unwrap_or_default();
+                    if (exchange - previous).abs() > previous.abs() * tolerance {
+                        found.push(Discrepancy::Balance {
+                            exchange_account_id: self.account.clone(),
+                            coin: coin.clone(),
+                            previous,
+                            exchange,
+                        });
+                    }
+                }
+            }
+            oms.set_balances(balances);
+        }
+        self.suspects = suspects;
+
+        for discrepancy in &found {
+            println!("{}: {:?}", self.account, discrepancy);
+            // nobody listening is fine
+            let _ = self
+                .events
+                .send(ExchangeEvent::Discrepancy(discrepancy.clone()));
+        }
+        Ok(found)
+    }
+}
+
+/// Reconciles every `interval_ms` until aborted.
+pub async fn run(mut reconciler: Reconciler) {
+    let mut interval =
+        tokio::time::interval(Duration::from_millis(reconciler.settings.interval_ms));
+    loop {
+        interval.tick().await;
+        if let Err(e) = reconciler.reconcile().await {
+            println!("{}: could not reconcile: {}", reconciler.account, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::fake::FakeClient;
+    use crate::exchanges::r#trait::{ExchangeBalance, OrderType, PlaceOrder, TimeInForce};
+
+    fn btc() -> Instrument {
+        Instrument::perpetual("btc", "usdt")
+    }
+
+    fn intent() -> PlaceOrder {
+        PlaceOrder {
+            client_order_id: None,
+            side: Side::Buy,
+            instrument: btc(),
+            order_type: OrderType::Limit,
+            qty: dec!(1),
+            price: Some(dec!(100)),
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn orphans_ghosts_and_positions() {
+        let fake = Arc::new(FakeClient::default());
+        let oms = Oms::shared();
+        let (sender, mut events) = broadcast::channel(16);
+        let mut reconciler = Reconciler::new(
+            "main".to_string(),
+            fake.clone(),
+            oms.clone(),
+            vec![btc()],
+            ReconcileSettings {
+                cancel_orphans: true,
+                ..Default::default()
+            },
+            sender,
+        );
+
+        // known on both sides
+        let known = fake.place_order(intent()).await.unwrap();
+        let id = oms.lock().unwrap().record("mm", intent()).unwrap();
+        oms.lock().unwrap().placed(&id, &known).unwrap();
+        // only on the exchange
+        let orphan = fake.place_order(intent()).await.unwrap();
+        // only here
+        let ghost = Order {
+            order_id: "lost".to_string(),
+            ..known.clone()
+        };
+        let ghost_id = oms.lock().unwrap().record("mm", intent()).unwrap();
+        oms.lock().unwrap().placed(&ghost_id, &ghost).unwrap();
+        fake.set_position(btc(), Side::Sell, dec!(2));
+
+        // suspects only, the position is taken as it is
+        assert!(reconciler.reconcile().await.unwrap().is_empty());
+        assert_eq!(oms.lock().unwrap().position(&btc()), Some(dec!(-2)));
+
+        let found = reconciler.reconcile().await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().any(|d| matches!(
+            d,
+            Discrepancy::Orphan { order, canceled: true, .. } if order.order_id == orphan.order_id
+        )));
+        assert!(found.iter().any(|d| matches!(
+            d,
+            Discrepancy::Ghost { client_order_id, .. } if *client_order_id == ghost_id
+        )));
+        let orders = fake.orders.lock().unwrap().clone();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order_id, known.order_id);
+        assert_eq!(
+            oms.lock().unwrap().get(&ghost_id).unwrap().state,
+            OrderState::Canceled
+        );
+        assert_eq!(oms.lock().unwrap().get(&id).unwrap().state, OrderState::New);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ExchangeEvent::Discrepancy(_))
+        ));
+
+        // a missed fill shows up in the position
+        fake.set_position(btc(), Side::Sell, dec!(1));
+        assert!(reconciler.reconcile().await.unwrap().is_empty());
+        let found = reconciler.reconcile().await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Discrepancy::Position { local, exchange, .. }] if *local == dec!(-2) && *exchange == dec!(-1)
+        ));
+        assert_eq!(oms.lock().unwrap().position(&btc()), Some(dec!(-1)));
+    }
+
+    #[tokio::test]
+    async fn lost_answers_and_balances() {
+        let fake = Arc::new(FakeClient::default());
+        let oms = Oms::shared();
+        let (sender, _) = broadcast::channel(16);
+        let mut reconciler = Reconciler::new(
+            "main".to_string(),
+            fake.clone(),
+            oms.clone(),
+            vec![btc()],
+            ReconcileSettings::default(),
+            sender,
+        );
+        let balance = |balance: Decimal| {
+            let balance = ExchangeBalance {
+                balance,
+                equity: balance,
+            };
+            *fake.balances.lock().unwrap() = HashMap::from([("USDT".to_string(), balance)]);
+        };
+
+        // placed, partly filled, and the answer never came
+        let id = oms.lock().unwrap().record("mm", intent()).unwrap();
+        let mut sent = intent();
+        sent.client_order_id = Some(id.clone());
+        fake.place_order(sent).await.unwrap();
+        {
+            let mut orders = fake.orders.lock().unwrap();
+            orders[0].order_status = OrderStatus::PartiallyFilled;
+            orders[0].filled_qty = dec!(0.4);
+            orders[0].avg_price = dec!(100);
+        }
+        fake.set_position(btc(), Side::Buy, dec!(0.4));
+        balance(dec!(1000));
+
+        assert!(reconciler.reconcile().await.unwrap().is_empty());
+        {
+            let oms = oms.lock().unwrap();
+            let order = oms.get(&id).unwrap();
+            assert_eq!(order.state, OrderState::PartiallyFilled);
+            assert_eq!(order.filled_qty, dec!(0.4));
+            assert_eq!(order.order_id.as_deref(), Some("fake-1"));
+            assert_eq!(oms.position(&btc()), Some(dec!(0.4)));
+        }
+
+        // within the tolerance
+        balance(dec!(1000.05));
+        assert!(reconciler.reconcile().await.unwrap().is_empty());
+        balance(dec!(990));
+        let found = reconciler.reconcile().await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Discrepancy::Balance { coin, previous, exchange, .. }]
+                if coin == "USDT" && *previous == dec!(1000.05) && *exchange == dec!(990)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unanswered_orders_are_looked_up() {
+        let fake = Arc::new(FakeClient::default());
+        let oms = Oms::shared();
+        let (sender, _) = broadcast::channel(16);
+        let mut reconciler = Reconciler::new(
+            "main".to_string(),
+            fake.clone(),
+            oms.clone(),
+            vec![btc()],
+            ReconcileSettings::default(),
+            sender,
+        );
+
+        // the answer got lost and it filled since, so it isn't open any more
+        let filled = oms.lock().unwrap().record("mm", intent()).unwrap();
+        let mut sent = intent();
+        sent.client_order_id = Some(filled.clone());
+        *fake.lost_answers.lock().unwrap() = 1;
+        assert!(fake.place_order(sent).await.unwrap_err().outcome_unknown());
+        {
+            let mut orders = fake.orders.lock().unwrap();
+            orders[0].order_status = OrderStatus::Filled;
+            orders[0].filled_qty = dec!(1);
+            orders[0].avg_price = dec!(100);
+        }
+        // never got there
+        let lost = oms.lock().unwrap().record("mm", intent()).unwrap();
+        fake.set_position(btc(), Side::Buy, dec!(1));
+
+        assert!(reconciler.reconcile().await.unwrap().is_empty());
+        {
+            let oms = oms.lock().unwrap();
+            let order = oms.get(&filled).unwrap();
+            assert_eq!(order.state, OrderState::Filled);
+            assert_eq!(order.filled_qty, dec!(1));
+            assert_eq!(order.order_id.as_deref(), Some("fake-1"));
+            assert_eq!(oms.get(&lost).unwrap().state, OrderState::PendingNew);
+            assert_eq!(oms.open_orders().count(), 1);
+        }
+
+        let found = reconciler.reconcile().await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Discrepancy::Lost { client_order_id, .. }] if *client_order_id == lost
+        ));
+        let oms = oms.lock().unwrap();
+        assert_eq!(oms.get(&lost).unwrap().state, OrderState::Rejected);
+        assert_eq!(oms.open_orders().count(), 0);
+    }
+}
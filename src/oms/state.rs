@@ -0,0 +1,92 @@
+use serde::Serialize;
+
+use crate::exchanges::r#trait::OrderStatus;
+
+/// Where an order is from our side, the exchange's `OrderStatus` plus the requests in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OrderState {
+    /// Recorded, not acknowledged by the exchange yet.
+    PendingNew,
+    New,
+    PartiallyFilled,
+    Filled,
+    /// A cancel was sent, the order may still fill until it's confirmed.
+    PendingCancel,
+    Canceled,
+    Rejected,
+}
+
+impl OrderState {
+    pub fn is_open(self) -> bool {
+        !self.is_final()
+    }
+
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            OrderState::Filled | OrderState::Canceled | OrderState::Rejected
+        )
+    }
+
+    /// Whether an order in this state can move to `next`, staying put always can.
+    pub fn can_become(self, next: OrderState) -> bool {
+        use OrderState::*;
+        if self == next {
+            return true;
+        }
+        match self {
+            PendingNew => next != PendingNew,
+            New => matches!(next, PartiallyFilled | Filled | PendingCancel | Canceled),
+            PartiallyFilled => matches!(next, Filled | PendingCancel | Canceled),
+            // a refused cancel puts the order back where it was
+            PendingCancel => matches!(next, New | PartiallyFilled | Filled | Canceled),
+            Filled | Canceled | Rejected => false,
+        }
+    }
+}
+
+impl From<OrderStatus> for OrderState {
+    fn from(status: OrderStatus) -> Self {
+        match status {
+            OrderStatus::New => OrderState::New,
+            OrderStatus::PartiallyFilled => OrderState::PartiallyFilled,
+            OrderStatus::Filled => OrderState::Filled,
+            OrderStatus::Canceled => OrderState::Canceled,
+            OrderStatus::Rejected => OrderState::Rejected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderState::*;
+
+    #[test]
+    fn transitions() {
+        for (from, to) in [
+            (PendingNew, New),
+            (PendingNew, Filled),
+            (PendingNew, Rejected),
+            (New, PartiallyFilled),
+            (PartiallyFilled, PartiallyFilled),
+            (PartiallyFilled, Filled),
+            (New, PendingCancel),
+            (PendingCancel, Filled),
+            (PendingCancel, New),
+            (PendingCancel, Canceled),
+        ] {
+            assert!(from.can_become(to), "{:?} -> {:?}", from, to);
+        }
+        for (from, to) in [
+            (Filled, New),
+            (Filled, PartiallyFilled),
+            (Canceled, New),
+            (Rejected, New),
+            (PartiallyFilled, New),
+            (New, PendingNew),
+            (New, Rejected),
+        ] {
+            assert!(!from.can_become(to), "{:?} -> {:?}", from, to);
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Notify;
+
+use crate::exchanges::error::Result;
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::ExchangeClient;
+use crate::exchanges::util::Clock;
+use crate::executor;
+use crate::settings::settings::KillSwitchSettings;
+
+/// Why the kill switch tripped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Trip {
+    Drawdown {
+        equity: Decimal,
+        peak: Decimal,
+        max: Decimal,
+    },
+    ExchangeErrors {
+        count: u32,
+        last: String,
+    },
+    FeedLost {
+        silent_ms: u128,
+    },
+    /// A fill couldn't be written to the database.
+    Storage {
+        error: String,
+    },
+    /// Through the control api.
+    Manual,
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trip::Drawdown { equity, peak, max } => write!(
+                f,
+                "equity {} is more than {} below its peak {}",
+                equity, max, peak
+            ),
+            Trip::ExchangeErrors { count, last } => {
+                write!(f, "{} exchange errors in a row, the last: {}", count, last)
+            }
+            Trip::FeedLost { silent_ms } => write!(f, "no market data for {}ms", silent_ms),
+            Trip::Storage { error } => write!(f, "a fill could not be stored: {}", error),
+            Trip::Manual => write!(f, "tripped by an operator"),
+        }
+    }
+}
+
+/// What `GET /kill-switch` shows.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub tripped: Option<Trip>,
+    /// ms timestamp of the trip
+    pub tripped_at: Option<u128>,
+    pub consecutive_errors: u32,
+    pub peak_equity: Option<Decimal>,
+}
+
+struct State {
+    trip: Option<(Trip, u128)>,
+    errors: u32,
+    last_event: Instant,
+    peak: Option<Decimal>,
+}
+
+/// Engine wide circuit breaker. Once tripped every account's orders are canceled (and positions
+/// closed with `flatten`) by `run`, and strategy orders are refused until it's re-armed.
+pub struct KillSwitch {
+    settings: KillSwitchSettings,
+    state: Mutex<State>,
+    tripped: Notify,
+    clock: Clock,
+}
+
+impl KillSwitch {
+    pub fn new(settings: KillSwitchSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(State {
+                trip: None,
+                errors: 0,
+                last_event: Instant::now(),
+                peak: None,
+            }),
+            tripped: Notify::new(),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Stamps trips with the time of `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn tripped(&self) -> Option<Trip> {
+        self.state
+            .lock()
+            .unwrap()
+            .trip
+            .clone()
+            .map(|(trip, _)| trip)
+    }
+
+    /// Trips unless it already is, returns whether this call tripped it.
+    pub fn trip(&self, trip: Trip) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.trip_locked(&mut state, trip)
+    }
+
+    fn trip_locked(&self, state: &mut State, trip: Trip) -> bool {
+        if state.trip.is_some() {
+            return false;
+        }
+        println!("kill switch tripped: {}", trip);
+        state.trip = Some((trip, self.clock.now()));
+        self.tripped.notify_one();
+        true
+    }
+
+    /// Counts the exchange errors of strategy orders, any success starts the count over.
+    pub fn record_result<T>(&self, result: &Result<T>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(_) => state.errors = 0,
+            Err(e) => {
+                state.errors += 1;
+                if let Some(max) = self.settings.max_consecutive_errors {
+                    if state.errors >= max {
+                        let trip = Trip::ExchangeErrors {
+                            count: state.errors,
+                            last: e.to_string(),
+                        };
+                        self.trip_locked(&mut state, trip);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Market data arrived.
+    pub fn record_event(&self) {
+        self.state.lock().unwrap().last_event = Instant::now();
+    }
+
+    /// Tracks the peak and trips once `equity` falls `max_drawdown` below it.
+    pub fn record_equity(&self, equity: Decimal) {
+        let mut state = self.state.lock().unwrap();
+        if state.trip.is_some() {
+            return;
+        }
+        let peak = *state.peak.get_or_insert(equity);
+        if equity > peak {
+            state.peak = Some(equity);
+            return;
+        }
+        if let Some(max) = self.settings.max_drawdown {
+            if peak - equity >= max {
+                let trip = Trip::Drawdown { equity, peak, max };
+                self.trip_locked(&mut state, trip);
+            }
+        }
+    }
+
+    fn check_feed(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let timeout = match self.settings.feed_timeout_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => return,
+        };
+        let silent = now.saturating_duration_since(state.last_event);
+        if silent > timeout {
+            let trip = Trip::FeedLost {
+                silent_ms: silent.as_millis(),
+            };
+            self.trip_locked(&mut state, trip);
+        }
+    }
+
+    /// Lets orders through again, the error count, feed timer and equity peak start over.
+    /// Returns what had tripped it.
+    pub fn rearm(&self) -> Option<Trip> {
+        let mut state = self.state.lock().unwrap();
+        state.errors = 0;
+        state.last_event = Instant::now();
+        state.peak = None;
+        let trip = state.trip.take().map(|(trip, _)| trip);
+        if trip.is_some() {
+            println!("kill switch re-armed");
+        }
+        trip
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        Status {
+            tripped: state.trip.as_ref().map(|(trip, _)| trip.clone()),
+            tripped_at: state.trip.as_ref().map(|(_, at)| *at),
+            consecutive_errors: state.errors,
+            peak_equity: state.peak,
+        }
+    }
+}
+
+/// Watches the feed and the equity, and halts every account each time the switch trips.
+pub async fn run(
+    kill_switch: Arc<KillSwitch>,
+    clients: HashMap<String, Arc<dyn ExchangeClient>>,
+    instruments: HashMap<String, Vec<Instrument>>,
+    mut events: broadcast::Receiver<ExchangeEvent>,
+) {
+    let settings = kill_switch.settings.clone();
+    let mut interval = tokio::time::interval(Duration::from_millis(settings.check_interval_ms));
+    let mut feed_open = true;
+    loop {
+        tokio::select! {
+            event = events.recv(), if feed_open => match event {
+                Ok(ExchangeEvent::Discrepancy(_) | ExchangeEvent::Bar(_)) => {}
+                Ok(_) | Err(RecvError::Lagged(_)) => kill_switch.record_event(),
+                Err(RecvError::Closed) => feed_open = false,
+            },
+            _ = interval.tick() => {
+                kill_switch.check_feed(Instant::now());
+                if settings.max_drawdown.is_some() {
+                    match equity(&clients, &settings.equity_coin).await {
+                        Ok(equity) => kill_switch.record_equity(equity),
+                        Err(e) => println!("kill switch: could not get the equity: {}", e),
+                    }
+                }
+            }
+            _ = kill_switch.tripped.notified() => {
+                let reports =
+                    executor::halt(&clients, instruments.clone(), settings.flatten).await;
+                for (account, report) in reports {
+                    println!("kill switch: {} {:?}", account, report);
+                }
+            }
+        }
+    }
+}
+
+/// Summed over every account.
+async fn equity(clients: &HashMap<String, Arc<dyn ExchangeClient>>, coin: &str) -> Result<Decimal> {
+    let mut equity = Decimal::ZERO;
+    for client in clients.values() {
+        let balances = client.get_balance(Some(coin.to_string())).await?;
+        equity += balances
+            .balances
+            .get(coin)
+            .map_or(Decimal::ZERO, |b| b.equity);
+    }
+    Ok(equity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::error::ExchangeError;
+    use crate::exchanges::fake::FakeClient;
+    use crate::exchanges::r#trait::{OrderType, PlaceOrder, Side, TimeInForce};
+
+    fn kill_switch(settings: KillSwitchSettings) -> KillSwitch {
+        KillSwitch::new(settings)
+    }
+
+    #[test]
+    fn consecutive_errors() {
+        let ks = kill_switch(KillSwitchSettings {
+            max_consecutive_errors: Some(2),
+            ..Default::default()
+        });
+        let err: Result<()> = Err(ExchangeError::unknown_error("down"));
+
+        ks.record_result(&err);
+        ks.record_result(&Ok(()));
+        ks.record_result(&err);
+        assert_eq!(ks.tripped(), None);
+        ks.record_result(&err);
+        assert!(matches!(
+            ks.tripped(),
+            Some(Trip::ExchangeErrors { count: 2, .. })
+        ));
+
+        assert!(ks.rearm().is_some());
+        assert_eq!(ks.status().consecutive_errors, 0);
+        assert_eq!(ks.tripped(), None);
+    }
+
+    #[test]
+    fn drawdown_from_the_peak() {
+        let ks = kill_switch(KillSwitchSettings {
+            max_drawdown: Some(dec!(100)),
+            ..Default::default()
+        });
+        ks.record_equity(dec!(1000));
+        ks.record_equity(dec!(1200));
+        ks.record_equity(dec!(1150));
+        assert_eq!(ks.tripped(), None);
+        ks.record_equity(dec!(1100));
+        assert_eq!(
+            ks.tripped(),
+            Some(Trip::Drawdown {
+                equity: dec!(1100),
+                peak: dec!(1200),
+                max: dec!(100)
+            })
+        );
+        // the first trip sticks
+        assert!(!ks.trip(Trip::Manual));
+
+        // the peak starts over
+        ks.rearm();
+        ks.record_equity(dec!(1050));
+        assert_eq!(ks.tripped(), None);
+        assert_eq!(ks.status().peak_equity, Some(dec!(1050)));
+    }
+
+    #[test]
+    fn feed_lost() {
+        let ks = kill_switch(KillSwitchSettings {
+            feed_timeout_ms: Some(1000),
+            ..Default::default()
+        });
+        ks.check_feed(Instant::now());
+        assert_eq!(ks.tripped(), None);
+        ks.check_feed(Instant::now() + Duration::from_secs(2));
+        assert!(matches!(ks.tripped(), Some(Trip::FeedLost { .. })));
+    }
+
+    #[tokio::test]
+    async fn tripping_halts_every_account() {
+        let btc = Instrument::perpetual("btc", "usdt");
+        let fake = Arc::new(FakeClient::default());
+        fake.set_position(btc.clone(), Side::Buy, dec!(2));
+        fake.place_order(PlaceOrder {
+            client_order_id: None,
+            side: Side::Buy,
+            instrument: btc.clone(),
+            order_type: OrderType::Limit,
+            qty: dec!(1),
+            price: Some(dec!(1)),
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        })
+        .await
+        .unwrap();
+
+        let ks = Arc::new(kill_switch(KillSwitchSettings {
+            flatten: true,
+            ..Default::default()
+        }));
+        let client: Arc<dyn ExchangeClient> = fake.clone();
+        let (sender, events) = broadcast::channel(8);
+        let task = tokio::spawn(run(
+            ks.clone(),
+            HashMap::from([("main".to_string(), client)]),
+            HashMap::from([("main".to_string(), vec![btc.clone()])]),
+            events,
+        ));
+        ks.trip(Trip::Manual);
+
+        // the resting order is canceled, the long closed by a reduce-only sell
+        for _ in 0..100 {
+            let orders = fake.orders.lock().unwrap().clone();
+            if orders.len() == 1 && orders[0].side == Side::Sell {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let orders = fake.orders.lock().unwrap().clone();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].side, Side::Sell);
+        assert_eq!(orders[0].qty, dec!(2));
+        task.abort();
+        drop(sender);
+    }
+}
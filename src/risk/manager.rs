@@ -0,0 +1,488 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{ExchangeClient, OrderType, PlaceOrder, Side};
+use crate::exchanges::util::Clock;
+use crate::oms::oms::SharedOms;
+use crate::risk::kill_switch::Trip;
+use crate::settings::settings::RiskSettings;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Why an order never reached the exchange.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Rejection {
+    #[error("qty {qty} is above the max of {max} per order")]
+    OrderQty { qty: Decimal, max: Decimal },
+    #[error("notional {notional} is above the max of {max} per order")]
+    OrderNotional { notional: Decimal, max: Decimal },
+    #[error("position on {instrument} would be {position}, the max is {max}")]
+    Position {
+        instrument: Instrument,
+        position: Decimal,
+        max: Decimal,
+    },
+    #[error("account notional would be {notional}, the max is {max}")]
+    AccountNotional { notional: Decimal, max: Decimal },
+    #[error("{open} orders are open already, the max is {max}")]
+    OpenOrders { open: usize, max: usize },
+    #[error("price {price} is {bps} bps away from the mark {mark}, the max is {max} bps")]
+    PriceBand {
+        price: Decimal,
+        mark: Decimal,
+        bps: Decimal,
+        max: Decimal,
+    },
+    #[error("more than {max} orders in the last second")]
+    OrderRate { max: u32 },
+    #[error("the kill switch tripped: {0}")]
+    Halted(Trip),
+    /// The exchange or the oms couldn't tell what a check needs, orders are refused rather than
+    /// guessed.
+    #[error("could not check the order: {0}")]
+    Unavailable(String),
+}
+
+/// Pre-trade checks for one account, every order a strategy sends goes through `check`.
+pub struct RiskManager {
+    limits: RiskSettings,
+    /// What the account's strategies trade, for the account wide limits.
+    instruments: Vec<Instrument>,
+    /// ms of the orders passed within the last `RATE_WINDOW`
+    sent: Mutex<VecDeque<u128>>,
+    clock: Clock,
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskSettings, instruments: Vec<Instrument>) -> Self {
+        Self {
+            limits,
+            instruments,
+            sent: Mutex::new(VecDeque::new()),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Counts the order rate by `clock` in place of the system time.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Passes `order` if no limit is broken, `max_amount` is the sending strategy's cap per order.
+    /// Reduce-only orders skip the position limits, they can only shrink a position. Positions
+    /// and open orders are the account's `oms`, only marks are asked from the exchange. The
+    /// position limits count what's still working on the order's side as filled already. An order
+    /// counts against the rate once every other check passed.
+    pub async fn check(
+        &self,
+        client: &dyn ExchangeClient,
+        oms: &SharedOms,
+        order: &PlaceOrder,
+        max_amount: Decimal,
+    ) -> Result<(), Rejection> {
+        let max_qty = self
+            .limits
+            .max_order_qty
+            .map_or(max_amount, |max| max.min(max_amount));
+        if order.qty > max_qty {
+            return Err(Rejection::OrderQty {
+                qty: order.qty,
+                max: max_qty,
+            });
+        }
+        // the oms is never locked across an await, the order itself is recorded already
+        let (open, working, positions) = {
+            let oms = oms.lock().unwrap();
+            let others: Vec<_> = oms
+                .open_orders()
+                .filter(|o| order.client_order_id.as_ref() != Some(&o.client_order_id))
+                .collect();
+            let working: Decimal = others
+                .iter()
+                .filter(|o| o.intent.instrument == order.instrument && o.intent.side == order.side)
+                .map(|o| o.working_qty())
+                .sum();
+            let positions: HashMap<Instrument, Decimal> =
+                oms.positions().map(|(i, p)| (i.clone(), *p)).collect();
+            (others.len(), working, positions)
+        };
+        // as if everything working on this side filled
+        let added = signed(order.side, working + order.qty);
+        let position = |instrument: &Instrument| {
+            positions.get(instrument).copied().ok_or_else(|| {
+                Rejection::Unavailable(format!("no position on {} reconciled yet", instrument))
+            })
+        };
+
+        let limits = &self.limits;
+        if let Some(max) = limits.max_open_orders {
+            if open >= max {
+                return Err(Rejection::OpenOrders { open, max });
+            }
+        }
+        let needs_mark = limits.price_band_bps.is_some()
+            || limits.max_account_notional.is_some()
+            || (limits.max_order_notional.is_some() && order.price.is_none());
+        let mark = match needs_mark {
+            true => Some(mark_price(client, &order.instrument).await?),
+            false => None,
+        };
+        if let (Some(max), Some(price)) = (limits.max_order_notional, order.price.or(mark)) {
+            let notional = order.qty * price;
+            if notional > max {
+                return Err(Rejection::OrderNotional { notional, max });
+            }
+        }
+        if let (Some(max), Some(mark), OrderType::Limit, Some(price)) =
+            (limits.price_band_bps, mark, order.order_type, order.price)
+        {
+            check_band(price, mark, max)?;
+        }
+
+        if !order.reduce_only {
+            if let Some(max) = limits.max_position {
+                let position = position(&order.instrument)? + added;
+                if position.abs() > max {
+                    return Err(Rejection::Position {
+                        instrument: order.instrument.clone(),
+                        position,
+                        max,
+                    });
+                }
+            }
+            if let Some(max) = limits.max_account_notional {
+                let mut notional =
+                    (position(&order.instrument)? + added).abs() * mark.unwrap_or_default();
+                for instrument in self.instruments.iter().filter(|i| **i != order.instrument) {
+                    let position = position(instrument)?;
+                    if !position.is_zero() {
+                        notional += position.abs() * mark_price(client, instrument).await?;
+                    }
+                }
+                if notional > max {
+                    return Err(Rejection::AccountNotional { notional, max });
+                }
+            }
+        }
+
+        self.check_rate(self.clock.now())
+    }
+
+    /// Counts the order against `max_orders_per_second` unless that's used up.
+    fn check_rate(&self, now: u128) -> Result<(), Rejection> {
+        let max = match self.limits.max_orders_per_second {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut sent = self.sent.lock().unwrap();
+        while sent
+            .front()
+            .is_some_and(|t| now.saturating_sub(*t) >= RATE_WINDOW.as_millis())
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= max as usize {
+            return Err(Rejection::OrderRate { max });
+        }
+        sent.push_back(now);
+        Ok(())
+    }
+}
+
+fn check_band(price: Decimal, mark: Decimal, max: Decimal) -> Result<(), Rejection> {
+    if mark <= Decimal::ZERO {
+        return Err(Rejection::Unavailable(format!("mark price is {}", mark)));
+    }
+    let bps = ((price - mark).abs() / mark * Decimal::from(10_000)).round_dp(2);
+    match bps > max {
+        true => Err(Rejection::PriceBand {
+            price,
+            mark,
+            bps,
+            max,
+        }),
+        false => Ok(()),
+    }
+}
+
+fn signed(side: Side, qty: Decimal) -> Decimal {
+    match side {
+        Side::Buy => qty,
+        Side::Sell => -qty,
+    }
+}
+
+async fn mark_price(
+    client: &dyn ExchangeClient,
+    instrument: &Instrument,
+) -> Result<Decimal, Rejection> {
+    client
+        .get_mark_price(instrument.clone())
+        .await
+        .map_err(|e| Rejection::Unavailable(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::fake::FakeClient;
+    use crate::exchanges::r#trait::TimeInForce;
+    use crate::oms::oms::Oms;
+
+    fn btc() -> Instrument {
+        Instrument::perpetual("btc", "usdt")
+    }
+
+    fn eth() -> Instrument {
+        Instrument::perpetual("eth", "usdt")
+    }
+
+    fn order(side: Side, qty: Decimal, price: Option<Decimal>) -> PlaceOrder {
+        PlaceOrder {
+            client_order_id: None,
+            side,
+            instrument: btc(),
+            order_type: match price {
+                Some(_) => OrderType::Limit,
+                None => OrderType::Market,
+            },
+            qty,
+            price,
+            time_in_force: TimeInForce::GoodTillCancel,
+            reduce_only: false,
+            close_on_trigger: false,
+        }
+    }
+
+    fn client() -> FakeClient {
+        let client = FakeClient::default();
+        client.set_mark(btc(), dec!(100));
+        client.set_mark(eth(), dec!(10));
+        client
+    }
+
+    fn manager(limits: RiskSettings) -> RiskManager {
+        RiskManager::new(limits, vec![btc(), eth()])
+    }
+
+    fn oms() -> SharedOms {
+        let oms = Oms::shared();
+        oms.lock().unwrap().set_position(btc(), dec!(0));
+        oms.lock().unwrap().set_position(eth(), dec!(0));
+        oms
+    }
+
+    #[tokio::test]
+    async fn order_size() {
+        let (client, oms) = (client(), oms());
+        let risk = manager(RiskSettings {
+            max_order_qty: Some(dec!(2)),
+            max_order_notional: Some(dec!(150)),
+            ..Default::default()
+        });
+
+        // max_amount is tighter than max_order_qty here
+        assert_eq!(
+            risk.check(&client, &oms, &order(Side::Buy, dec!(1.5), None), dec!(1))
+                .await,
+            Err(Rejection::OrderQty {
+                qty: dec!(1.5),
+                max: dec!(1)
+            })
+        );
+        // market orders are valued at the mark
+        assert!(risk
+            .check(&client, &oms, &order(Side::Buy, dec!(1.5), None), dec!(5))
+            .await
+            .is_ok());
+        assert!(matches!(
+            risk.check(
+                &client,
+                &oms,
+                &order(Side::Buy, dec!(1.6), Some(dec!(100))),
+                dec!(5)
+            )
+            .await,
+            Err(Rejection::OrderNotional { .. })
+        ));
+        assert!(risk
+            .check(
+                &client,
+                &oms,
+                &order(Side::Buy, dec!(1), Some(dec!(100))),
+                dec!(5)
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn positions() {
+        let (client, oms) = (client(), oms());
+        oms.lock().unwrap().set_position(btc(), dec!(3));
+        oms.lock().unwrap().set_position(eth(), dec!(-10));
+        // the oms is what counts, not the exchange
+        client.set_position(btc(), Side::Sell, dec!(3));
+        let risk = manager(RiskSettings {
+            max_position: Some(dec!(4)),
+            max_account_notional: Some(dec!(550)),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            risk.check(&client, &oms, &order(Side::Buy, dec!(2), None), dec!(10))
+                .await,
+            Err(Rejection::Position { position, .. }) if position == dec!(5)
+        ));
+        // 4 * 100 + 10 * 10
+        assert!(risk
+            .check(&client, &oms, &order(Side::Buy, dec!(1), None), dec!(10))
+            .await
+            .is_ok());
+        client.set_mark(eth(), dec!(20));
+        assert!(matches!(
+            risk.check(&client, &oms, &order(Side::Buy, dec!(1), None), dec!(10))
+                .await,
+            Err(Rejection::AccountNotional { notional, .. }) if notional == dec!(600)
+        ));
+
+        // closing is always allowed
+        let mut close = order(Side::Sell, dec!(3), None);
+        close.reduce_only = true;
+        assert!(risk.check(&client, &oms, &close, dec!(10)).await.is_ok());
+        assert!(risk
+            .check(&client, &oms, &order(Side::Sell, dec!(6), None), dec!(10))
+            .await
+            .is_ok());
+
+        // nothing is guessed before the reconciler set the positions
+        assert!(matches!(
+            risk.check(
+                &client,
+                &Oms::shared(),
+                &order(Side::Buy, dec!(1), None),
+                dec!(10)
+            )
+            .await,
+            Err(Rejection::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn band_and_rate() {
+        let (client, oms) = (client(), oms());
+        let risk = manager(RiskSettings {
+            price_band_bps: Some(dec!(500)),
+            max_orders_per_second: Some(3),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            risk.check(&client, &oms, &order(Side::Buy, dec!(1), Some(dec!(94))), dec!(1))
+                .await,
+            Err(Rejection::PriceBand { bps, .. }) if bps == dec!(600)
+        ));
+        // the rejected order doesn't count against the rate
+        for _ in 0..3 {
+            let order = order(Side::Buy, dec!(1), Some(dec!(96)));
+            risk.check(&client, &oms, &order, dec!(1)).await.unwrap();
+        }
+        assert_eq!(
+            risk.check(
+                &client,
+                &oms,
+                &order(Side::Buy, dec!(1), Some(dec!(96))),
+                dec!(1)
+            )
+            .await,
+            Err(Rejection::OrderRate { max: 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn open_orders() {
+        let (client, oms) = (client(), oms());
+        let risk = manager(RiskSettings {
+            max_open_orders: Some(2),
+            ..Default::default()
+        });
+        let record = || {
+            let mut order = order(Side::Buy, dec!(1), Some(dec!(96)));
+            let id = oms.lock().unwrap().record("s", order.clone()).unwrap();
+            order.client_order_id = Some(id);
+            order
+        };
+        let (first, second) = (record(), record());
+        // the order checked is recorded already and doesn't count itself
+        assert!(risk.check(&client, &oms, &second, dec!(1)).await.is_ok());
+        let third = record();
+        assert_eq!(
+            risk.check(&client, &oms, &third, dec!(1)).await,
+            Err(Rejection::OpenOrders { open: 2, max: 2 })
+        );
+        oms.lock()
+            .unwrap()
+            .reject(first.client_order_id.as_ref().unwrap(), "no".to_string())
+            .unwrap();
+        assert!(risk.check(&client, &oms, &third, dec!(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refused_without_a_mark() {
+        let client = FakeClient::default();
+        let risk = manager(RiskSettings {
+            price_band_bps: Some(dec!(500)),
+            ..Default::default()
+        });
+        assert!(matches!(
+            risk.check(
+                &client,
+                &oms(),
+                &order(Side::Buy, dec!(1), Some(dec!(1))),
+                dec!(1)
+            )
+            .await,
+            Err(Rejection::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn working_orders_count_toward_the_position() {
+        let (client, oms) = (client(), oms());
+        let risk = manager(RiskSettings {
+            max_position: Some(dec!(3)),
+            ..Default::default()
+        });
+        let record = |side: Side, qty: Decimal| {
+            let mut order = order(side, qty, Some(dec!(100)));
+            let id = oms.lock().unwrap().record("s", order.clone()).unwrap();
+            order.client_order_id = Some(id);
+            order
+        };
+
+        let first = record(Side::Buy, dec!(2));
+        assert!(risk.check(&client, &oms, &first, dec!(10)).await.is_ok());
+        // each under the limit, together over it
+        let second = record(Side::Buy, dec!(2));
+        assert!(matches!(
+            risk.check(&client, &oms, &second, dec!(10)).await,
+            Err(Rejection::Position { position, .. }) if position == dec!(4)
+        ));
+        oms.lock()
+            .unwrap()
+            .reject(second.client_order_id.as_ref().unwrap(), String::new())
+            .unwrap();
+        // the other side doesn't add up
+        let sell = record(Side::Sell, dec!(3));
+        assert!(risk.check(&client, &oms, &sell, dec!(10)).await.is_ok());
+    }
+}
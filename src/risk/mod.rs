@@ -0,0 +1,2 @@
+pub mod kill_switch;
+pub mod manager;
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::num::NonZeroU32;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::secret::REDACTED;
+
+/// First field of every encrypted file, also authenticated along with the contents.
+pub const FORMAT: &str = "decay-credentials-v1";
+/// PBKDF2-HMAC-SHA256 rounds for new files, existing ones keep the count they were written with.
+pub const ITERATIONS: u32 = 600_000;
+/// Env var (after the settings prefix) holding the passphrase, `DECAY_CREDENTIALS_PASSPHRASE`.
+pub const PASSPHRASE_VAR: &str = "CREDENTIALS_PASSPHRASE";
+
+const KDF: &str = "pbkdf2-sha256";
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum EncryptedError {
+    #[error("Not an encrypted credentials file: {0}")]
+    Format(String),
+    #[error("Could not decrypt the credentials, wrong passphrase or a damaged file")]
+    Decrypt,
+    #[error("Could not encrypt the credentials")]
+    Encrypt,
+    #[error("{0}")]
+    Passphrase(String),
+}
+
+/// Unlocks an encrypted credentials file, wiped on drop and never printed.
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl From<String> for Passphrase {
+    fn from(value: String) -> Self {
+        Self(Zeroizing::new(value))
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(value: &str) -> Self {
+        Self::from(value.to_string())
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase({})", REDACTED)
+    }
+}
+
+/// What's written to disk, json so it survives being copied around as text.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    format: String,
+    kdf: String,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Whether `contents` is a file written by `encrypt`, plain toml never parses as one.
+pub fn is_encrypted(contents: &str) -> bool {
+    serde_json::from_str::<Envelope>(contents).is_ok_and(|e| e.format == FORMAT)
+}
+
+/// AES-256-GCM with a key derived from `passphrase`, a fresh salt and nonce every time.
+pub fn encrypt(plaintext: &str, passphrase: &Passphrase) -> Result<String, EncryptedError> {
+    encrypt_with(plaintext, passphrase, ITERATIONS)
+}
+
+pub fn encrypt_with(
+    plaintext: &str,
+    passphrase: &Passphrase,
+    iterations: u32,
+) -> Result<String, EncryptedError> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| EncryptedError::Encrypt)?;
+    rng.fill(&mut nonce).map_err(|_| EncryptedError::Encrypt)?;
+
+    let key = derive_key(passphrase, &salt, iterations)?;
+    let mut in_out = plaintext.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(FORMAT.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| EncryptedError::Encrypt)?;
+
+    let envelope = Envelope {
+        format: FORMAT.to_string(),
+        kdf: KDF.to_string(),
+        iterations,
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(in_out),
+    };
+    serde_json::to_string_pretty(&envelope).map_err(|_| EncryptedError::Encrypt)
+}
+
+pub fn decrypt(contents: &str, passphrase: &Passphrase) -> Result<String, EncryptedError> {
+    let envelope: Envelope =
+        serde_json::from_str(contents).map_err(|e| EncryptedError::Format(e.to_string()))?;
+    if envelope.format != FORMAT || envelope.kdf != KDF {
+        return Err(EncryptedError::Format(format!(
+            "{} / {} is not supported",
+            envelope.format, envelope.kdf
+        )));
+    }
+    let decode = |field: &str, value: &str| {
+        base64::decode(value).map_err(|e| EncryptedError::Format(format!("{}: {}", field, e)))
+    };
+    let salt = decode("salt", &envelope.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&decode("nonce", &envelope.nonce)?)
+        .map_err(|_| EncryptedError::Format("nonce has the wrong length".to_string()))?;
+    let mut in_out = decode("ciphertext", &envelope.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, envelope.iterations)?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(FORMAT.as_bytes()), &mut in_out)
+        .map_err(|_| EncryptedError::Decrypt)?;
+    String::from_utf8(plaintext.to_vec()).map_err(|_| EncryptedError::Decrypt)
+}
+
+fn derive_key(
+    passphrase: &Passphrase,
+    salt: &[u8],
+    iterations: u32,
+) -> Result<LessSafeKey, EncryptedError> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| EncryptedError::Format("iterations can't be 0".to_string()))?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.0.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| EncryptedError::Encrypt)?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// The passphrase from `var` if set, otherwise asked for on the terminal.
+pub fn passphrase(
+    var: Option<String>,
+    var_name: &str,
+    confirm: bool,
+) -> Result<Passphrase, EncryptedError> {
+    if let Some(passphrase) = var {
+        return Ok(Passphrase::from(passphrase));
+    }
+    let prompt = |text: &str| {
+        rpassword::prompt_password(text)
+            .map(Zeroizing::new)
+            .map_err(|e| {
+                EncryptedError::Passphrase(format!(
+                    "No passphrase, set {} or run from a terminal: {}",
+                    var_name, e
+                ))
+            })
+    };
+    let passphrase = prompt("Credentials passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(EncryptedError::Passphrase(
+            "The passphrase can't be empty".to_string(),
+        ));
+    }
+    if confirm && *prompt("Repeat the passphrase: ")? != *passphrase {
+        return Err(EncryptedError::Passphrase(
+            "The passphrases don't match".to_string(),
+        ));
+    }
+    Ok(Passphrase(passphrase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: &str = "[exchanges.bybit]\napi_key = \"key\"\nsecret_key = \"s3cr\\\"et\"\n";
+
+    #[test]
+    fn never_printed() {
+        let passphrase = Passphrase::from("hunter2");
+        assert!(!format!("{:?}", passphrase).contains("hunter2"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let encrypted = encrypt_with(PLAIN, &"passphrase".into(), 1000).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(PLAIN));
+        assert!(!encrypted.contains("s3cr"));
+        assert_eq!(decrypt(&encrypted, &"passphrase".into()).unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn fresh_salt_and_nonce() {
+        assert_ne!(
+            encrypt_with(PLAIN, &"passphrase".into(), 1000).unwrap(),
+            encrypt_with(PLAIN, &"passphrase".into(), 1000).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrong_passphrase_or_tampering() {
+        let encrypted = encrypt_with(PLAIN, &"passphrase".into(), 1000).unwrap();
+        assert!(matches!(
+            decrypt(&encrypted, &"other".into()),
+            Err(EncryptedError::Decrypt)
+        ));
+
+        let mut envelope: Envelope = serde_json::from_str(&encrypted).unwrap();
+        envelope.iterations = 1001;
+        let tampered = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(
+            decrypt(&tampered, &"passphrase".into()),
+            Err(EncryptedError::Decrypt)
+        ));
+
+        assert!(matches!(
+            decrypt(PLAIN, &"passphrase".into()),
+            Err(EncryptedError::Format(_))
+        ));
+    }
+}
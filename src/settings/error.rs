@@ -0,0 +1,43 @@
+use std::fmt;
+
+use config::ConfigError;
+use thiserror::Error;
+
+use super::encrypted::EncryptedError;
+use super::sources::Layer;
+
+/// A single thing wrong with the settings, `key` is the dotted path (`exchanges.bybit.api_key`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub key: String,
+    /// The layer that set the offending value, `None` when it is missing altogether.
+    pub layer: Option<Layer>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.layer {
+            Some(layer) => write!(f, "{} ({}): {}", self.key, layer, self.message),
+            None => write!(f, "{} (not set): {}", self.key, self.message),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Could not load the settings: {0}")]
+    Load(#[from] ConfigError),
+    #[error("{0}")]
+    Encrypted(#[from] EncryptedError),
+    #[error("{} problem(s) in the settings:\n{}", .0.len(), list(.0))]
+    Invalid(Vec<Problem>),
+}
+
+fn list(problems: &[Problem]) -> String {
+    problems
+        .iter()
+        .map(|p| format!("  {}", p))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
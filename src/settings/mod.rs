@@ -1 +1,7 @@
+pub mod encrypted;
+pub mod error;
+pub mod secret;
+#[allow(clippy::module_inception)]
 pub mod settings;
+pub mod sources;
+pub mod watcher;
@@ -1,86 +1,955 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 
-use anyhow::anyhow;
-use config::{Config, File};
-use serde::Deserialize;
+use chrono::NaiveDate;
+use config::{Config, ConfigError, Map, Value};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::error::{Problem, SettingsError};
+use super::secret::Secret;
+use super::sources::{self, Layer, SettingsOptions};
+use crate::exchanges::bybit::bybit::Category;
+use crate::exchanges::instrument::{Instrument, InstrumentKind, OptionRight};
+use crate::exchanges::rest_client::{exchange_from_string, ExchangeType};
+use crate::market::bars::BarSpec;
+use crate::market::replay::Speed;
+use crate::strategy::strategy;
 
 pub static CONFIG_PATH: &str = "src/settings/config";
 pub static CREDENTIALS_PATH: &str = "src/settings/credentials";
 
+/// How requests get signed, `hmac` uses `secret_key`, the others read `private_key_path`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    #[default]
+    Hmac,
+    Rsa,
+    Ed25519,
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Credentials {
-    pub secret_key: String,
-    pub api_key: String,
+    pub key_type: KeyType,
+    /// Empty unless `key_type` is hmac.
+    pub secret_key: Secret<String>,
+    /// PEM file holding the private key for rsa/ed25519.
+    pub private_key_path: Option<String>,
+    pub api_key: Secret<String>,
     pub exchange_account_id: String,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-struct Pair {
-    base: String,
-    qoute: String,
+impl Credentials {
+    // Not deserialized directly so that every missing field gets reported. Without `keys` (paper
+    // accounts) only the exchange_account_id is needed.
+    fn from_table(
+        prefix: &str,
+        table: &Map<String, Value>,
+        keys: bool,
+        problems: &mut Problems,
+    ) -> Option<Credentials> {
+        let field = |name: &str, problems: &mut Problems| match table.get(name) {
+            Some(value) if !value.to_string().is_empty() => Some(value.to_string()),
+            _ => {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    format!("no {} entry in credentials.toml", name),
+                );
+                None
+            }
+        };
+
+        if !keys {
+            return Some(Credentials {
+                key_type: KeyType::Hmac,
+                secret_key: Secret::default(),
+                private_key_path: None,
+                api_key: Secret::default(),
+                exchange_account_id: field("exchange_account_id", problems)?,
+            });
+        }
+        let api_key = field("api_key", problems);
+        let exchange_account_id = field("exchange_account_id", problems);
+        let key_type = match table.get("key_type").map(|t| t.to_string()) {
+            None => Some(KeyType::Hmac),
+            Some(t) => match t.as_str() {
+                "hmac" => Some(KeyType::Hmac),
+                "rsa" => Some(KeyType::Rsa),
+                "ed25519" => Some(KeyType::Ed25519),
+                whatever => {
+                    problems.push(
+                        &format!("{}.key_type", prefix),
+                        format!("{} <- is not a key_type (hmac, rsa, ed25519)", whatever),
+                    );
+                    None
+                }
+            },
+        };
+        let (secret_key, private_key_path) = match key_type? {
+            KeyType::Hmac => (Secret::new(field("secret_key", problems)?), None),
+            _ => (
+                Secret::default(),
+                Some(field("private_key_path", problems)?),
+            ),
+        };
+
+        Some(Credentials {
+            key_type: key_type?,
+            secret_key,
+            private_key_path,
+            api_key: Secret::new(api_key?),
+            exchange_account_id: exchange_account_id?,
+        })
+    }
+
+    /// Key types the exchange doesn't take, bybit signs with hmac or rsa only.
+    fn validate(&self, prefix: &str, exchange: Option<&ExchangeType>, problems: &mut Problems) {
+        if matches!(exchange, Some(ExchangeType::Bybit)) && self.key_type == KeyType::Ed25519 {
+            problems.push(
+                &format!("{}.key_type", prefix),
+                "bybit only accepts hmac and rsa keys".to_string(),
+            );
+        }
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-pub struct Strategy {
-    max_amount: f64,
-    currency_pair: Pair,
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Pair {
+    pub base: String,
+    pub qoute: String,
+    /// `spot`, `perp`, `future` or `option`, perpetuals when left out.
+    #[serde(default)]
+    pub kind: InstrumentKind,
+    /// Futures and options, `2023-03-31`.
+    pub expiry: Option<NaiveDate>,
+    /// Options only.
+    pub strike: Option<Decimal>,
+    /// Options only, `C` or `P`.
+    pub right: Option<OptionRight>,
+}
+
+impl Pair {
+    /// What the pair trades, clients turn it into their own symbols.
+    pub fn instrument(&self) -> Instrument {
+        Instrument {
+            base: self.base.to_uppercase(),
+            quote: self.qoute.to_uppercase(),
+            kind: self.kind,
+            expiry: self.expiry,
+            strike: self.strike,
+            right: self.right,
+        }
+    }
+}
+
+/// What `strategies` trade, grouped by exchange_account_id.
+pub fn instruments_by_account(strategies: &[StrategySettings]) -> HashMap<String, Vec<Instrument>> {
+    let mut instruments = HashMap::<String, Vec<Instrument>>::new();
+    for strategy in strategies {
+        let account = instruments
+            .entry(strategy.exchange_account_id.clone())
+            .or_default();
+        for pair in &strategy.pairs {
+            if !account.contains(&pair.instrument()) {
+                account.push(pair.instrument());
+            }
+        }
+    }
+    instruments
+}
+
+/// Free-form parameters handed to a strategy instance.
+pub type Parameters = serde_json::Map<String, serde_json::Value>;
+
+/// One `[[strategies]]` entry, each one runs as its own instance.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StrategySettings {
+    pub id: String,
+    /// Which implementation to run, see `strategy::KINDS`.
+    pub kind: String,
+    /// Account (from credentials.toml) the instance trades on.
+    pub exchange_account_id: String,
+    pub pairs: Vec<Pair>,
+    pub max_amount: f64,
+    #[serde(default)]
+    pub parameters: Parameters,
+    /// Bars built from the pairs' trades, see `BarSpec`.
+    #[serde(default)]
+    pub bars: Vec<String>,
+}
+
+impl StrategySettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.id.trim().is_empty() {
+            problems.push(&format!("{}.id", prefix), "can't be empty".to_string());
+        }
+        if !strategy::KINDS.contains(&self.kind.as_str()) {
+            problems.push(
+                &format!("{}.kind", prefix),
+                format!("{} is not one of {:?}", self.kind, strategy::KINDS),
+            );
+        }
+        if !self.max_amount.is_finite() || self.max_amount <= 0.0 {
+            problems.push(
+                &format!("{}.max_amount", prefix),
+                format!("has to be above 0, got {}", self.max_amount),
+            );
+        }
+        if self.pairs.is_empty() {
+            problems.push(
+                &format!("{}.pairs", prefix),
+                "needs at least one pair".to_string(),
+            );
+        }
+        for (i, pair) in self.pairs.iter().enumerate() {
+            pair.validate(&format!("{}.pairs[{}]", prefix, i), problems);
+        }
+        for (i, bar) in self.bars.iter().enumerate() {
+            if let Err(e) = bar.parse::<BarSpec>() {
+                problems.push(&format!("{}.bars[{}]", prefix, i), e);
+            }
+        }
+    }
+
+    /// `bars` parsed, the ones that don't parse are left out.
+    pub fn bar_specs(&self) -> Vec<BarSpec> {
+        self.bars.iter().filter_map(|b| b.parse().ok()).collect()
+    }
+}
+
+impl Pair {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        let mut currencies = true;
+        for (name, currency) in [("base", &self.base), ("qoute", &self.qoute)] {
+            if currency.is_empty() || !currency.chars().all(|c| c.is_ascii_alphanumeric()) {
+                currencies = false;
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    format!(
+                        "{:?} is not a currency, expected something like \"usdt\"",
+                        currency
+                    ),
+                );
+            }
+        }
+        if self.base.eq_ignore_ascii_case(&self.qoute) {
+            problems.push(prefix, "base and qoute are the same currency".to_string());
+        }
+        // bad currencies are reported above already
+        if let (true, Err(e)) = (currencies, self.instrument().validate()) {
+            problems.push(prefix, e);
+        }
+    }
+}
+
+/// Which deployment of an exchange to talk to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Testnet,
+    Mainnet,
+    /// Uses `base_url` and `ws_url` as given.
+    Custom,
+}
+
+/// Connection settings for a single exchange, read from `[exchanges.<name>]` in config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ExchangeSettings {
+    /// Exchange type, defaults to the entry name so `[exchanges.bybit]` needs none.
+    /// Set it to run several accounts of one exchange, e.g. `[exchanges.bybit_sub]`.
+    pub exchange: Option<String>,
+    pub environment: Environment,
+    /// Has to be set for any client to start against mainnet.
+    pub confirm_mainnet: bool,
+    /// How long (ms) after its timestamp a signed request is still taken by the exchange, room
+    /// for clock drift and network latency. Defaults to 5000.
+    pub recv_window: u64,
+    pub request_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub pool_max_idle_per_host: usize,
+    /// TCP keep-alive interval in ms, disabled when left out.
+    pub tcp_keepalive_ms: Option<u64>,
+    /// REST url, only read when `environment = "custom"`.
+    pub base_url: Option<String>,
+    /// WebSocket url, only read when `environment = "custom"`. For bybit the base of the v5 public
+    /// streams, `<ws_url>/<category>`.
+    pub ws_url: Option<String>,
+    /// Product category for symbols that don't give it away (bybit: spot, linear, inverse, option).
+    pub default_category: Option<Category>,
+}
+
+impl ExchangeSettings {
+    /// The exchange type of the `[exchanges.<name>]` entry.
+    pub fn exchange_type<'a>(&'a self, name: &'a str) -> &'a str {
+        self.exchange.as_deref().unwrap_or(name)
+    }
+
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        for (name, value) in [
+            ("recv_window", self.recv_window),
+            ("request_timeout_ms", self.request_timeout_ms),
+            ("connect_timeout_ms", self.connect_timeout_ms),
+        ] {
+            if value == 0 {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    "has to be above 0".to_string(),
+                );
+            }
+        }
+        if self.environment == Environment::Custom {
+            for (name, url) in [("base_url", &self.base_url), ("ws_url", &self.ws_url)] {
+                if url.is_none() {
+                    problems.push(
+                        &format!("{}.{}", prefix, name),
+                        "required with environment = \"custom\"".to_string(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Default for ExchangeSettings {
+    fn default() -> Self {
+        Self {
+            exchange: None,
+            environment: Environment::Testnet,
+            confirm_mainnet: false,
+            recv_window: 5000,
+            request_timeout_ms: 10_000,
+            connect_timeout_ms: 5000,
+            pool_max_idle_per_host: 8,
+            tcp_keepalive_ms: Some(60_000),
+            base_url: None,
+            ws_url: None,
+            default_category: None,
+        }
+    }
+}
+
+/// The local control api, `[control_api]` in config.toml with the token in credentials.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ControlApiSettings {
+    pub enabled: bool,
+    /// Has to be a loopback address.
+    pub listen: String,
+    /// Every mutating request is appended here as a json line.
+    pub audit_log: String,
+    /// Bearer token for the mutating endpoints, they refuse everything while it's unset.
+    pub token: Option<Secret<String>>,
+}
+
+impl Validate for ControlApiSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        match self.listen.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() => {}
+            Ok(addr) => problems.push(
+                &format!("{}.listen", prefix),
+                format!("{} is not a localhost address", addr),
+            ),
+            Err(_) => problems.push(
+                &format!("{}.listen", prefix),
+                format!("{} <- should look like 127.0.0.1:8787", self.listen),
+            ),
+        }
+        if self.audit_log.trim().is_empty() {
+            problems.push(
+                &format!("{}.audit_log", prefix),
+                "can't be empty".to_string(),
+            );
+        }
+        if self.token.as_ref().is_some_and(Secret::is_empty) {
+            problems.push(
+                &format!("{}.token", prefix),
+                "can't be empty, leave it out to disable the mutating endpoints".to_string(),
+            );
+        }
+    }
+}
+
+impl Default for ControlApiSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "127.0.0.1:8787".to_string(),
+            audit_log: "control_audit.log".to_string(),
+            token: None,
+        }
+    }
+}
+
+/// The engine wide circuit breaker, `[kill_switch]` in config.toml. Each trigger left out is off.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct KillSwitchSettings {
+    /// How far the summed `equity_coin` equity of all accounts may fall below its peak.
+    pub max_drawdown: Option<Decimal>,
+    pub equity_coin: String,
+    /// Exchange errors in a row on strategy orders and cancels.
+    pub max_consecutive_errors: Option<u32>,
+    /// How long the market data may stay silent, ignored while nothing feeds it (no `[replay]`).
+    pub feed_timeout_ms: Option<u64>,
+    /// Close every position after canceling the orders.
+    pub flatten: bool,
+    /// How often the equity and the feed are checked.
+    pub check_interval_ms: u64,
+}
+
+impl Validate for KillSwitchSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.max_drawdown.is_some_and(|d| d <= Decimal::ZERO) {
+            problems.push(
+                &format!("{}.max_drawdown", prefix),
+                "has to be above 0".to_string(),
+            );
+        }
+        if self.equity_coin.trim().is_empty() {
+            problems.push(
+                &format!("{}.equity_coin", prefix),
+                "can't be empty".to_string(),
+            );
+        }
+        for (name, value) in [
+            (
+                "max_consecutive_errors",
+                self.max_consecutive_errors.map(u64::from),
+            ),
+            ("feed_timeout_ms", self.feed_timeout_ms),
+            ("check_interval_ms", Some(self.check_interval_ms)),
+        ] {
+            if value == Some(0) {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    "has to be above 0".to_string(),
+                );
+            }
+        }
+    }
+}
+
+impl Default for KillSwitchSettings {
+    fn default() -> Self {
+        Self {
+            max_drawdown: None,
+            equity_coin: "USDT".to_string(),
+            max_consecutive_errors: None,
+            feed_timeout_ms: None,
+            flatten: false,
+            check_interval_ms: 1000,
+        }
+    }
+}
+
+/// Periodic comparison of the local orders and positions with the exchange, `[reconcile]` in
+/// config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReconcileSettings {
+    pub interval_ms: u64,
+    /// Cancel orders found on the exchange that no strategy here sent.
+    pub cancel_orphans: bool,
+    /// A coin's balance moving more than this between two passes is reported.
+    pub balance_tolerance_bps: Decimal,
+}
+
+impl Validate for ReconcileSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.interval_ms == 0 {
+            problems.push(
+                &format!("{}.interval_ms", prefix),
+                "has to be above 0".to_string(),
+            );
+        }
+        if self.balance_tolerance_bps.is_sign_negative() {
+            problems.push(
+                &format!("{}.balance_tolerance_bps", prefix),
+                format!("can't be below 0, got {}", self.balance_tolerance_bps),
+            );
+        }
+    }
+}
+
+impl Default for ReconcileSettings {
+    fn default() -> Self {
+        Self {
+            interval_ms: 30_000,
+            cancel_orphans: false,
+            balance_tolerance_bps: Decimal::from(10),
+        }
+    }
+}
+
+/// The local database, `[storage]` in config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub enabled: bool,
+    /// SQLite file, made if it isn't there.
+    pub path: String,
+    /// How often positions, balances and changed orders are written.
+    pub snapshot_interval_ms: u64,
+}
+
+impl Validate for StorageSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.path.trim().is_empty() {
+            problems.push(&format!("{}.path", prefix), "can't be empty".to_string());
+        }
+        if self.snapshot_interval_ms == 0 {
+            problems.push(
+                &format!("{}.snapshot_interval_ms", prefix),
+                "has to be above 0".to_string(),
+            );
+        }
+    }
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "decay.sqlite".to_string(),
+            snapshot_interval_ms: 60_000,
+        }
+    }
+}
+
+/// Market data recording, `[recorder]` in config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RecorderSettings {
+    pub enabled: bool,
+    /// Files go in `<dir>/<venue>/<instrument>/<day>/<hour>.ndjson.zst`.
+    pub dir: String,
+    /// zstd level, 1 to 22
+    pub level: i32,
+}
+
+impl Validate for RecorderSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.dir.trim().is_empty() {
+            problems.push(&format!("{}.dir", prefix), "can't be empty".to_string());
+        }
+        if !(1..=22).contains(&self.level) {
+            problems.push(
+                &format!("{}.level", prefix),
+                format!("has to be from 1 to 22, got {}", self.level),
+            );
+        }
+    }
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "data".to_string(),
+            level: 3,
+        }
+    }
+}
+
+/// Playing a recording into the event stream in place of the feed, `[replay]` in config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReplaySettings {
+    pub enabled: bool,
+    /// A `[recorder]` dir
+    pub dir: String,
+    /// `realtime`, a factor like `10x` or `max`
+    pub speed: String,
+}
+
+impl Validate for ReplaySettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.dir.trim().is_empty() {
+            problems.push(&format!("{}.dir", prefix), "can't be empty".to_string());
+        }
+        if let Err(e) = self.speed.parse::<Speed>() {
+            problems.push(&format!("{}.speed", prefix), e);
+        }
+    }
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "data".to_string(),
+            speed: "realtime".to_string(),
+        }
+    }
+}
+
+/// The simulated exchange behind every `exchange = "paper"` account, `[paper]` in config.toml.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PaperSettings {
+    /// Whose trades and books the orders fill against.
+    pub venue: String,
+    pub maker_fee_bps: Decimal,
+    pub taker_fee_bps: Decimal,
+    /// From sending an order to it reaching the simulated book.
+    pub latency_ms: u64,
+    /// Starting balance per coin
+    pub balances: BTreeMap<String, Decimal>,
+}
+
+impl Validate for PaperSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        if self.venue.trim().is_empty() {
+            problems.push(&format!("{}.venue", prefix), "can't be empty".to_string());
+        }
+        if exchange_from_string(&self.venue).is_ok_and(|t| matches!(t, ExchangeType::Paper)) {
+            problems.push(
+                &format!("{}.venue", prefix),
+                "has to be a real exchange".to_string(),
+            );
+        }
+        for (name, fee) in [
+            ("maker_fee_bps", self.maker_fee_bps),
+            ("taker_fee_bps", self.taker_fee_bps),
+        ] {
+            if fee.abs() >= Decimal::from(10_000) {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    format!("has to be under 10000 either way, got {}", fee),
+                );
+            }
+        }
+        for (coin, balance) in &self.balances {
+            if balance.is_sign_negative() {
+                problems.push(
+                    &format!("{}.balances.{}", prefix, coin),
+                    format!("can't be below 0, got {}", balance),
+                );
+            }
+        }
+    }
+}
+
+impl Default for PaperSettings {
+    fn default() -> Self {
+        Self {
+            venue: "bybit".to_string(),
+            maker_fee_bps: Decimal::ONE,
+            taker_fee_bps: Decimal::from(6),
+            latency_ms: 50,
+            balances: BTreeMap::from([("USDT".to_string(), Decimal::from(10_000))]),
+        }
+    }
+}
+
+/// A run of the strategies over a recording on paper accounts, `[backtest]` in config.toml and
+/// started with `decay backtest`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BacktestSettings {
+    /// A `[recorder]` dir
+    pub dir: String,
+    /// Receive times in ms to play from and up to, the whole recording when left out
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Plays the recorded books too, without them takers fill at the last trade
+    pub books: bool,
+    /// How often the equity is sampled for the pnl curve
+    pub sample_ms: u64,
+    /// The coin the report is in, every instrument the strategies trade has to settle in it
+    pub coin: String,
+    /// The report goes to `<output>.json` and the curve to `<output>.csv`
+    pub output: String,
+}
+
+impl Validate for BacktestSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        for (name, value) in [
+            ("dir", &self.dir),
+            ("coin", &self.coin),
+            ("output", &self.output),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    "can't be empty".to_string(),
+                );
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from >= to {
+                problems.push(
+                    &format!("{}.to", prefix),
+                    format!("has to be after from ({}), got {}", from, to),
+                );
+            }
+        }
+        if self.sample_ms == 0 {
+            problems.push(
+                &format!("{}.sample_ms", prefix),
+                "has to be above 0".to_string(),
+            );
+        }
+    }
+}
+
+impl Default for BacktestSettings {
+    fn default() -> Self {
+        Self {
+            dir: "data".to_string(),
+            from: None,
+            to: None,
+            books: true,
+            sample_ms: 60_000,
+            coin: "USDT".to_string(),
+            output: "backtest".to_string(),
+        }
+    }
+}
+
+/// Pre-trade limits, `[risk]` in config.toml. Every account gets its own set of them, a limit
+/// left out isn't checked. Each strategy's `max_amount` caps its order qty on top of these.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct RiskSettings {
+    pub max_order_qty: Option<Decimal>,
+    /// qty times the limit price, or the mark for market orders.
+    pub max_order_notional: Option<Decimal>,
+    /// Net position per instrument, in contracts.
+    pub max_position: Option<Decimal>,
+    /// Sum of the position notionals at the mark over everything the account trades.
+    pub max_account_notional: Option<Decimal>,
+    pub max_open_orders: Option<usize>,
+    /// How far limit prices may be from the mark, in basis points.
+    pub price_band_bps: Option<Decimal>,
+    pub max_orders_per_second: Option<u32>,
+}
+
+impl Validate for RiskSettings {
+    fn validate(&self, prefix: &str, problems: &mut Problems) {
+        for (name, value) in [
+            ("max_order_qty", self.max_order_qty),
+            ("max_order_notional", self.max_order_notional),
+            ("max_position", self.max_position),
+            ("max_account_notional", self.max_account_notional),
+            ("max_open_orders", self.max_open_orders.map(Decimal::from)),
+            ("price_band_bps", self.price_band_bps),
+            (
+                "max_orders_per_second",
+                self.max_orders_per_second.map(Decimal::from),
+            ),
+        ] {
+            if value.is_some_and(|v| v <= Decimal::ZERO) {
+                problems.push(
+                    &format!("{}.{}", prefix, name),
+                    "has to be above 0, leave it out for no limit".to_string(),
+                );
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
-    pub strategy: Strategy,
+    pub strategies: Vec<StrategySettings>,
+    pub exchanges: HashMap<String, ExchangeSettings>,
     pub exchanges_credentials: HashMap<String, Credentials>,
+    pub control_api: ControlApiSettings,
+    pub risk: RiskSettings,
+    pub kill_switch: KillSwitchSettings,
+    pub reconcile: ReconcileSettings,
+    pub storage: StorageSettings,
+    pub recorder: RecorderSettings,
+    pub replay: ReplaySettings,
+    pub paper: PaperSettings,
+    pub backtest: BacktestSettings,
+    /// Which layer set each key, see `describe_sources`.
+    #[serde(skip)]
+    pub sources: BTreeMap<String, Layer>,
 }
 
 impl Settings {
-    pub fn new() -> Settings {
-        let s = Config::builder()
-            .add_source(File::with_name(CONFIG_PATH))
-            .add_source(File::with_name(CREDENTIALS_PATH))
-            .build()
-            .expect("config.toml and/or credentials.toml missing from settings folder.");
+    /// Loads from the default paths, the process environment and no cli overrides.
+    #[allow(dead_code)]
+    pub fn load() -> Result<Settings, SettingsError> {
+        Self::load_with(&SettingsOptions::default())
+    }
 
-        let strategy: Strategy = s.get("strategy").expect(&Self::config_err_info());
+    /// Loads and validates everything, all problems found are returned together.
+    pub fn load_with(options: &SettingsOptions) -> Result<Settings, SettingsError> {
+        let layered = sources::load(options)?;
+        let s = layered.config;
+        let mut problems = Problems::new(&layered.sources);
+
+        if s.get_table("strategy").is_ok() {
+            problems.push(
+                "strategy",
+                format!(
+                    "replaced by a list of [[strategies]]{}",
+                    Self::config_err_info()
+                ),
+            );
+        }
+        let strategies = match s.get::<Vec<StrategySettings>>("strategies") {
+            Ok(strategies) => strategies,
+            Err(ConfigError::NotFound(_)) => {
+                problems.push("strategies", format!("missing{}", Self::config_err_info()));
+                vec![]
+            }
+            Err(e) => {
+                problems.push("strategies", e.to_string());
+                vec![]
+            }
+        };
+        for (i, strategy) in strategies.iter().enumerate() {
+            strategy.validate(&format!("strategies[{}]", i), &mut problems);
+            if strategies[..i].iter().any(|other| other.id == strategy.id) {
+                problems.push(
+                    &format!("strategies[{}].id", i),
+                    format!("{} is used by more than one strategy", strategy.id),
+                );
+            }
+        }
 
-        let exchange_table = s
-            .get_table("exchanges")
-            .expect(&Self::credentials_err_info());
+        let exchange_table = match s.get_table("exchanges") {
+            Ok(table) => table,
+            Err(_) => {
+                problems.push(
+                    "exchanges",
+                    format!("missing{}", Self::credentials_err_info()),
+                );
+                Default::default()
+            }
+        };
 
+        // Credentials and connection settings share the [exchanges.<name>] tables,
+        // the unknown credential fields are simply ignored by ExchangeSettings.
+        let mut exchanges = HashMap::<String, ExchangeSettings>::new();
         let mut exchange_hmap = HashMap::<String, Credentials>::new();
 
         for (k, v) in exchange_table {
-            let table = v.into_table().unwrap();
-
-            let key = table
-                .get("api_key")
-                .ok_or_else(|| anyhow!("No api_key entry in credentials.toml"))
-                .unwrap();
-            let secret = table
-                .get("secret_key")
-                .ok_or_else(|| anyhow!("No secret_key entry in credentials.toml"))
-                .unwrap();
-            let id = table
-                .get("exchange_account_id")
-                .ok_or_else(|| anyhow!("No exchange_account_id entry in credentials.toml"))
-                .unwrap();
-
-            let cred = Credentials {
-                api_key: key.to_string(),
-                secret_key: secret.to_string(),
-                exchange_account_id: id.to_string(),
+            let prefix = format!("exchanges.{}", k);
+
+            let mut exchange_type = None;
+            match s.get::<ExchangeSettings>(&prefix) {
+                Ok(conn) => {
+                    match exchange_from_string(conn.exchange_type(&k)) {
+                        Ok(ExchangeType::Binance | ExchangeType::Ftx) => problems.push(
+                            &prefix,
+                            format!("{} is not implemented yet", conn.exchange_type(&k)),
+                        ),
+                        Ok(t) => exchange_type = Some(t),
+                        Err(_) => problems.push(
+                            &prefix,
+                            format!("{} is not a supported exchange", conn.exchange_type(&k)),
+                        ),
+                    }
+                    conn.validate(&prefix, &mut problems);
+                    exchanges.insert(k.to_string(), conn);
+                }
+                Err(e) => problems.push(&prefix, e.to_string()),
+            }
+
+            let table = match v.into_table() {
+                Ok(table) => table,
+                Err(e) => {
+                    problems.push(&prefix, e.to_string());
+                    continue;
+                }
             };
+            let keys = !matches!(exchange_type, Some(ExchangeType::Paper));
+            if let Some(cred) = Credentials::from_table(&prefix, &table, keys, &mut problems) {
+                cred.validate(&prefix, exchange_type.as_ref(), &mut problems);
+                exchange_hmap.insert(k.to_string(), cred);
+            }
+        }
 
-            exchange_hmap.insert(k.to_string(), cred);
+        let control_api: ControlApiSettings = section(&s, "control_api", &mut problems);
+        let risk: RiskSettings = section(&s, "risk", &mut problems);
+        let kill_switch: KillSwitchSettings = section(&s, "kill_switch", &mut problems);
+        let reconcile: ReconcileSettings = section(&s, "reconcile", &mut problems);
+        let storage: StorageSettings = section(&s, "storage", &mut problems);
+        let recorder: RecorderSettings = section(&s, "recorder", &mut problems);
+        let replay: ReplaySettings = section(&s, "replay", &mut problems);
+        let paper: PaperSettings = section(&s, "paper", &mut problems);
+        let backtest: BacktestSettings = section(&s, "backtest", &mut problems);
+
+        let mut accounts = HashMap::<&str, &str>::new();
+        for (name, cred) in &exchange_hmap {
+            if let Some(other) = accounts.insert(&cred.exchange_account_id, name) {
+                problems.push(
+                    &format!("exchanges.{}.exchange_account_id", name),
+                    format!(
+                        "{} is already used by exchanges.{}",
+                        cred.exchange_account_id, other
+                    ),
+                );
+            }
+        }
+        for (i, strategy) in strategies.iter().enumerate() {
+            if !accounts.contains_key(strategy.exchange_account_id.as_str()) {
+                problems.push(
+                    &format!("strategies[{}].exchange_account_id", i),
+                    format!(
+                        "no credentials for account {}",
+                        strategy.exchange_account_id
+                    ),
+                );
+            }
         }
 
-        Settings {
-            strategy: strategy,
-            exchanges_credentials: exchange_hmap,
+        if !problems.is_empty() {
+            return Err(SettingsError::Invalid(problems.list));
         }
+
+        Ok(Settings {
+            strategies,
+            exchanges,
+            exchanges_credentials: exchange_hmap,
+            control_api,
+            risk,
+            kill_switch,
+            reconcile,
+            storage,
+            recorder,
+            replay,
+            paper,
+            backtest,
+            sources: layered.sources,
+        })
+    }
+
+    /// Everything as json, every `Secret` serializes masked.
+    pub fn redacted(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Whether anything puts trades and books on the live event stream. Only a replay does, there
+    /// is no websocket feed for the exchanges yet.
+    pub fn has_market_feed(&self) -> bool {
+        self.replay.enabled
+    }
+
+    /// The `[exchanges.<name>]` entry and credentials of an account.
+    pub fn account(&self, exchange_account_id: &str) -> Option<(&str, &Credentials)> {
+        self.exchanges_credentials
+            .iter()
+            .find(|(_, cred)| cred.exchange_account_id == exchange_account_id)
+            .map(|(name, cred)| (name.as_str(), cred))
+    }
+
+    /// One `key <- layer` line per setting, values are left out since some are secrets.
+    pub fn describe_sources(&self) -> String {
+        self.sources
+            .iter()
+            .map(|(key, layer)| format!("{} <- {}", key, layer))
+            .collect::<Vec<String>>()
+            .join("\n")
     }
 
     fn credentials_err_info() -> String {
@@ -92,29 +961,421 @@ impl Settings {
                 exchange_account_id = ""
                 
                 [exchanges.ftx]
-                secret_key = ""
+                key_type = "rsa" # "hmac" (default) | "rsa" | "ed25519", bybit takes hmac or rsa
+                private_key_path = "/path/to/private_key.pem"
                 api_key = ""
                 exchange_account_id = ""
+
+            [control_api]
+                token = "" # bearer token for the mutating control api endpoints
         "#;
         format!("\n credentials.toml should look like: \n {} \n", info)
     }
 
     fn config_err_info() -> String {
         let info = r#"
-            [strategy]
-                currency_pair = { base = "ada", qoute = "usdt" }
+            [[strategies]]
+                id = "ada-usdt"
+                kind = "watch"
+                exchange_account_id = "bybit-main"
+                pairs = [{ base = "ada", qoute = "usdt" }]
+                # kind = "spot" | "perp" | "future" | "option", futures and options also take
+                # expiry = 2023-03-31, options strike = 18000 and right = "C" | "P"
                 max_amount = 0.1
+                # bars = ["1m", "1h", "tick:100", "volume:10", "dollar:100000"]
+                [strategies.parameters]
+                # anything the strategy kind reads
+
+            [exchanges]
+                [exchanges.bybit]
+                environment = "testnet" # "testnet" | "mainnet" | "custom"
+                # confirm_mainnet = true
+                recv_window = 5000 # ms a signed request stays valid for
+                request_timeout_ms = 10000
+                connect_timeout_ms = 5000
+                pool_max_idle_per_host = 8
+                tcp_keepalive_ms = 60000
+                # base_url = "https://api.bybit.com"
+                # ws_url = "wss://stream.bybit.com/v5/public"
+                default_category = "linear"
+
+            [control_api]
+                enabled = true
+                listen = "127.0.0.1:8787"
+                audit_log = "control_audit.log"
+
+            [risk]
+                # every limit is optional, max_amount always caps the order qty
+                max_order_qty = 1.0
+                max_order_notional = 10000
+                max_position = 2.0
+                max_account_notional = 50000
+                max_open_orders = 20
+                price_band_bps = 500
+                max_orders_per_second = 5
+
+            [kill_switch]
+                # every trigger is optional, re-arm with POST /kill-switch/rearm
+                max_drawdown = 500
+                equity_coin = "USDT"
+                max_consecutive_errors = 5
+                feed_timeout_ms = 30000
+                flatten = false
+                check_interval_ms = 1000
+
+            [reconcile]
+                interval_ms = 30000
+                cancel_orphans = false
+                balance_tolerance_bps = 10
+
+            [storage]
+                enabled = false
+                path = "decay.sqlite"
+                snapshot_interval_ms = 60000
+
+            [recorder]
+                enabled = false
+                dir = "data"
+                level = 3
+
+            [replay]
+                enabled = false
+                dir = "data"
+                speed = "realtime" # "realtime" | "10x" | "max"
+
+            [paper]
+                # for accounts with exchange = "paper", no keys needed
+                venue = "bybit"
+                maker_fee_bps = 1
+                taker_fee_bps = 6
+                latency_ms = 50
+                balances = { USDT = 10000 }
+
+            [backtest]
+                # decay backtest, every account trades on [paper]
+                dir = "data"
+                books = true
+                sample_ms = 60000
+                coin = "USDT"
+                output = "backtest"
         "#;
         format!("\n config.toml should look like: \n {} \n", info)
     }
 }
 
+/// A config.toml section that checks its own values.
+trait Validate {
+    fn validate(&self, prefix: &str, problems: &mut Problems);
+}
+
+/// Reads the optional section `key`, defaults when it is missing or doesn't parse.
+fn section<T: Default + DeserializeOwned + Validate>(
+    s: &Config,
+    key: &str,
+    problems: &mut Problems,
+) -> T {
+    let section = match s.get::<T>(key) {
+        Ok(section) => section,
+        Err(ConfigError::NotFound(_)) => T::default(),
+        Err(e) => {
+            problems.push(key, e.to_string());
+            T::default()
+        }
+    };
+    section.validate(key, problems);
+    section
+}
+
+/// Collects problems and looks up which layer set the key.
+struct Problems<'a> {
+    sources: &'a BTreeMap<String, Layer>,
+    list: Vec<Problem>,
+}
+
+impl<'a> Problems<'a> {
+    fn new(sources: &'a BTreeMap<String, Layer>) -> Self {
+        Self {
+            sources,
+            list: vec![],
+        }
+    }
+
+    fn push(&mut self, key: &str, message: String) {
+        let layer = self.layer(key);
+        self.list.push(Problem {
+            key: key.to_string(),
+            layer,
+            message,
+        });
+    }
+
+    // Sections have no layer of their own, use the one of their first key. Array elements
+    // (`strategies[0].max_amount`) aren't tracked one by one, those use the array's.
+    fn layer(&self, key: &str) -> Option<Layer> {
+        if let Some(layer) = self.sources.get(key) {
+            return Some(layer.clone());
+        }
+        let section = format!("{}.", key);
+        if let Some((_, layer)) = self
+            .sources
+            .range(section.clone()..)
+            .next()
+            .filter(|(k, _)| k.starts_with(&section))
+        {
+            return Some(layer.clone());
+        }
+        let parent = &key[..key.rfind(['.', '['])?];
+        if parent.contains('[') || parent.is_empty() {
+            return self.layer(parent);
+        }
+        self.sources.get(parent).cloned()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::fs;
+
+    fn options(name: &str, config: &str, credentials: &str) -> SettingsOptions {
+        let dir = std::env::temp_dir().join("decay_settings_test");
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join(format!("{}_config.toml", name));
+        let credentials_path = dir.join(format!("{}_credentials.toml", name));
+        fs::write(&config_path, config).unwrap();
+        fs::write(&credentials_path, credentials).unwrap();
+        SettingsOptions {
+            config_path: config_path.to_string_lossy().to_string(),
+            credentials_path: credentials_path.to_string_lossy().to_string(),
+            env: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn new_settings() {
-        let _settings = Settings::new();
+        let _settings = Settings::load().unwrap();
+    }
+
+    #[test]
+    fn valid_settings() {
+        let options = options(
+            "valid",
+            r#"
+            [[strategies]]
+            id = "ada"
+            kind = "watch"
+            exchange_account_id = "main"
+            pairs = [
+                { base = "ada", qoute = "usdt" },
+                { base = "btc", qoute = "usdc", kind = "option", expiry = 2022-12-30, strike = 18000, right = "C" },
+            ]
+            max_amount = 0.1
+
+            [[strategies]]
+            id = "btc-eth"
+            kind = "watch"
+            exchange_account_id = "sub"
+            pairs = [{ base = "btc", qoute = "usdt" }, { base = "eth", qoute = "usdt" }]
+            max_amount = 0.5
+            [strategies.parameters]
+            spread_bps = 5
+
+            [exchanges.bybit]
+            recv_window = 1000
+            [exchanges.bybit_sub]
+            exchange = "bybit"
+            "#,
+            r#"
+            [exchanges.bybit]
+            api_key = "key"
+            secret_key = "secret"
+            exchange_account_id = "main"
+            [exchanges.bybit_sub]
+            api_key = "sub_key"
+            secret_key = "secret"
+            exchange_account_id = "sub"
+            "#,
+        );
+        let settings = Settings::load_with(&options).unwrap();
+
+        assert_eq!(settings.exchanges["bybit"].recv_window, 1000);
+        assert_eq!(
+            settings.exchanges_credentials["bybit"].api_key,
+            Secret::from("key")
+        );
+        assert_eq!(settings.strategies.len(), 2);
+        assert_eq!(
+            settings.strategies[1].pairs[1].instrument(),
+            Instrument::perpetual("eth", "usdt")
+        );
+        assert_eq!(
+            settings.strategies[0].pairs[1].instrument().to_string(),
+            "BTC/USDC:option:2022-12-30:18000:C"
+        );
+        assert_eq!(settings.strategies[1].parameters["spread_bps"], 5);
+
+        let (name, cred) = settings.account("sub").unwrap();
+        assert_eq!(name, "bybit_sub");
+        assert_eq!(cred.api_key, Secret::from("sub_key"));
+        assert!(!format!("{:?}", settings).contains("sub_key"));
+        assert_eq!(settings.exchanges[name].exchange_type(name), "bybit");
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let options = options(
+            "invalid",
+            r#"
+            [[strategies]]
+            id = "ada"
+            kind = "watch"
+            exchange_account_id = "main"
+            pairs = [{ base = "ada", qoute = "us-dt" }, { base = "btc", qoute = "usd", kind = "future" }]
+            max_amount = -1.0
+
+            [[strategies]]
+            id = "ada"
+            kind = "moon"
+            exchange_account_id = "nobody"
+            pairs = []
+            max_amount = 1.0
+
+            [exchanges.bybit]
+            environment = "custom"
+            recv_window = 0
+            [exchanges.kraken]
+
+            [control_api]
+            listen = "0.0.0.0:8787"
+
+            [risk]
+            max_open_orders = 0
+
+            [kill_switch]
+            check_interval_ms = 0
+
+            [reconcile]
+            interval_ms = 0
+            balance_tolerance_bps = -1
+
+            [storage]
+            snapshot_interval_ms = 0
+
+            [recorder]
+            level = 0
+
+            [replay]
+            speed = "fast"
+
+            [paper]
+            venue = "paper"
+
+            [backtest]
+            sample_ms = 0
+            "#,
+            r#"
+            [exchanges.bybit]
+            api_key = "key"
+            key_type = "rsa"
+            [exchanges.kraken]
+            api_key = "key"
+            secret_key = "secret"
+            exchange_account_id = "main"
+            [exchanges.bybit_ed]
+            exchange = "bybit"
+            api_key = "key"
+            key_type = "ed25519"
+            private_key_path = "ed25519.pem"
+            exchange_account_id = "ed"
+            [exchanges.ftx]
+            api_key = "key"
+            secret_key = "secret"
+            exchange_account_id = "old"
+            "#,
+        );
+        let problems = match Settings::load_with(&options) {
+            Err(SettingsError::Invalid(problems)) => problems,
+            other => panic!("expected problems, got {:?}", other.map(|_| ())),
+        };
+        let keys: Vec<&str> = problems.iter().map(|p| p.key.as_str()).collect();
+
+        for key in [
+            "strategies[0].max_amount",
+            "strategies[0].pairs[0].qoute",
+            "strategies[0].pairs[1]",
+            "strategies[1].id",
+            "strategies[1].kind",
+            "strategies[1].pairs",
+            "strategies[1].exchange_account_id",
+            "exchanges.bybit.recv_window",
+            "exchanges.bybit.base_url",
+            "exchanges.bybit.ws_url",
+            "exchanges.bybit.exchange_account_id",
+            "exchanges.bybit.private_key_path",
+            "exchanges.kraken",
+            "exchanges.bybit_ed.key_type",
+            "control_api.listen",
+            "risk.max_open_orders",
+            "kill_switch.check_interval_ms",
+            "reconcile.interval_ms",
+            "reconcile.balance_tolerance_bps",
+            "storage.snapshot_interval_ms",
+            "recorder.level",
+            "replay.speed",
+            "paper.venue",
+            "backtest.sample_ms",
+        ] {
+            assert!(keys.contains(&key), "{} not in {:?}", key, keys);
+        }
+
+        let max_amount = problems
+            .iter()
+            .find(|p| p.key == "strategies[0].max_amount")
+            .unwrap();
+        assert_eq!(
+            max_amount.layer,
+            Some(Layer::File(options.config_path.clone()))
+        );
+        let missing = problems
+            .iter()
+            .find(|p| p.key == "exchanges.bybit.private_key_path")
+            .unwrap();
+        assert_eq!(missing.layer, None);
+        let ftx = problems.iter().find(|p| p.key == "exchanges.ftx").unwrap();
+        assert!(ftx.message.contains("not implemented"), "{}", ftx.message);
+    }
+
+    #[test]
+    fn old_strategy_section() {
+        let options = options(
+            "old",
+            "[strategy]\ncurrency_pair = { base = \"ada\", qoute = \"usdt\" }\nmax_amount = 0.1\n",
+            "",
+        );
+        match Settings::load_with(&options) {
+            Err(SettingsError::Invalid(problems)) => {
+                assert_eq!(problems[0].key, "strategy");
+                assert!(problems[0].message.starts_with("replaced by"));
+            }
+            other => panic!("expected problems, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_sections() {
+        let options = options("empty", "", "");
+        match Settings::load_with(&options) {
+            Err(SettingsError::Invalid(problems)) => {
+                let keys: Vec<&str> = problems.iter().map(|p| p.key.as_str()).collect();
+                assert_eq!(keys, vec!["strategies", "exchanges"]);
+            }
+            other => panic!("expected problems, got {:?}", other.map(|_| ())),
+        }
     }
 }
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use std::fs;
+
+use config::{Config, Environment, File, FileFormat, Map, Source, Value, ValueKind};
+use serde_json::Value as JsonValue;
+
+use super::encrypted::{self, Passphrase, PASSPHRASE_VAR};
+use super::error::SettingsError;
+use super::settings::{ExchangeSettings, CONFIG_PATH, CREDENTIALS_PATH};
+
+pub static ENV_PREFIX: &str = "DECAY";
+
+/// One layer of the settings, later layers win over earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+    Default,
+    File(String),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Default => write!(f, "default"),
+            Layer::File(path) => write!(f, "file {}", path),
+            Layer::Env(var) => write!(f, "env {}", var),
+            Layer::Cli => write!(f, "cli --set"),
+        }
+    }
+}
+
+/// Where to load the settings from, see `Cli` for the flags filling this in.
+#[derive(Debug, Clone)]
+pub struct SettingsOptions {
+    pub config_path: String,
+    pub credentials_path: String,
+    pub env_prefix: String,
+    /// Replaces the process environment, handy for tests.
+    pub env: Option<Map<String, String>>,
+    /// `key.path=value` pairs given on the command line.
+    pub overrides: Vec<(String, String)>,
+    /// For an encrypted credentials file, falls back to `DECAY_CREDENTIALS_PASSPHRASE`.
+    pub credentials_passphrase: Option<Passphrase>,
+}
+
+impl SettingsOptions {
+    /// Asks for the passphrase up front if the credentials file is encrypted and none is
+    /// set, so later reloads don't have to.
+    pub fn unlock(&mut self) -> Result<(), SettingsError> {
+        let encrypted = resolve_path(&self.credentials_path)
+            .and_then(|path| fs::read_to_string(path).ok())
+            .is_some_and(|contents| encrypted::is_encrypted(&contents));
+        if encrypted && self.credentials_passphrase.is_none() {
+            let var = env_var(&self.env_prefix, PASSPHRASE_VAR);
+            self.credentials_passphrase =
+                Some(encrypted::passphrase(self.env_value(&var), &var, false)?);
+        }
+        Ok(())
+    }
+
+    fn env_value(&self, var: &str) -> Option<String> {
+        match &self.env {
+            Some(env) => env.get(var).cloned(),
+            None => std::env::var(var).ok(),
+        }
+    }
+}
+
+impl Default for SettingsOptions {
+    fn default() -> Self {
+        Self {
+            config_path: CONFIG_PATH.to_string(),
+            credentials_path: CREDENTIALS_PATH.to_string(),
+            env_prefix: ENV_PREFIX.to_string(),
+            env: None,
+            overrides: vec![],
+            credentials_passphrase: None,
+        }
+    }
+}
+
+/// The merged config together with the layer that set each key.
+pub struct Layered {
+    pub config: Config,
+    pub sources: BTreeMap<String, Layer>,
+}
+
+/// Merges defaults < config file < credentials file < env vars < cli flags.
+pub fn load(options: &SettingsOptions) -> Result<Layered, SettingsError> {
+    let files: Vec<Box<dyn Source + Send + Sync>> = vec![
+        Box::new(File::with_name(&options.config_path)),
+        credentials_file(options)?,
+    ];
+    // the passphrase is no setting and mustn't show up as one
+    let passphrase_var = env_var(&options.env_prefix, PASSPHRASE_VAR);
+    let mut env_vars: Map<String, String> = match &options.env {
+        Some(env) => env.clone(),
+        None => std::env::vars().collect(),
+    };
+    env_vars.remove(&passphrase_var);
+    let env = Environment::with_prefix(&options.env_prefix)
+        .prefix_separator("_")
+        .separator("__")
+        .source(Some(env_vars));
+
+    let mut layers: Vec<(Layer, Vec<String>)> = vec![];
+    for (file, path) in files
+        .iter()
+        .zip([&options.config_path, &options.credentials_path])
+    {
+        layers.push((Layer::File(path.clone()), flatten(file.collect()?)));
+    }
+    layers.push((Layer::Env(String::new()), flatten(env.collect()?)));
+    layers.push((
+        Layer::Cli,
+        options.overrides.iter().map(|(k, _)| k.clone()).collect(),
+    ));
+
+    let defaults = exchange_defaults(layers.iter().flat_map(|(_, keys)| keys));
+
+    let mut builder = Config::builder();
+    for (key, value) in &defaults {
+        builder = builder.set_default(key.as_str(), value.clone())?;
+    }
+    builder = builder.add_source(files);
+    builder = builder.add_source(env);
+    for (key, value) in &options.overrides {
+        builder = builder.set_override(key.as_str(), value.as_str())?;
+    }
+
+    let mut sources: BTreeMap<String, Layer> = defaults
+        .into_iter()
+        .map(|(key, _)| (key, Layer::Default))
+        .collect();
+    for (layer, keys) in layers {
+        for key in keys {
+            let layer = match &layer {
+                Layer::Env(_) => Layer::Env(env_var(&options.env_prefix, &key)),
+                other => other.clone(),
+            };
+            sources.insert(key, layer);
+        }
+    }
+
+    Ok(Layered {
+        config: builder.build()?,
+        sources,
+    })
+}
+
+// Encrypted files are decrypted in memory and read from there
+fn credentials_file(
+    options: &SettingsOptions,
+) -> Result<Box<dyn Source + Send + Sync>, SettingsError> {
+    let contents = resolve_path(&options.credentials_path).and_then(|p| fs::read_to_string(p).ok());
+    match contents {
+        Some(contents) if encrypted::is_encrypted(&contents) => {
+            let var = env_var(&options.env_prefix, PASSPHRASE_VAR);
+            let passphrase = options
+                .credentials_passphrase
+                .clone()
+                .or_else(|| options.env_value(&var).map(Passphrase::from))
+                .ok_or_else(|| {
+                    encrypted::EncryptedError::Passphrase(format!(
+                        "{} is encrypted, set {}",
+                        options.credentials_path, var
+                    ))
+                })?;
+            let plaintext = encrypted::decrypt(&contents, &passphrase)?;
+            Ok(Box::new(File::from_str(&plaintext, FileFormat::Toml)))
+        }
+        _ => Ok(Box::new(File::with_name(&options.credentials_path))),
+    }
+}
+
+/// The file `File::with_name` reads for `path`, which may leave out the extension, `.enc` for
+/// encrypted credentials.
+pub fn resolve_path(path: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(path);
+    if path.is_file() {
+        return Some(path);
+    }
+    [
+        "toml", "json", "yaml", "yml", "ini", "ron", "json5", "toml.enc", "enc",
+    ]
+    .iter()
+    .map(|ext| path.with_extension(ext))
+    .find(|p| p.is_file())
+}
+
+/// `exchanges.bybit.api_key` is read from `DECAY_EXCHANGES__BYBIT__API_KEY`.
+pub fn env_var(prefix: &str, key: &str) -> String {
+    format!("{}_{}", prefix, key.replace('.', "__")).to_uppercase()
+}
+
+/// `key.path=value` from the command line.
+pub fn parse_override(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_lowercase(), value.to_string()))
+        }
+        _ => Err(format!("{} <- should look like key.path=value", arg)),
+    }
+}
+
+fn flatten(map: Map<String, Value>) -> Vec<String> {
+    fn walk(prefix: &str, map: Map<String, Value>, out: &mut Vec<String>) {
+        for (k, v) in map {
+            let key = match prefix {
+                "" => k,
+                p => format!("{}.{}", p, k),
+            };
+            match v.kind {
+                ValueKind::Table(table) => walk(&key, table, out),
+                _ => out.push(key),
+            }
+        }
+    }
+    let mut out = vec![];
+    walk("", map, &mut out);
+    out
+}
+
+// Connection defaults are filled in for every exchange entry that shows up in any layer
+fn exchange_defaults<'a>(keys: impl Iterator<Item = &'a String>) -> Vec<(String, ValueKind)> {
+    let names: BTreeSet<&str> = keys
+        .filter_map(|k| k.strip_prefix("exchanges."))
+        .filter_map(|k| k.split('.').next())
+        .collect();
+
+    let default = match serde_json::to_value(ExchangeSettings::default()) {
+        Ok(JsonValue::Object(map)) => map,
+        _ => return vec![],
+    };
+
+    let mut defaults = vec![];
+    for name in names {
+        for (field, value) in &default {
+            let kind = match value {
+                JsonValue::Bool(b) => ValueKind::Boolean(*b),
+                JsonValue::Number(n) => match n.as_i64() {
+                    Some(i) => ValueKind::I64(i),
+                    None => ValueKind::Float(n.as_f64().unwrap_or_default()),
+                },
+                JsonValue::String(s) => ValueKind::String(s.clone()),
+                _ => continue,
+            };
+            defaults.push((format!("exchanges.{}.{}", name, field), kind));
+        }
+    }
+    defaults
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_config(name: &str, contents: &str) -> String {
+        let dir = std::env::temp_dir().join("decay_sources_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let config_path = write_config(
+            "layers_config.toml",
+            "[exchanges.bybit]\nrecv_window = 1000\nrequest_timeout_ms = 2000\n",
+        );
+        let credentials_path = write_config(
+            "layers_credentials.toml",
+            "[exchanges.bybit]\napi_key = \"file\"\nsecret_key = \"file\"\n",
+        );
+        let mut env = Map::new();
+        env.insert(
+            "TEST_EXCHANGES__BYBIT__API_KEY".to_string(),
+            "env".to_string(),
+        );
+        env.insert(
+            "TEST_EXCHANGES__BYBIT__RECV_WINDOW".to_string(),
+            "3000".to_string(),
+        );
+        env.insert("OTHER_VAR".to_string(), "ignored".to_string());
+
+        let options = SettingsOptions {
+            config_path: config_path.clone(),
+            credentials_path: credentials_path.clone(),
+            env_prefix: "TEST".to_string(),
+            env: Some(env),
+            overrides: vec![parse_override("exchanges.bybit.recv_window=4000").unwrap()],
+            credentials_passphrase: None,
+        };
+        let layered = load(&options).unwrap();
+
+        let get = |key: &str| layered.config.get::<String>(key).unwrap();
+        assert_eq!(get("exchanges.bybit.recv_window"), "4000");
+        assert_eq!(get("exchanges.bybit.api_key"), "env");
+        assert_eq!(get("exchanges.bybit.secret_key"), "file");
+        assert_eq!(get("exchanges.bybit.request_timeout_ms"), "2000");
+        assert_eq!(get("exchanges.bybit.connect_timeout_ms"), "5000");
+
+        let source = |key: &str| layered.sources.get(key).cloned().unwrap();
+        assert_eq!(source("exchanges.bybit.recv_window"), Layer::Cli);
+        assert_eq!(
+            source("exchanges.bybit.api_key"),
+            Layer::Env("TEST_EXCHANGES__BYBIT__API_KEY".to_string())
+        );
+        assert_eq!(
+            source("exchanges.bybit.secret_key"),
+            Layer::File(credentials_path)
+        );
+        assert_eq!(
+            source("exchanges.bybit.request_timeout_ms"),
+            Layer::File(config_path)
+        );
+        assert_eq!(source("exchanges.bybit.connect_timeout_ms"), Layer::Default);
+    }
+
+    #[test]
+    fn encrypted_credentials() {
+        let config_path = write_config("encrypted_config.toml", "[exchanges.bybit]\n");
+        let plaintext = "[exchanges.bybit]\napi_key = \"key\"\nsecret_key = \"secret\"\n";
+        let credentials_path = write_config(
+            "encrypted_credentials.enc",
+            &encrypted::encrypt_with(plaintext, &"passphrase".into(), 1000).unwrap(),
+        );
+        let mut env = Map::new();
+        env.insert(
+            "TEST_CREDENTIALS_PASSPHRASE".to_string(),
+            "passphrase".to_string(),
+        );
+        let mut options = SettingsOptions {
+            config_path,
+            credentials_path: credentials_path.clone(),
+            env_prefix: "TEST".to_string(),
+            env: Some(env),
+            ..Default::default()
+        };
+
+        let layered = load(&options).unwrap();
+        assert_eq!(
+            layered
+                .config
+                .get::<String>("exchanges.bybit.api_key")
+                .unwrap(),
+            "key"
+        );
+        assert_eq!(
+            layered.sources["exchanges.bybit.api_key"],
+            Layer::File(credentials_path)
+        );
+        assert!(!layered.sources.contains_key("credentials_passphrase"));
+
+        options.unlock().unwrap();
+        assert!(options.credentials_passphrase.is_some());
+
+        options.env = Some(Map::new());
+        options.credentials_passphrase = Some("wrong".into());
+        assert!(matches!(load(&options), Err(SettingsError::Encrypted(_))));
+        options.credentials_passphrase = None;
+        assert!(matches!(load(&options), Err(SettingsError::Encrypted(_))));
+    }
+
+    #[test]
+    fn overrides() {
+        assert_eq!(
+            parse_override("exchanges.bybit.environment=mainnet").unwrap(),
+            (
+                "exchanges.bybit.environment".to_string(),
+                "mainnet".to_string()
+            )
+        );
+        assert!(parse_override("no_value").is_err());
+        assert!(parse_override("=value").is_err());
+    }
+
+    #[test]
+    fn env_var_names() {
+        assert_eq!(
+            env_var(ENV_PREFIX, "exchanges.bybit.api_key"),
+            "DECAY_EXCHANGES__BYBIT__API_KEY"
+        );
+    }
+}
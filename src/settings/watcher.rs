@@ -0,0 +1,295 @@
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::settings::Settings;
+use super::sources::{self, SettingsOptions};
+use crate::executor::{Control, Controls};
+use crate::settings::settings::StrategySettings;
+use crate::strategy::strategy;
+
+/// How often the config and credentials files are checked for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads the settings when their files change and hands new parameters to the instances.
+pub struct Watcher {
+    options: SettingsOptions,
+    /// Shared with the control api, which changes parameters too.
+    running: Arc<RwLock<Settings>>,
+    instances: Controls,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl Watcher {
+    pub fn new(
+        options: SettingsOptions,
+        running: Arc<RwLock<Settings>>,
+        instances: Controls,
+    ) -> Self {
+        let modified = modified(&options);
+        Self {
+            options,
+            running,
+            instances,
+            modified,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = modified(&self.options);
+            if modified != self.modified {
+                self.modified = modified;
+                self.reload();
+            }
+        }
+    }
+
+    /// Loads the settings again, either every change is applied or none.
+    pub fn reload(&mut self) {
+        let new = match Settings::load_with(&self.options) {
+            Ok(new) => new,
+            Err(e) => {
+                println!(
+                    "settings reload failed, keeping the running settings:\n{}",
+                    e
+                );
+                return;
+            }
+        };
+        let mut running = self.running.write().unwrap();
+        let updates = match diff(&running, &new) {
+            Ok(updates) => updates,
+            Err(reasons) => {
+                println!("settings reload rejected, nothing was applied:");
+                for reason in reasons {
+                    println!("  {}", reason);
+                }
+                return;
+            }
+        };
+
+        for settings in updates {
+            let id = settings.id.clone();
+            match self.instances.get(&id) {
+                Some(control) if control.send(Control::Update(settings)).is_ok() => {
+                    println!("settings reload: sent new parameters to {}", id)
+                }
+                _ => println!("settings reload: {} is not running", id),
+            }
+        }
+        *running = new;
+    }
+}
+
+/// The strategies whose `parameters` or `max_amount` changed, or why `new` can't be applied
+/// while running.
+pub fn diff(running: &Settings, new: &Settings) -> Result<Vec<StrategySettings>, Vec<String>> {
+    let mut reasons = vec![];
+    let mut updates = vec![];
+
+    let mut names: Vec<&String> = running
+        .exchanges
+        .keys()
+        .chain(new.exchanges.keys())
+        .collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        if running.exchanges.get(name) != new.exchanges.get(name) {
+            reasons.push(format!(
+                "exchanges.{} changed, connections need a restart",
+                name
+            ));
+        }
+        if running.exchanges_credentials.get(name) != new.exchanges_credentials.get(name) {
+            reasons.push(format!(
+                "credentials of exchanges.{} changed, swapping credentials needs a restart",
+                name
+            ));
+        }
+    }
+
+    if running.control_api != new.control_api {
+        reasons.push("control_api changed, the api needs a restart".to_string());
+    }
+    if running.risk != new.risk {
+        reasons.push("risk changed, the limits need a restart".to_string());
+    }
+    if running.kill_switch != new.kill_switch {
+        reasons.push("kill_switch changed, that needs a restart".to_string());
+    }
+    if running.reconcile != new.reconcile {
+        reasons.push("reconcile changed, that needs a restart".to_string());
+    }
+    if running.storage != new.storage {
+        reasons.push("storage changed, that needs a restart".to_string());
+    }
+    if running.recorder != new.recorder {
+        reasons.push("recorder changed, that needs a restart".to_string());
+    }
+    if running.replay != new.replay {
+        reasons.push("replay changed, that needs a restart".to_string());
+    }
+    if running.paper != new.paper {
+        reasons.push("paper changed, that needs a restart".to_string());
+    }
+
+    for old in &running.strategies {
+        if !new.strategies.iter().any(|s| s.id == old.id) {
+            reasons.push(format!(
+                "strategy {} was removed, that needs a restart",
+                old.id
+            ));
+        }
+    }
+    for new in &new.strategies {
+        let old = match running.strategies.iter().find(|s| s.id == new.id) {
+            Some(old) => old,
+            None => {
+                reasons.push(format!(
+                    "strategy {} was added, that needs a restart",
+                    new.id
+                ));
+                continue;
+            }
+        };
+        if old == new {
+            continue;
+        }
+        for (field, changed) in [
+            ("kind", old.kind != new.kind),
+            (
+                "exchange_account_id",
+                old.exchange_account_id != new.exchange_account_id,
+            ),
+            ("pairs", old.pairs != new.pairs),
+            ("bars", old.bars != new.bars),
+        ] {
+            if changed {
+                reasons.push(format!("strategy {}: {} can't change live", new.id, field));
+            }
+        }
+        if let Err(e) = strategy::build(new) {
+            reasons.push(format!("strategy {}: {}", new.id, e));
+        }
+        updates.push(new.clone());
+    }
+
+    match reasons.is_empty() {
+        true => Ok(updates),
+        false => Err(reasons),
+    }
+}
+
+fn modified(options: &SettingsOptions) -> Vec<Option<SystemTime>> {
+    [&options.config_path, &options.credentials_path]
+        .into_iter()
+        .map(|path| {
+            sources::resolve_path(path)
+                .and_then(|p| fs::metadata(p).ok())
+                .and_then(|m| m.modified().ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str, strategies: &str, credentials: &str) -> Settings {
+        let dir = std::env::temp_dir().join("decay_watcher_test");
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join(format!("{}_config.toml", name));
+        let credentials_path = dir.join(format!("{}_credentials.toml", name));
+        fs::write(&config_path, format!("{}\n[exchanges.bybit]\n", strategies)).unwrap();
+        fs::write(&credentials_path, credentials).unwrap();
+        Settings::load_with(&SettingsOptions {
+            config_path: config_path.to_string_lossy().to_string(),
+            credentials_path: credentials_path.to_string_lossy().to_string(),
+            env: Some(Default::default()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    const CREDENTIALS: &str = r#"
+        [exchanges.bybit]
+        api_key = "key"
+        secret_key = "secret"
+        exchange_account_id = "main"
+        "#;
+
+    fn strategy(id: &str, base: &str, max_amount: f64, min_qty: f64) -> String {
+        format!(
+            r#"
+            [[strategies]]
+            id = "{}"
+            kind = "watch"
+            exchange_account_id = "main"
+            pairs = [{{ base = "{}", qoute = "usdt" }}]
+            max_amount = {}
+            [strategies.parameters]
+            min_qty = {}
+            "#,
+            id, base, max_amount, min_qty
+        )
+    }
+
+    #[test]
+    fn parameter_changes_are_applied() {
+        let running = settings(
+            "running",
+            &(strategy("ada", "ada", 0.1, 0.0) + &strategy("btc", "btc", 0.1, 0.0)),
+            CREDENTIALS,
+        );
+        let new = settings(
+            "params",
+            &(strategy("ada", "ada", 0.2, 5.0) + &strategy("btc", "btc", 0.1, 0.0)),
+            CREDENTIALS,
+        );
+
+        let updates = diff(&running, &new).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].id, "ada");
+        assert_eq!(updates[0].max_amount, 0.2);
+        assert_eq!(updates[0].parameters["min_qty"], 5.0);
+
+        assert!(diff(&running, &running).unwrap().is_empty());
+    }
+
+    #[test]
+    fn non_live_changes_are_rejected() {
+        let running = settings("base", &strategy("ada", "ada", 0.1, 0.0), CREDENTIALS);
+        let new = settings(
+            "swapped",
+            &(strategy("ada", "eth", 0.2, 1.0) + &strategy("btc", "btc", 0.1, 0.0)),
+            &CREDENTIALS.replace("\"secret\"", "\"other\""),
+        );
+
+        let reasons = diff(&running, &new).unwrap_err();
+        assert_eq!(reasons.len(), 3);
+        assert!(reasons[0].contains("credentials of exchanges.bybit"));
+        assert!(reasons[1].contains("ada: pairs"));
+        assert!(reasons[2].contains("btc was added"));
+        assert!(reasons.iter().all(|r| !r.contains("other")));
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let running = settings("valid", &strategy("ada", "ada", 0.1, 0.0), CREDENTIALS);
+        let new = settings(
+            "bad_param",
+            &strategy("ada", "ada", 0.1, 0.0).replace("min_qty = 0", "min_qty = \"lots\""),
+            CREDENTIALS,
+        );
+
+        let reasons = diff(&running, &new).unwrap_err();
+        assert_eq!(
+            reasons,
+            vec!["strategy ada: min_qty should be a number, got \"lots\""]
+        );
+    }
+}
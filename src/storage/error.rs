@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("The database is at version {version}, this build only knows up to {known}")]
+    TooNew { version: usize, known: usize },
+    #[error("Could not write: {0}")]
+    Write(String),
+    #[error("The writer stopped")]
+    Closed,
+}
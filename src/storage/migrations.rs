@@ -0,0 +1,101 @@
+use rusqlite::Connection;
+
+use super::error::StorageError;
+
+/// Schema changes in order, each runs once. `PRAGMA user_version` is how many have run, so only
+/// ever append.
+pub const MIGRATIONS: &[&str] = &[
+    // 1
+    "CREATE TABLE orders (
+        exchange_account_id TEXT NOT NULL,
+        client_order_id TEXT NOT NULL,
+        order_id TEXT,
+        strategy_id TEXT NOT NULL,
+        instrument TEXT NOT NULL,
+        side TEXT NOT NULL,
+        state TEXT NOT NULL,
+        qty TEXT NOT NULL,
+        price TEXT,
+        filled_qty TEXT NOT NULL,
+        avg_fill_price TEXT NOT NULL,
+        reason TEXT,
+        updated INTEGER NOT NULL,
+        PRIMARY KEY (exchange_account_id, client_order_id)
+    );
+    CREATE TABLE fills (
+        id INTEGER PRIMARY KEY,
+        exchange_account_id TEXT NOT NULL,
+        client_order_id TEXT NOT NULL,
+        strategy_id TEXT NOT NULL,
+        instrument TEXT NOT NULL,
+        side TEXT NOT NULL,
+        qty TEXT NOT NULL,
+        price TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE INDEX fills_by_order ON fills (exchange_account_id, client_order_id);
+    CREATE TABLE positions (
+        id INTEGER PRIMARY KEY,
+        exchange_account_id TEXT NOT NULL,
+        instrument TEXT NOT NULL,
+        qty TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE balances (
+        id INTEGER PRIMARY KEY,
+        exchange_account_id TEXT NOT NULL,
+        coin TEXT NOT NULL,
+        balance TEXT NOT NULL,
+        equity TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        data TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );",
+];
+
+/// Runs the migrations `conn` hasn't had yet, all in one transaction. Returns the version it's
+/// at now.
+pub fn migrate(conn: &mut Connection) -> Result<usize, StorageError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(StorageError::TooNew {
+            version,
+            known: MIGRATIONS.len(),
+        });
+    }
+    let tx = conn.transaction()?;
+    for migration in MIGRATIONS.iter().skip(version) {
+        tx.execute_batch(migration)?;
+    }
+    // pragmas don't take parameters
+    tx.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+    tx.commit()?;
+    Ok(MIGRATIONS.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+        // nothing left to run, creating the tables again would fail
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+        let fills: i64 = conn
+            .query_row("SELECT COUNT(*) FROM fills", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(fills, 0);
+
+        conn.execute_batch("PRAGMA user_version = 99").unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(StorageError::TooNew { version: 99, .. })
+        ));
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, Transaction};
+use rust_decimal::Decimal;
+use tokio::sync::{broadcast, oneshot};
+
+use super::error::StorageError;
+use super::migrations;
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::ExchangeBalance;
+use crate::exchanges::util;
+use crate::oms::oms::{Fill, SharedOms, TrackedOrder};
+
+/// Most records written in one transaction.
+const MAX_BATCH: usize = 512;
+/// Tries at a durable record before its writer is told it's lost.
+const DURABLE_TRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// One row to write.
+#[derive(Debug, Clone)]
+pub enum Record {
+    /// Inserted, or replaced by its client order id.
+    Order {
+        exchange_account_id: String,
+        order: TrackedOrder,
+    },
+    Fill {
+        exchange_account_id: String,
+        fill: Fill,
+    },
+    Position {
+        exchange_account_id: String,
+        instrument: Instrument,
+        /// Net, long above 0
+        qty: Decimal,
+        timestamp: u128,
+    },
+    Balance {
+        exchange_account_id: String,
+        coin: String,
+        balance: ExchangeBalance,
+        timestamp: u128,
+    },
+    Event {
+        event: ExchangeEvent,
+        /// Local, ms
+        timestamp: u128,
+    },
+}
+
+struct Message {
+    /// None only waits for what was sent before
+    record: Option<Record>,
+    ack: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+/// Sends records to the writer thread, which commits them in batches and each durable record in
+/// a transaction of its own. Cheap to clone, the thread stops once every clone is gone.
+#[derive(Debug, Clone)]
+pub struct Store {
+    sender: mpsc::Sender<Message>,
+}
+
+impl Store {
+    /// Opens (or makes) the database at `path`, migrates it and starts the writer.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, thread::JoinHandle<()>), StorageError> {
+        let mut conn = Connection::open(path)?;
+        // with the wal a commit is on disk once it returns
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;")?;
+        migrations::migrate(&mut conn)?;
+        let (sender, messages) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("storage".to_string())
+            .spawn(move || write_all(conn, messages))
+            .map_err(|e| StorageError::Write(e.to_string()))?;
+        Ok((Self { sender }, writer))
+    }
+
+    /// Queues `record`, whatever happens to it is only printed.
+    pub fn write(&self, record: Record) {
+        let _ = self.sender.send(Message {
+            record: Some(record),
+            ack: None,
+        });
+    }
+
+    /// Returns once `record` is committed, for what can't be lost in a crash.
+    pub async fn write_durable(&self, record: Record) -> Result<(), StorageError> {
+        self.acked(Some(record)).await
+    }
+
+    /// Returns once everything queued before is committed.
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        self.acked(None).await
+    }
+
+    async fn acked(&self, record: Option<Record>) -> Result<(), StorageError> {
+        let (ack, answer) = oneshot::channel();
+        self.sender
+            .send(Message {
+                record,
+                ack: Some(ack),
+            })
+            .map_err(|_| StorageError::Closed)?;
+        answer
+            .await
+            .map_err(|_| StorageError::Closed)?
+            .map_err(StorageError::Write)
+    }
+}
+
+// The plain records of a batch go in one transaction, one by one if that fails so a bad record
+// only loses itself. Durable records never share a transaction and are retried.
+fn write_all(mut conn: Connection, messages: mpsc::Receiver<Message>) {
+    while let Ok(first) = messages.recv() {
+        let mut batch = vec![first];
+        batch.extend(messages.try_iter().take(MAX_BATCH - 1));
+
+        let plain: Vec<&Record> = batch
+            .iter()
+            .filter(|m| m.ack.is_none())
+            .filter_map(|m| m.record.as_ref())
+            .collect();
+        let mut lost = 0;
+        if let Err(e) = write_batch(&mut conn, &plain) {
+            println!(
+                "storage: could not write {} records at once, one by one: {}",
+                plain.len(),
+                e
+            );
+            for record in &plain {
+                if let Err(e) = write_batch(&mut conn, &[record]) {
+                    println!("storage: could not write {:?}: {}", record, e);
+                    lost += 1;
+                }
+            }
+        }
+
+        for message in batch.into_iter().filter(|m| m.ack.is_some()) {
+            let written = match &message.record {
+                Some(record) => write_durable(&mut conn, record),
+                None if lost > 0 => Err(format!("{} records could not be written", lost)),
+                None => Ok(()),
+            };
+            let _ = message.ack.unwrap().send(written);
+        }
+    }
+}
+
+fn write_durable(conn: &mut Connection, record: &Record) -> Result<(), String> {
+    let mut tries = 1;
+    loop {
+        match write_batch(conn, &[record]) {
+            Ok(()) => return Ok(()),
+            Err(e) if tries == DURABLE_TRIES => {
+                println!("storage: could not write {:?}: {}", record, e);
+                return Err(e.to_string());
+            }
+            Err(e) => {
+                println!("storage: write failed, trying again: {}", e);
+                thread::sleep(RETRY_DELAY * tries);
+                tries += 1;
+            }
+        }
+    }
+}
+
+fn write_batch(conn: &mut Connection, records: &[&Record]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    for record in records {
+        insert(&tx, record)?;
+    }
+    tx.commit()
+}
+
+fn insert(tx: &Transaction, record: &Record) -> rusqlite::Result<usize> {
+    match record {
+        Record::Order {
+            exchange_account_id,
+            order,
+        } => tx.execute(
+            "INSERT OR REPLACE INTO orders (exchange_account_id, client_order_id, order_id,
+                strategy_id, instrument, side, state, qty, price, filled_qty, avg_fill_price,
+                reason, updated)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                exchange_account_id,
+                order.client_order_id,
+                order.order_id,
+                order.strategy_id,
+                order.intent.instrument.to_string(),
+                format!("{:?}", order.intent.side),
+                format!("{:?}", order.state),
+                order.intent.qty.to_string(),
+                order.intent.price.map(|p| p.to_string()),
+                order.filled_qty.to_string(),
+                order.avg_fill_price.to_string(),
+                order.reason,
+                order.updated as i64,
+            ],
+        ),
+        Record::Fill {
+            exchange_account_id,
+            fill,
+        } => tx.execute(
+            "INSERT INTO fills (exchange_account_id, client_order_id, strategy_id, instrument,
+                side, qty, price, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                exchange_account_id,
+                fill.client_order_id,
+                fill.strategy_id,
+                fill.instrument.to_string(),
+                format!("{:?}", fill.side),
+                fill.qty.to_string(),
+                fill.price.to_string(),
+                fill.timestamp as i64,
+            ],
+        ),
+        Record::Position {
+            exchange_account_id,
+            instrument,
+            qty,
+            timestamp,
+        } => tx.execute(
+            "INSERT INTO positions (exchange_account_id, instrument, qty, timestamp)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                exchange_account_id,
+                instrument.to_string(),
+                qty.to_string(),
+                *timestamp as i64,
+            ],
+        ),
+        Record::Balance {
+            exchange_account_id,
+            coin,
+            balance,
+            timestamp,
+        } => tx.execute(
+            "INSERT INTO balances (exchange_account_id, coin, balance, equity, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                exchange_account_id,
+                coin,
+                balance.balance.to_string(),
+                balance.equity.to_string(),
+                *timestamp as i64,
+            ],
+        ),
+        Record::Event { event, timestamp } => {
+            let kind = match event {
+                ExchangeEvent::Trade(_) => "trade",
+                ExchangeEvent::OrderUpdate(_) => "order_update",
+                ExchangeEvent::Discrepancy(_) => "discrepancy",
+                ExchangeEvent::Bar(_) => "bar",
+                ExchangeEvent::Book(_) => "book",
+            };
+            let data = serde_json::to_string(event)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            tx.execute(
+                "INSERT INTO events (kind, data, timestamp) VALUES (?1, ?2, ?3)",
+                params![kind, data, *timestamp as i64],
+            )
+        }
+    }
+}
+
+/// Writes every event but the trades and books (those are market data) as it comes, and every
+/// `snapshot_interval_ms` the positions, the balances and the orders that changed since the last
+/// snapshot. Fills are written by `oms::run`.
+pub async fn run(
+    store: Store,
+    oms: HashMap<String, SharedOms>,
+    snapshot_interval_ms: u64,
+    mut events: broadcast::Receiver<ExchangeEvent>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(snapshot_interval_ms));
+    let mut since = 0;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(ExchangeEvent::Trade(_) | ExchangeEvent::Book(_)) => {}
+                Ok(event) => store.write(Record::Event {
+                    event,
+                    timestamp: util::millseconds().unwrap_or_default(),
+                }),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("storage: missed {} events", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = interval.tick() => {
+                let now = util::millseconds().unwrap_or_default();
+                for (account, oms) in &oms {
+                    for record in snapshot(account, oms, since, now) {
+                        store.write(record);
+                    }
+                }
+                since = now;
+            }
+        }
+    }
+}
+
+/// Orders changed from `since` on, positions and balances as of `now`.
+fn snapshot(account: &str, oms: &SharedOms, since: u128, now: u128) -> Vec<Record> {
+    let oms = oms.lock().unwrap();
+    let orders = oms
+        .orders()
+        .filter(|o| o.updated >= since)
+        .map(|o| Record::Order {
+            exchange_account_id: account.to_string(),
+            order: o.clone(),
+        });
+    let positions = oms.positions().map(|(instrument, qty)| Record::Position {
+        exchange_account_id: account.to_string(),
+        instrument: instrument.clone(),
+        qty: *qty,
+        timestamp: now,
+    });
+    let balances = oms.balances().map(|(coin, balance)| Record::Balance {
+        exchange_account_id: account.to_string(),
+        coin: coin.clone(),
+        balance: balance.clone(),
+        timestamp: now,
+    });
+    orders.chain(positions).chain(balances).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    use crate::exchanges::r#trait::Side;
+
+    #[tokio::test]
+    async fn durable_writes_are_committed() {
+        let path = std::env::temp_dir().join(format!(
+            "decay-store-{}.sqlite",
+            util::millseconds().unwrap()
+        ));
+        let (store, writer) = Store::open(&path).unwrap();
+        let fill = Fill {
+            client_order_id: "mm-1".to_string(),
+            strategy_id: "mm".to_string(),
+            instrument: Instrument::perpetual("btc", "usdt"),
+            side: Side::Buy,
+            qty: dec!(0.5),
+            price: dec!(100),
+            timestamp: 1,
+        };
+        store.write(Record::Position {
+            exchange_account_id: "main".to_string(),
+            instrument: fill.instrument.clone(),
+            qty: dec!(0.5),
+            timestamp: 2,
+        });
+        store
+            .write_durable(Record::Fill {
+                exchange_account_id: "main".to_string(),
+                fill,
+            })
+            .await
+            .unwrap();
+
+        // what a crash right after would leave
+        let conn = Connection::open(&path).unwrap();
+        let (qty, price): (String, String) = conn
+            .query_row("SELECT qty, price FROM fills", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((qty.as_str(), price.as_str()), ("0.5", "100"));
+        let positions: i64 = conn
+            .query_row("SELECT COUNT(*) FROM positions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(positions, 1);
+
+        drop(store);
+        writer.join().unwrap();
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_bad_record_only_loses_itself() {
+        let path = std::env::temp_dir().join(format!(
+            "decay-store-bad-{}.sqlite",
+            util::millseconds().unwrap()
+        ));
+        let (store, writer) = Store::open(&path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TRIGGER no_eth BEFORE INSERT ON positions WHEN NEW.instrument LIKE 'ETH%'
+                BEGIN SELECT RAISE(ABORT, 'no eth'); END;
+            CREATE TRIGGER no_sells BEFORE INSERT ON fills WHEN NEW.side = 'Sell'
+                BEGIN SELECT RAISE(ABORT, 'no sells'); END;",
+        )
+        .unwrap();
+        let position = |base: &str| Record::Position {
+            exchange_account_id: "main".to_string(),
+            instrument: Instrument::perpetual(base, "usdt"),
+            qty: dec!(1),
+            timestamp: 1,
+        };
+        let fill = |side: Side| Record::Fill {
+            exchange_account_id: "main".to_string(),
+            fill: Fill {
+                client_order_id: "mm-1".to_string(),
+                strategy_id: "mm".to_string(),
+                instrument: Instrument::perpetual("btc", "usdt"),
+                side,
+                qty: dec!(1),
+                price: dec!(100),
+                timestamp: 1,
+            },
+        };
+
+        store.write(position("eth"));
+        store.write(position("btc"));
+        store.write_durable(fill(Side::Buy)).await.unwrap();
+        // retried, then given up on
+        assert!(store.write_durable(fill(Side::Sell)).await.is_err());
+
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count("positions"), 1);
+        assert_eq!(count("fills"), 1);
+
+        drop(store);
+        writer.join().unwrap();
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
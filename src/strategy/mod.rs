@@ -0,0 +1,3 @@
+#[allow(clippy::module_inception)]
+pub mod strategy;
+pub mod watch;
@@ -0,0 +1,126 @@
+use rust_decimal::Decimal;
+
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::exchanges::r#trait::{PlaceOrder, Side};
+use crate::oms::oms::{Fill, SharedOms, TrackedOrder};
+use crate::settings::settings::{Parameters, StrategySettings};
+
+use super::watch::Watch;
+
+/// Every `kind` a `[[strategies]]` entry can use.
+pub const KINDS: &[&str] = &["watch"];
+
+/// What an instance wants done, executed by the executor after each callback.
+#[derive(Debug)]
+pub enum Command {
+    PlaceOrder(PlaceOrder),
+    CancelOrder {
+        instrument: Instrument,
+        order_id: String,
+    },
+    /// Also works before the exchange acknowledged the order.
+    CancelByClientId {
+        instrument: Instrument,
+        client_order_id: String,
+    },
+}
+
+/// Handed to every callback, collects the commands of one strategy instance.
+pub struct Context {
+    pub settings: StrategySettings,
+    /// The account's orders, queried without asking the exchange.
+    pub oms: SharedOms,
+    commands: Vec<Command>,
+}
+
+impl Context {
+    pub fn new(settings: StrategySettings, oms: SharedOms) -> Self {
+        Self {
+            settings,
+            oms,
+            commands: vec![],
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.settings.id
+    }
+
+    /// This instance's orders that haven't filled, been canceled or rejected yet.
+    #[allow(dead_code)]
+    pub fn open_orders(&self) -> Vec<TrackedOrder> {
+        let oms = self.oms.lock().unwrap();
+        oms.open_orders()
+            .filter(|o| o.strategy_id == self.id())
+            .cloned()
+            .collect()
+    }
+
+    /// Unfilled qty of this instance's open orders on one side of `instrument`.
+    #[allow(dead_code)]
+    pub fn working_qty(&self, instrument: &Instrument, side: Side) -> Decimal {
+        self.open_orders()
+            .iter()
+            .filter(|o| o.intent.instrument == *instrument && o.intent.side == side)
+            .map(TrackedOrder::working_qty)
+            .sum()
+    }
+
+    /// This instance's fills, oldest first.
+    #[allow(dead_code)]
+    pub fn fills(&self) -> Vec<Fill> {
+        let oms = self.oms.lock().unwrap();
+        oms.fills()
+            .iter()
+            .filter(|f| f.strategy_id == self.id())
+            .cloned()
+            .collect()
+    }
+
+    /// The account's net position on `instrument` as the oms sees it, None before the first
+    /// reconciliation.
+    #[allow(dead_code)]
+    pub fn position(&self, instrument: &Instrument) -> Option<Decimal> {
+        self.oms.lock().unwrap().position(instrument)
+    }
+
+    #[allow(dead_code)]
+    pub fn place_order(&mut self, order: PlaceOrder) {
+        self.commands.push(Command::PlaceOrder(order));
+    }
+
+    #[allow(dead_code)]
+    pub fn cancel_order(&mut self, instrument: Instrument, order_id: String) {
+        self.commands.push(Command::CancelOrder {
+            instrument,
+            order_id,
+        });
+    }
+
+    #[allow(dead_code)]
+    pub fn cancel_by_client_id(&mut self, instrument: Instrument, client_order_id: String) {
+        self.commands.push(Command::CancelByClientId {
+            instrument,
+            client_order_id,
+        });
+    }
+
+    pub fn take_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+}
+
+pub trait Strategy: Send {
+    fn on_event(&mut self, event: &ExchangeEvent, ctx: &mut Context);
+    /// Takes new `parameters` while running, either all of them or none.
+    fn update_parameters(&mut self, parameters: &Parameters) -> Result<(), String>;
+}
+
+/// Builds the implementation named by `kind`.
+pub fn build(settings: &StrategySettings) -> Result<Box<dyn Strategy>, String> {
+    match settings.kind.as_str() {
+        "watch" => Ok(Box::new(Watch::new(settings)?)),
+        whatever => Err(format!("{} <- is not a strategy kind", whatever)),
+    }
+}
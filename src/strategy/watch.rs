@@ -0,0 +1,54 @@
+use std::collections::HashSet;
+
+use rust_decimal::Decimal;
+
+use crate::exchanges::event::ExchangeEvent;
+use crate::exchanges::instrument::Instrument;
+use crate::settings::settings::{Parameters, StrategySettings};
+
+use super::strategy::{Context, Strategy};
+
+/// Prints the trades of its pairs and never trades, handy to check a setup end to end.
+///
+/// Parameters: `min_qty` (number, default 0) only prints trades at least this big.
+pub struct Watch {
+    instruments: HashSet<Instrument>,
+    min_qty: Decimal,
+}
+
+impl Watch {
+    pub fn new(settings: &StrategySettings) -> Result<Self, String> {
+        Ok(Self {
+            instruments: settings.pairs.iter().map(|p| p.instrument()).collect(),
+            min_qty: min_qty(&settings.parameters)?,
+        })
+    }
+}
+
+fn min_qty(parameters: &Parameters) -> Result<Decimal, String> {
+    match parameters.get("min_qty") {
+        None => Ok(Decimal::ZERO),
+        Some(v) => v
+            .as_f64()
+            .and_then(|f| Decimal::try_from(f).ok())
+            .ok_or_else(|| format!("min_qty should be a number, got {}", v)),
+    }
+}
+
+impl Strategy for Watch {
+    fn on_event(&mut self, event: &ExchangeEvent, ctx: &mut Context) {
+        match event {
+            ExchangeEvent::Trade(trade)
+                if self.instruments.contains(&trade.instrument) && trade.qty >= self.min_qty =>
+            {
+                println!("[{}] {:?}", ctx.id(), trade);
+            }
+            _ => {}
+        }
+    }
+
+    fn update_parameters(&mut self, parameters: &Parameters) -> Result<(), String> {
+        self.min_qty = min_qty(parameters)?;
+        Ok(())
+    }
+}